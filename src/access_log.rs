@@ -0,0 +1,356 @@
+//! Access log
+//!
+//! Writes one line per request in the Combined Log Format to the file given by
+//! `--log`, followed by the duration of the request in microseconds:
+//!
+//! ```text
+//! 127.0.0.1 - rustic [16/Nov/2024:12:34:56 +0100] "GET /repo/config HTTP/1.1" 200 155 "-" "rustic" 1234
+//! ```
+//!
+//! A line is written once the response body has been sent completely or the
+//! client has gone away, so the number of bytes is the number actually sent.
+//! Lines are handed to a writer thread, so requests never wait for the disk.
+//! The file is reopened on `SIGHUP`, so it can be rotated by tools like `logrotate`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Local};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::{
+    auth::AuthenticatedUser,
+    error::{AppResult, ErrorKind},
+    log::InspectBody,
+};
+
+// Static storage of our access log
+pub static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+pub(crate) fn init_access_log(access_log: AccessLog) -> AppResult<()> {
+    let _ = ACCESS_LOG.get_or_init(|| access_log);
+    Ok(())
+}
+
+/// Number of lines waiting for the writer thread before new lines are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// Messages to the writer thread
+#[derive(Debug)]
+enum Message {
+    Line(String),
+    Reopen(oneshot::Sender<io::Result<()>>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// `AccessLog` appends request lines to a file
+#[derive(Debug)]
+pub struct AccessLog {
+    path: PathBuf,
+    sender: SyncSender<Message>,
+}
+
+impl AccessLog {
+    /// Opens the access log at `path` for appending and starts its writer thread
+    pub fn open(path: impl Into<PathBuf>) -> AppResult<Self> {
+        let path = path.into();
+        let file = open_log_file(&path).map_err(|err| {
+            ErrorKind::Io.context(format!(
+                "Could not open access log at `{}`: {err}",
+                path.display()
+            ))
+        })?;
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let writer_path = path.clone();
+        let _ = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(&writer_path, file, &receiver))
+            .map_err(|err| {
+                ErrorKind::Io.context(format!("Could not start access log writer: {err}"))
+            })?;
+
+        Ok(Self { path, sender })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reopens the access log, e.g. after it has been rotated
+    ///
+    /// All lines queued before are written to the previous file.
+    pub async fn reopen(&self) -> io::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Message::Reopen(sender))
+            .map_err(|_| writer_gone())?;
+        receiver.await.map_err(|_| writer_gone())?
+    }
+
+    /// Waits until all queued lines have been written
+    pub async fn flush(&self) {
+        let (sender, receiver) = oneshot::channel();
+        if self.sender.send(Message::Flush(sender)).is_ok() {
+            let _ = receiver.await;
+        }
+    }
+
+    /// Queues a line, dropping it if the writer thread can't keep up
+    fn write_line(&self, line: String) {
+        match self.sender.try_send(Message::Line(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(path = %self.path.display(), "[access_log] queue is full, dropping line");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!(path = %self.path.display(), "[access_log] writer has stopped, dropping line");
+            }
+        }
+    }
+}
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::other("access log writer has stopped")
+}
+
+/// Writes the queued lines until the `AccessLog` is dropped
+fn write_lines(path: &Path, mut file: File, receiver: &Receiver<Message>) {
+    for message in receiver {
+        match message {
+            Message::Line(line) => {
+                if let Err(err) = writeln!(file, "{line}") {
+                    warn!(%err, path = %path.display(), "[access_log] could not write line");
+                }
+            }
+            Message::Reopen(reply) => {
+                let result = open_log_file(path).map(|reopened| file = reopened);
+                let _ = reply.send(result);
+            }
+            Message::Flush(reply) => {
+                let _ = reply.send(());
+            }
+        }
+    }
+}
+
+/// Reopens the access log whenever the server receives `SIGHUP`
+#[cfg(unix)]
+pub fn reopen_on_sighup() -> AppResult<tokio::task::JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|err| ErrorKind::Io.context(format!("Could not listen for SIGHUP: {err}")))?;
+
+    Ok(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let Some(access_log) = ACCESS_LOG.get() else {
+                continue;
+            };
+
+            match access_log.reopen().await {
+                Ok(()) => info!(path = %access_log.path().display(), "[access_log] reopened"),
+                Err(err) => warn!(%err, "[access_log] could not reopen"),
+            }
+        }
+    }))
+}
+
+/// Reopening the access log on `SIGHUP` is only supported on unix
+#[cfg(not(unix))]
+pub fn reopen_on_sighup() -> AppResult<tokio::task::JoinHandle<()>> {
+    Ok(tokio::spawn(async {}))
+}
+
+/// Everything of a request that goes into its log line, except the bytes sent
+#[derive(Debug)]
+struct AccessLogEntry {
+    remote_addr: Option<SocketAddr>,
+    user: AuthenticatedUser,
+    time: DateTime<Local>,
+    request_line: String,
+    status: u16,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    start: Instant,
+}
+
+impl AccessLogEntry {
+    fn format(&self, bytes: u64, duration: Duration) -> String {
+        format!(
+            r#"{} - {} [{}] "{}" {} {} "{}" "{}" {}"#,
+            self.remote_addr
+                .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string()),
+            self.user
+                .get()
+                .filter(|user| !user.is_empty())
+                .unwrap_or("-"),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.request_line),
+            self.status,
+            // `-` instead of `0`, just like the common log format does
+            if bytes == 0 {
+                "-".to_string()
+            } else {
+                bytes.to_string()
+            },
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+            duration.as_micros(),
+        )
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string)
+}
+
+/// Escapes quotes and control characters, so a line can't be forged
+fn escape(value: &str) -> String {
+    value.chars().flat_map(char::escape_default).collect()
+}
+
+/// Middleware writing a line to the access log for every request
+///
+/// This has to wrap the whole `Router`, so the original path of a nested repository is logged.
+pub async fn write_access_log(mut request: Request, next: Next) -> Response {
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return next.run(request).await;
+    };
+
+    let start = Instant::now();
+    let time = Local::now();
+    let remote_addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let request_line = format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri(),
+        request.version()
+    );
+    let referer = header_value(request.headers(), header::REFERER);
+    let user_agent = header_value(request.headers(), header::USER_AGENT);
+
+    // Filled in by the authentication extractor
+    let user = AuthenticatedUser::default();
+    let _ = request.extensions_mut().insert(user.clone());
+
+    let response = next.run(request).await;
+
+    let entry = AccessLogEntry {
+        remote_addr,
+        user,
+        time,
+        request_line,
+        status: response.status().as_u16(),
+        referer,
+        user_agent,
+        start,
+    };
+
+    response.map(|body| {
+        Body::new(InspectBody::new(body, 0, move |inspected| {
+            access_log.write_line(entry.format(inspected.bytes, entry.start.elapsed()));
+        }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use axum::{
+        http::{header, Method},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use crate::{
+        access_log::{init_access_log, write_access_log, AccessLog, ACCESS_LOG},
+        auth::AuthFromRequest,
+        testing::{init_test_environment, request_uri_for_test, server_config},
+    };
+
+    async fn hello(_auth: AuthFromRequest) -> &'static str {
+        "Hello World"
+    }
+
+    #[tokio::test]
+    async fn test_access_log_passes() {
+        init_test_environment(server_config());
+
+        let base = PathBuf::from("tests/generated/test_access_log");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&base).unwrap();
+
+        let path = base.join("access.log");
+        init_access_log(AccessLog::open(&path).unwrap()).unwrap();
+
+        let app = Router::new()
+            .route("/hello", get(hello))
+            .layer(middleware::from_fn(write_access_log));
+
+        let mut request = request_uri_for_test("/hello", Method::GET);
+        let _ = request
+            .headers_mut()
+            .insert(header::USER_AGENT, "rustic \"test\"".parse().unwrap());
+        let resp = app.clone().oneshot(request).await.unwrap();
+        let _ = resp.into_body().collect().await.unwrap();
+        ACCESS_LOG.get().unwrap().flush().await;
+
+        let log = fs::read_to_string(&path).unwrap();
+        let line = log.lines().next().unwrap();
+        assert!(line.starts_with("- - rustic ["), "{line}");
+        assert!(
+            line.contains(r#"] "GET /hello HTTP/1.1" 200 11 "-" "rustic \"test\"" "#),
+            "{line}"
+        );
+
+        // after a rotation, the log is written to a new file
+        let rotated = base.join("access.log.1");
+        fs::rename(&path, &rotated).unwrap();
+        ACCESS_LOG.get().unwrap().reopen().await.unwrap();
+
+        let request = request_uri_for_test("/hello", Method::GET);
+        let resp = app.oneshot(request).await.unwrap();
+        let _ = resp.into_body().collect().await.unwrap();
+        ACCESS_LOG.get().unwrap().flush().await;
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap().lines().count(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
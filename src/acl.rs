@@ -1,27 +1,43 @@
-use std::{collections::BTreeMap, fs, path::PathBuf, sync::OnceLock};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
 
+use arc_swap::ArcSwap;
+use clap::ValueEnum;
+use glob::{MatchOptions, Pattern, PatternError};
 use serde_derive::{Deserialize, Serialize};
 use tracing::debug;
 
 use crate::{
     config::AclSettings,
     error::{AppResult, ErrorKind},
+    quota::QuotaLimits,
     typed_path::TpeKind,
 };
 
 // Static storage of our credentials
-pub static ACL: OnceLock<Acl> = OnceLock::new();
+pub static ACL: OnceLock<ArcSwap<Acl>> = OnceLock::new();
 
 pub fn init_acl(acl: Acl) -> AppResult<()> {
-    let _ = ACL.get_or_init(|| acl);
+    let _ = ACL.get_or_init(|| ArcSwap::from_pointee(acl));
     Ok(())
 }
 
+/// Replaces the ACL of the running server, e.g. after the ACL file has been changed
+pub fn replace_acl(acl: Acl) {
+    if let Some(current) = ACL.get() {
+        current.store(Arc::new(acl));
+    }
+}
+
 /// Access Types
 ///
 // IMPORTANT: The order of the variants is important, as it is used
 // to determine the access level! Don't change it!
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Serialize, Deserialize, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Serialize, Deserialize, Copy, ValueEnum)]
 pub enum AccessType {
     /// No access
     NoAccess,
@@ -30,8 +46,9 @@ pub enum AccessType {
     ///
     /// # Note
     ///
-    /// This is a special access type that allows a user to unlock a lock
-    /// without having to have the Modify access type.
+    /// This is a special access type that allows a user to read a repository
+    /// and to remove the locks of other users, e.g. stale ones, without
+    /// having to have the Modify access type. See [`AccessType::grants`].
     ForceUnlock,
 
     /// Read-only access
@@ -48,8 +65,236 @@ pub enum AccessType {
     Modify,
 }
 
+impl AccessType {
+    /// Returns if an ACL entry of `self` allows an access of type `requested`
+    ///
+    /// The access types are ordered, except for `ForceUnlock`: it grants `Read`,
+    /// and it is only granted by `ForceUnlock` and `Modify`.
+    pub fn grants(self, requested: Self) -> bool {
+        match requested {
+            Self::ForceUnlock => matches!(self, Self::ForceUnlock | Self::Modify),
+            Self::Read => self >= Self::ForceUnlock,
+            _ => self >= requested,
+        }
+    }
+}
+
+/// What clients without credentials may access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum AnonymousAccess {
+    /// Only the repositories the ACL grants the `anonymous` user access to
+    Acl,
+    /// All repositories, read-only; the ACL may still restrict this for a repository
+    ReadOnly,
+    /// All repositories, unless the ACL restricts a repository (the default of `--no-auth`)
+    Full,
+}
+
+/// Name of the user in ACL entries for clients without credentials
+pub const ANONYMOUS_USER: &str = "anonymous";
+
 pub trait AclChecker: Send + Sync + 'static {
     fn is_allowed(&self, user: &str, path: &str, tpe: Option<TpeKind>, access: AccessType) -> bool;
+
+    /// Like [`AclChecker::is_allowed`], but the user is also a member of `groups`
+    fn is_allowed_in_groups(
+        &self,
+        user: &str,
+        groups: &[String],
+        path: &str,
+        tpe: Option<TpeKind>,
+        access: AccessType,
+    ) -> bool {
+        let _ = groups;
+        self.is_allowed(user, path, tpe, access)
+    }
+
+    /// Like [`AclChecker::is_allowed`], for a client without credentials under `policy`
+    fn is_allowed_anonymous(
+        &self,
+        policy: AnonymousAccess,
+        path: &str,
+        tpe: Option<TpeKind>,
+        access: AccessType,
+    ) -> bool {
+        (policy != AnonymousAccess::ReadOnly || AccessType::Read.grants(lock_access(tpe, access)))
+            && self.is_allowed(ANONYMOUS_USER, path, tpe, access)
+    }
+}
+
+/// Prefix of group entries in a repository ACL, e.g. `"@backup" = "Append"`
+pub const GROUP_PREFIX: char = '@';
+
+/// Placeholder for the requesting user in repository patterns, e.g. `["{user}/**"]`
+///
+/// Within the ACL of such a pattern, an entry `"{user}"` applies to the requesting user.
+pub const USER_PLACEHOLDER: &str = "{user}";
+
+/// Maximum number of users a pattern with `{user}` keeps compiled, so unknown users can't exhaust memory
+const MAX_COMPILED_USERS: usize = 10_000;
+
+/// Wildcards of repository patterns are not matched across a `/`, only `**` spans levels
+const PATTERN_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Returns if an ACL key is a repository pattern instead of a repository name
+fn is_pattern(key: &str) -> bool {
+    key.contains(['*', '?', '[']) || key.contains(USER_PLACEHOLDER)
+}
+
+/// Returns the number of characters a glob pattern matches literally
+///
+/// Wildcards and bracket expressions don't count, except for a bracket
+/// expression of a single character like `[*]`, which `Pattern::escape` uses
+/// for special characters.
+fn literal_count(pattern: &str) -> usize {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut count = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' | '?' => i += 1,
+            '[' => {
+                // The first member may be `]`, so the expression ends at the next one
+                let Some(end) = chars
+                    .get(i + 2..)
+                    .and_then(|rest| rest.iter().position(|c| *c == ']'))
+                else {
+                    break;
+                };
+                let members = &chars[i + 1..i + 2 + end];
+                if members.len() == 1 && members[0] != '!' {
+                    count += 1;
+                }
+                i += end + 3;
+            }
+            _ => {
+                count += 1;
+                i += 1;
+            }
+        }
+    }
+
+    count
+}
+
+/// A repository pattern, compiled when the ACL is loaded
+#[derive(Clone, Debug)]
+enum RepoPattern {
+    /// A pattern without `{user}`
+    Fixed {
+        pattern: Pattern,
+        specificity: usize,
+    },
+    /// A pattern with `{user}`, which is compiled once per user when it is first needed
+    PerUser {
+        key: String,
+        placeholders: usize,
+        specificity: usize,
+        compiled: Arc<Mutex<HashMap<String, Pattern>>>,
+    },
+}
+
+impl RepoPattern {
+    fn new(key: &str) -> Result<Self, PatternError> {
+        if key.contains(USER_PLACEHOLDER) {
+            let _ = Pattern::new(&key.replace(USER_PLACEHOLDER, "user"))?;
+            Ok(Self::PerUser {
+                key: key.to_string(),
+                placeholders: key.matches(USER_PLACEHOLDER).count(),
+                specificity: literal_count(&key.replace(USER_PLACEHOLDER, "")),
+                compiled: Arc::default(),
+            })
+        } else {
+            Ok(Self::Fixed {
+                pattern: Pattern::new(key)?,
+                specificity: literal_count(key),
+            })
+        }
+    }
+
+    /// Returns how specific the pattern matches `path`, if it does
+    ///
+    /// The specificity is the number of literal characters of the pattern, with
+    /// `{user}` replaced by the name of the user. Patterns with `{user}` never
+    /// match without a user.
+    fn specificity(&self, path: &str, user: Option<&str>) -> Option<usize> {
+        match self {
+            Self::Fixed {
+                pattern,
+                specificity,
+            } => pattern
+                .matches_with(path, PATTERN_OPTIONS)
+                .then_some(*specificity),
+            Self::PerUser {
+                key,
+                placeholders,
+                specificity,
+                compiled,
+            } => {
+                let user = user.filter(|user| !user.is_empty())?;
+                let mut compiled = compiled.lock().unwrap();
+                let pattern = match compiled.get(user) {
+                    Some(pattern) => pattern,
+                    None => {
+                        let pattern =
+                            Pattern::new(&key.replace(USER_PLACEHOLDER, &Pattern::escape(user)))
+                                .ok()?;
+                        if compiled.len() >= MAX_COMPILED_USERS {
+                            compiled.clear();
+                        }
+                        compiled.entry(user.to_string()).or_insert(pattern)
+                    }
+                };
+
+                pattern
+                    .matches_with(path, PATTERN_OPTIONS)
+                    .then(|| specificity + placeholders * user.chars().count())
+            }
+        }
+    }
+}
+
+/// Checks and compiles the repository patterns of an ACL file, so an invalid pattern is refused at load time
+fn compile_patterns(
+    repos: &BTreeMap<Repository, RepoAcl>,
+) -> AppResult<BTreeMap<Repository, RepoPattern>> {
+    let mut patterns = BTreeMap::new();
+
+    for (key, repo_acl) in repos {
+        let has_placeholder = key.contains(USER_PLACEHOLDER);
+
+        if key.replace(USER_PLACEHOLDER, "").contains(['{', '}']) {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "Repository `{key}` contains an unknown placeholder, only `{USER_PLACEHOLDER}` is supported"
+                ))
+                .into());
+        }
+
+        if is_pattern(key) {
+            let pattern = RepoPattern::new(key).map_err(|err| {
+                ErrorKind::Config.context(format!("Invalid repository pattern `{key}`: {err}"))
+            })?;
+            _ = patterns.insert(key.clone(), pattern);
+        }
+
+        if !has_placeholder && repo_acl.contains_key(USER_PLACEHOLDER) {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "Repository `{key}` has an entry `{USER_PLACEHOLDER}`, but its name doesn't contain `{USER_PLACEHOLDER}`"
+                ))
+                .into());
+        }
+    }
+
+    Ok(patterns)
 }
 
 type HtPasswdUsername = String;
@@ -80,27 +325,58 @@ impl std::ops::Deref for RepoAcl {
 
 type Repository = String;
 
+type GroupName = String;
+
+/// Sections of the ACL file that can't name a repository
+pub const RESERVED_SECTIONS: [&str; 2] = ["quota", "groups"];
+
+/// Layout of the ACL file
+///
+/// The `[quota]` and `[groups]` sections are reserved, all other tables are repository ACLs.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+struct AclFile {
+    #[serde(default, skip_serializing_if = "QuotaLimits::is_empty")]
+    quota: QuotaLimits,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<GroupName, Vec<HtPasswdUsername>>,
+    #[serde(flatten)]
+    repos: BTreeMap<Repository, RepoAcl>,
+}
+
 /// `Acl` holds ACLs for all repos
+///
+/// Entries of a repository ACL name a user or, prefixed with `@`, a group.
+/// Groups are defined in the `[groups]` section or come from the
+/// authentication backend. If any entry that applies to a user is `NoAccess`,
+/// the access is denied, otherwise the highest access of these entries wins.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Acl {
     private_repo: bool,
     append_only: bool,
     repos: BTreeMap<Repository, RepoAcl>,
+    /// The compiled repository patterns among the keys of `repos`
+    #[serde(skip)]
+    patterns: BTreeMap<Repository, RepoPattern>,
+    groups: BTreeMap<GroupName, Vec<HtPasswdUsername>>,
+    quota: QuotaLimits,
 }
 
 impl Default for Acl {
     fn default() -> Self {
         Self {
             repos: BTreeMap::new(),
+            patterns: BTreeMap::new(),
+            groups: BTreeMap::new(),
             append_only: true,
             private_repo: true,
+            quota: QuotaLimits::default(),
         }
     }
 }
 
 // read_toml is a helper func that reads the given file in toml
 // into a Hashmap mapping each user to the whole passwd line
-fn read_toml(file_path: &PathBuf) -> AppResult<BTreeMap<String, RepoAcl>> {
+fn read_toml(file_path: &PathBuf) -> AppResult<AclFile> {
     let s = fs::read_to_string(file_path).map_err(|err| {
         ErrorKind::Io.context(format!(
             "Could not read toml file: {} at {:?}",
@@ -110,16 +386,29 @@ fn read_toml(file_path: &PathBuf) -> AppResult<BTreeMap<String, RepoAcl>> {
     // make the contents static in memory
     let s = Box::leak(s.into_boxed_str());
 
-    let mut repos: BTreeMap<String, RepoAcl> = toml::from_str(s)
+    let mut acl_file: AclFile = toml::from_str(s)
         .map_err(|err| ErrorKind::Config.context(format!("Could not parse TOML: {}", err)))?;
 
+    if let Some((group, member)) = acl_file.groups.iter().find_map(|(group, members)| {
+        members
+            .iter()
+            .find(|member| member.starts_with(GROUP_PREFIX))
+            .map(|member| (group, member))
+    }) {
+        return Err(ErrorKind::Config
+            .context(format!(
+                "Group `{group}` can't contain the group `{member}`, groups can't be nested"
+            ))
+            .into());
+    }
+
     // copy key "default" into ""
-    if let Some(default) = repos.get("default") {
+    if let Some(default) = acl_file.repos.get("default") {
         let default = default.clone();
-        let _ = repos.insert(String::new(), default);
+        let _ = acl_file.repos.insert(String::new(), default);
     }
 
-    Ok(repos)
+    Ok(acl_file)
 }
 
 impl Acl {
@@ -128,17 +417,32 @@ impl Acl {
         private_repos: bool,
         file_path: Option<PathBuf>,
     ) -> AppResult<Self> {
-        let repos = match file_path {
-            Some(file_path) => read_toml(&file_path).map_err(|err| {
-                ErrorKind::Config.context(format!("Could not read ACL file: {err}"))
-            })?,
-            None => BTreeMap::new(),
+        let (
+            AclFile {
+                quota,
+                groups,
+                repos,
+            },
+            patterns,
+        ) = match file_path {
+            Some(file_path) => read_toml(&file_path)
+                .and_then(|acl_file| {
+                    let patterns = compile_patterns(&acl_file.repos)?;
+                    Ok((acl_file, patterns))
+                })
+                .map_err(|err| {
+                    ErrorKind::Config.context(format!("Could not read ACL file: {err}"))
+                })?,
+            None => (AclFile::default(), BTreeMap::new()),
         };
 
         Ok(Self {
             append_only,
             private_repo: private_repos,
             repos,
+            patterns,
+            groups,
+            quota,
         })
     }
 
@@ -157,7 +461,13 @@ impl Acl {
 
         _ = repos.remove("");
 
-        let toml_string = toml::to_string(&repos).map_err(|err| {
+        let acl_file = AclFile {
+            quota: self.quota.clone(),
+            groups: self.groups.clone(),
+            repos,
+        };
+
+        let toml_string = toml::to_string(&acl_file).map_err(|err| {
             ErrorKind::Config.context(format!(
                 "Could not serialize ACL config to TOML value: {err}"
             ))
@@ -169,6 +479,19 @@ impl Acl {
         Ok(())
     }
 
+    /// Groups of the `[groups]` section `user` is a member of
+    fn groups_of<'a>(&'a self, user: &'a str) -> impl Iterator<Item = &'a str> {
+        self.groups
+            .iter()
+            .filter(move |(_, members)| members.iter().any(|member| member == user))
+            .map(|(group, _)| group.as_str())
+    }
+
+    /// Per-repository and per-user quotas from the `[quota]` section
+    pub const fn quota(&self) -> &QuotaLimits {
+        &self.quota
+    }
+
     pub fn set_append_only(self, append_only: bool) -> Self {
         Self {
             append_only,
@@ -176,6 +499,48 @@ impl Acl {
         }
     }
 
+    /// Returns the key and the ACL that apply to a repository accessed by `user`
+    ///
+    /// An exact entry wins over a matching pattern, but a repository named like
+    /// a pattern, e.g. `{user}/data`, is only matched by the pattern. Of several
+    /// matching patterns, the one with the most literal characters wins, and
+    /// among those the first in alphabetical order. A nested repository without a matching entry
+    /// inherits the ACL of its closest parent, e.g. `org/team/host1` falls back
+    /// to `org/team` and then to `org`.
+    fn repo_acl(&self, path: &str, user: Option<&str>) -> Option<(&str, &RepoAcl)> {
+        let mut path = path;
+
+        loop {
+            if let Some((key, repo_acl)) = self
+                .repos
+                .get_key_value(path)
+                .filter(|(key, _)| !is_pattern(key))
+            {
+                return Some((key, repo_acl));
+            }
+
+            // `max_by_key` returns the last of equal elements, so iterate in reverse
+            // to prefer the first key in alphabetical order
+            let best = self
+                .patterns
+                .iter()
+                .rev()
+                .filter_map(|(key, pattern)| {
+                    pattern
+                        .specificity(path, user)
+                        .map(|specificity| (specificity, key))
+                })
+                .max_by_key(|(specificity, _)| *specificity);
+            if let Some((key, repo_acl)) =
+                best.and_then(|(_, key)| self.repos.get_key_value(key.as_str()))
+            {
+                return Some((key, repo_acl));
+            }
+
+            path = path.rsplit_once('/')?.0;
+        }
+    }
+
     pub fn default_repo_access(&mut self, user: &str, access: AccessType) {
         // If we do not have a key with ""-value then "default" is also not a key
         // Since we guarantee this during the reading of a acl-file
@@ -195,33 +560,52 @@ impl Acl {
     }
 }
 
+/// Returns the access a request of `access_type` to `tpe` needs
+///
+/// Locks have their own semantics: reading and creating a lock, and removing
+/// one's own lock, need `Read`; removing the lock of someone else, which
+/// handlers request as `ForceUnlock`, needs `ForceUnlock`.
+pub(crate) fn lock_access(tpe: Option<TpeKind>, access_type: AccessType) -> AccessType {
+    match access_type {
+        AccessType::NoAccess | AccessType::ForceUnlock => access_type,
+        _ if tpe == Some(TpeKind::Locks) => AccessType::Read,
+        _ => access_type,
+    }
+}
+
+/// Returns if `path` is the private repository of `user` or lies below it,
+/// e.g. `bob` and `bob/host1` both belong to the user `bob`
+pub(crate) fn is_user_namespace(user: &str, path: &str) -> bool {
+    path.strip_prefix(user)
+        .is_some_and(|rest| rest.is_empty() || (!user.is_empty() && rest.starts_with('/')))
+}
+
 impl AclChecker for Acl {
     // allowed yields whether these access to {path, tpe, access} is allowed by user
+    fn is_allowed(&self, user: &str, path: &str, tpe: Option<TpeKind>, access: AccessType) -> bool {
+        self.is_allowed_in_groups(user, &[], path, tpe, access)
+    }
+
     #[tracing::instrument(level = "debug", skip(self))]
-    fn is_allowed(
+    fn is_allowed_in_groups(
         &self,
         user: &str,
+        groups: &[String],
         path: &str,
         tpe: Option<TpeKind>,
         access_type: AccessType,
-        // _force_unlock: bool,
     ) -> bool {
-        // Access to locks is always treated as Read
-        // FIXME: This is a bit of a hack, we should probably have a separate access type for locks
-        // FIXME: to be able to force remove them with `unlock`
-        let access_type = if tpe.is_some_and(|v| v == TpeKind::Locks) {
-            AccessType::Read
-        } else {
-            access_type
-        };
+        let access_type = lock_access(tpe, access_type);
 
-        self.repos.get(path).map_or_else(
+        self.repo_acl(path, Some(user)).map_or_else(
             || {
                 debug!("No ACL for repository found, applying default ACL.");
 
-                let is_user_path = user == path;
+                let is_user_path = is_user_namespace(user, path);
                 let is_not_private_repo = !self.private_repo;
-                let is_not_modify_access = access_type != AccessType::Modify;
+                // Removing the locks of others counts as modifying, except in the user's own namespace
+                let is_not_modify_access = access_type != AccessType::Modify
+                    && (access_type != AccessType::ForceUnlock || is_user_path);
                 let is_not_append_only = !self.append_only;
 
                 debug!(%is_user_path, %is_not_private_repo, %is_not_modify_access, %is_not_append_only);
@@ -235,9 +619,26 @@ impl AclChecker for Acl {
 
                 access
             },
-            |repo_acl| {
-                let access =
-                    matches!(repo_acl.get(user), Some(user_access) if user_access >= &access_type);
+            |(key, repo_acl)| {
+                let group_access = groups
+                    .iter()
+                    .map(String::as_str)
+                    .chain(self.groups_of(user))
+                    .filter_map(|group| repo_acl.get(&format!("{GROUP_PREFIX}{group}")));
+                // Users can't take the access of a group by their name
+                let user_access = repo_acl
+                    .get(user)
+                    .or_else(|| {
+                        key.contains(USER_PLACEHOLDER)
+                            .then(|| repo_acl.get(USER_PLACEHOLDER))
+                            .flatten()
+                    })
+                    .filter(|_| !user.starts_with(GROUP_PREFIX));
+                let grants: Vec<_> = user_access.into_iter().chain(group_access).collect();
+
+                // An explicit `NoAccess` wins, otherwise the highest access does
+                let access = !grants.contains(&&AccessType::NoAccess)
+                    && grants.iter().any(|grant| grant.grants(access_type));
 
                 debug!(?repo_acl, %access, "Access check");
 
@@ -245,6 +646,42 @@ impl AclChecker for Acl {
             },
         )
     }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn is_allowed_anonymous(
+        &self,
+        policy: AnonymousAccess,
+        path: &str,
+        tpe: Option<TpeKind>,
+        access_type: AccessType,
+    ) -> bool {
+        let access_type = lock_access(tpe, access_type);
+
+        if policy == AnonymousAccess::ReadOnly && !AccessType::Read.grants(access_type) {
+            return false;
+        }
+
+        // There is no namespace of the anonymous user, so private repositories don't apply
+        let access = self.repo_acl(path, None).map_or_else(
+            || match policy {
+                AnonymousAccess::Acl => false,
+                AnonymousAccess::ReadOnly => true,
+                AnonymousAccess::Full => {
+                    !matches!(access_type, AccessType::Modify | AccessType::ForceUnlock)
+                        || !self.append_only
+                }
+            },
+            |(_, repo_acl)| {
+                repo_acl
+                    .get(ANONYMOUS_USER)
+                    .is_some_and(|anonymous_access| anonymous_access.grants(access_type))
+            },
+        );
+
+        debug!(%access, "Anonymous access check");
+
+        access
+    }
 }
 
 #[cfg(test)]
@@ -263,7 +700,7 @@ mod tests {
 
         init_acl(auth).unwrap();
 
-        let acl = ACL.get().unwrap();
+        let acl = ACL.get().unwrap().load();
         assert!(&acl.private_repo);
         assert!(!&acl.append_only);
         let access = acl.repos.get("test_repo").unwrap();
@@ -301,6 +738,29 @@ mod tests {
         assert!(acl.is_allowed("bob", "", Some(TpeKind::Data), Modify));
     }
 
+    #[test]
+    fn test_private_repo_namespace_passes() {
+        let mut acl = Acl::default().set_append_only(false);
+
+        // the user's namespace
+        assert!(acl.is_allowed("bob", "bob", Some(TpeKind::Data), Modify));
+        assert!(acl.is_allowed("bob", "bob/host1", Some(TpeKind::Data), Modify));
+        assert!(acl.is_allowed("bob", "bob/host1/home", Some(TpeKind::Keys), Read));
+
+        // other namespaces
+        assert!(!acl.is_allowed("bob", "bobby", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("bob", "sam/bob", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("bob", "sam/host1", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("", "sam/host1", Some(TpeKind::Data), Read));
+
+        // an explicit ACL entry takes precedence over the namespace
+        let mut acl_host = RepoAcl::new();
+        _ = acl_host.insert("sam".to_string(), Append);
+        _ = acl.repos.insert("bob/shared".to_string(), acl_host);
+        assert!(!acl.is_allowed("bob", "bob/shared", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("sam", "bob/shared/host1", Some(TpeKind::Data), Append));
+    }
+
     #[test]
     fn test_repo_acl_passes() {
         let mut acl = Acl::default();
@@ -349,4 +809,375 @@ mod tests {
         assert!(acl.is_allowed("paul", "paul", Some(TpeKind::Data), Append));
         assert!(!acl.is_allowed("paul", "paul", Some(TpeKind::Data), Modify));
     }
+
+    #[test]
+    fn test_nested_repo_acl_passes() {
+        let mut acl = Acl::default();
+
+        let mut acl_org = RepoAcl::new();
+        _ = acl_org.insert("bob".to_string(), Read);
+        _ = acl.repos.insert("org".to_string(), acl_org);
+
+        let mut acl_team = RepoAcl::new();
+        _ = acl_team.insert("bob".to_string(), Append);
+        _ = acl_team.insert("sam".to_string(), Modify);
+        _ = acl.repos.insert("org/team".to_string(), acl_team);
+
+        // repositories inherit the ACL of their closest parent
+        assert!(acl.is_allowed("bob", "org/other", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("bob", "org/other", Some(TpeKind::Data), Append));
+        assert!(acl.is_allowed("bob", "org/team/host1", Some(TpeKind::Data), Append));
+        assert!(acl.is_allowed("sam", "org/team/host1/sub", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("sam", "org/other", Some(TpeKind::Data), Read));
+
+        // a prefix has to match whole segments
+        assert!(!acl.is_allowed("bob", "organisation", Some(TpeKind::Data), Read));
+    }
+
+    #[test]
+    fn test_group_repo_acl_passes() {
+        let mut acl = Acl::default();
+
+        let mut acl_all = RepoAcl::new();
+        _ = acl_all.insert("@backup".to_string(), Append);
+        _ = acl_all.insert("@admins".to_string(), Modify);
+        _ = acl_all.insert("paul".to_string(), Read);
+        _ = acl.repos.insert("all".to_string(), acl_all);
+
+        let backup = ["backup".to_string()];
+        let admins = ["users".to_string(), "admins".to_string()];
+
+        assert!(acl.is_allowed_in_groups("bob", &backup, "all", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed_in_groups("bob", &backup, "all", Some(TpeKind::Data), Modify));
+        assert!(acl.is_allowed_in_groups("sam", &admins, "all", Some(TpeKind::Data), Modify));
+        assert!(acl.is_allowed_in_groups("paul", &backup, "all", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("bob", "all", Some(TpeKind::Data), Read));
+
+        // a user named like a group doesn't get its access
+        assert!(!acl.is_allowed("@backup", "all", Some(TpeKind::Data), Read));
+    }
+
+    #[test]
+    fn test_anonymous_access_passes() {
+        let mut acl = Acl::default();
+
+        let mut acl_mirror = RepoAcl::new();
+        _ = acl_mirror.insert(ANONYMOUS_USER.to_string(), Read);
+        _ = acl_mirror.insert("bob".to_string(), Modify);
+        _ = acl.repos.insert("mirrors".to_string(), acl_mirror);
+
+        let mut acl_bob = RepoAcl::new();
+        _ = acl_bob.insert("bob".to_string(), Modify);
+        _ = acl.repos.insert("bob".to_string(), acl_bob);
+
+        let anonymous =
+            |policy, path, tpe, access| acl.is_allowed_anonymous(policy, path, tpe, access);
+
+        // only repositories naming the anonymous user
+        let policy = AnonymousAccess::Acl;
+        assert!(anonymous(policy, "mirrors", Some(TpeKind::Data), Read));
+        assert!(anonymous(
+            policy,
+            "mirrors/debian",
+            Some(TpeKind::Locks),
+            Append
+        ));
+        assert!(!anonymous(policy, "mirrors", Some(TpeKind::Data), Append));
+        assert!(!anonymous(policy, "bob", Some(TpeKind::Data), Read));
+        assert!(!anonymous(policy, "sam", Some(TpeKind::Data), Read));
+        assert!(!anonymous(policy, "", Some(TpeKind::Data), Read));
+
+        // reading everything the ACL doesn't restrict
+        let policy = AnonymousAccess::ReadOnly;
+        assert!(anonymous(policy, "mirrors", Some(TpeKind::Data), Read));
+        assert!(anonymous(policy, "sam", Some(TpeKind::Data), Read));
+        assert!(!anonymous(policy, "sam", Some(TpeKind::Data), Append));
+        assert!(!anonymous(policy, "bob", Some(TpeKind::Data), Read));
+
+        // everything the ACL doesn't restrict, private repositories don't apply
+        let policy = AnonymousAccess::Full;
+        assert!(anonymous(policy, "sam", Some(TpeKind::Data), Append));
+        assert!(!anonymous(policy, "sam", Some(TpeKind::Data), Modify));
+        assert!(!anonymous(policy, "mirrors", Some(TpeKind::Data), Append));
+        assert!(!anonymous(policy, "bob", Some(TpeKind::Data), Read));
+        acl.append_only = false;
+        assert!(acl.is_allowed_anonymous(policy, "sam", Some(TpeKind::Data), Modify));
+    }
+
+    #[test]
+    fn test_group_acl_passes() {
+        let dir = PathBuf::from("tests/generated/test_acl_groups");
+        fs::create_dir_all(&dir).unwrap();
+        let acl_path = dir.join("acl.toml");
+
+        fs::write(
+            &acl_path,
+            r#"
+[groups]
+team = ["bob", "sam"]
+admins = ["paul"]
+interns = ["sam"]
+
+[all]
+"@team" = "Append"
+"@admins" = "Modify"
+
+[team]
+"@team" = "Modify"
+"@interns" = "NoAccess"
+
+[paul]
+paul = "Read"
+"@admins" = "Modify"
+"#,
+        )
+        .unwrap();
+
+        let acl = Acl::from_file(true, true, Some(acl_path.clone())).unwrap();
+        insta::assert_debug_snapshot!(acl);
+
+        // members of a group get its access
+        assert!(acl.is_allowed("bob", "all", Some(TpeKind::Data), Append));
+        assert!(acl.is_allowed("sam", "all/host1", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("bob", "all", Some(TpeKind::Data), Modify));
+        assert!(acl.is_allowed("paul", "all", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("alex", "all", Some(TpeKind::Data), Read));
+
+        // an explicit `NoAccess` wins
+        assert!(acl.is_allowed("bob", "team", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("sam", "team", Some(TpeKind::Data), Read));
+
+        // otherwise the highest access wins
+        assert!(acl.is_allowed("paul", "paul", Some(TpeKind::Data), Modify));
+
+        // groups of the ACL file and of the authentication backend add up
+        let interns = ["interns".to_string()];
+        assert!(!acl.is_allowed_in_groups("bob", &interns, "team", Some(TpeKind::Data), Read));
+
+        // the groups survive a roundtrip
+        acl.to_file(&acl_path).unwrap();
+        let reread = Acl::from_file(true, true, Some(acl_path.clone())).unwrap();
+        assert_eq!(reread.groups, acl.groups);
+        assert!(!reread.repos.contains_key("groups"));
+
+        // groups can't be nested
+        fs::write(&acl_path, "[groups]\nteam = [\"@admins\"]\n").unwrap();
+        assert!(Acl::from_file(true, true, Some(acl_path)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_pattern_acl_passes() {
+        let dir = PathBuf::from("tests/generated/test_acl_patterns");
+        fs::create_dir_all(&dir).unwrap();
+        let acl_path = dir.join("acl.toml");
+
+        fs::write(
+            &acl_path,
+            r#"
+["hosts-*"]
+backup = "Append"
+
+["hosts-db*"]
+dba = "Modify"
+
+["hosts-db1"]
+paul = "Read"
+
+["db-[0-9][0-9]"]
+dba = "Read"
+
+["db-1*"]
+dba = "Modify"
+
+["team/**"]
+"@team" = "Modify"
+
+["{user}/**"]
+"{user}" = "Modify"
+admin = "Read"
+
+[mirror]
+anonymous = "Read"
+
+["mirror-*"]
+anonymous = "Read"
+"#,
+        )
+        .unwrap();
+
+        let acl = Acl::from_file(true, true, Some(acl_path.clone())).unwrap();
+
+        // `*` doesn't match across levels, but nested repositories inherit
+        assert!(acl.is_allowed("backup", "hosts-web", Some(TpeKind::Data), Append));
+        assert!(acl.is_allowed("backup", "hosts-web/home", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("backup", "hosts", Some(TpeKind::Data), Read));
+
+        // an exact entry wins over patterns, the most specific pattern over others
+        assert!(acl.is_allowed("paul", "hosts-db1", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("dba", "hosts-db1", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("dba", "hosts-db2", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("backup", "hosts-db2", Some(TpeKind::Data), Append));
+        // the characters a bracket expression matches aren't literal
+        assert!(acl.is_allowed("dba", "db-12", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("dba", "db-22", Some(TpeKind::Data), Modify));
+
+        // `**` matches any number of levels
+        let team = ["team".to_string()];
+        assert!(acl.is_allowed_in_groups("bob", &team, "team/a/b", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "team/a", Some(TpeKind::Data), Read));
+
+        // `{user}` is the requesting user, also within the ACL of the pattern
+        assert!(acl.is_allowed("bob", "bob/host1", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "sam/host1", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("admin", "admin/host1", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("admin", "bob/host1", Some(TpeKind::Data), Read));
+        // and the pattern is compiled once per user
+        let Some(RepoPattern::PerUser { compiled, .. }) = acl.patterns.get("{user}/**") else {
+            panic!("`{{user}}/**` is not a pattern with `{{user}}`");
+        };
+        let users = compiled.lock().unwrap().len();
+        assert!(acl.is_allowed("bob", "bob/host2", Some(TpeKind::Data), Modify));
+        assert!(compiled.lock().unwrap().contains_key("bob"));
+        assert_eq!(compiled.lock().unwrap().len(), users);
+
+        // special characters of the user name are matched literally
+        assert!(!acl.is_allowed("b*", "bob/host1", Some(TpeKind::Data), Read));
+
+        // a repository named like a pattern doesn't get the ACL of its key
+        assert!(!acl.is_allowed("sam", "{user}/**", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("sam", "{user}/**/x", Some(TpeKind::Data), Read));
+
+        // patterns with `{user}` don't apply without credentials
+        let policy = AnonymousAccess::Acl;
+        assert!(acl.is_allowed_anonymous(policy, "mirror-eu", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed_anonymous(policy, "anonymous/x", Some(TpeKind::Data), Read));
+
+        // invalid patterns are refused
+        for invalid in [
+            "[\"hosts-[a\"]\nbob = \"Read\"\n",
+            "[\"{users}/**\"]\nbob = \"Read\"\n",
+            "[team]\n\"{user}\" = \"Read\"\n",
+        ] {
+            fs::write(&acl_path, invalid).unwrap();
+            assert!(Acl::from_file(true, true, Some(acl_path.clone())).is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_literal_count_passes() {
+        assert_eq!(literal_count("hosts-db*"), 8);
+        assert_eq!(literal_count("db-[0-9]?"), 3);
+        assert_eq!(literal_count(&Pattern::escape("b*[x]")), 5);
+        assert_eq!(literal_count("[]]x"), 2);
+        assert_eq!(literal_count("[!a]x"), 1);
+    }
+
+    #[test]
+    fn test_access_type_matrix_passes() {
+        use AccessType::{ForceUnlock, NoAccess};
+
+        // rows: granted access, columns: requested Read, Append, Modify, ForceUnlock
+        let matrix = [
+            (NoAccess, [false, false, false, false]),
+            (ForceUnlock, [true, false, false, true]),
+            (Read, [true, false, false, false]),
+            (Append, [true, true, false, false]),
+            (Modify, [true, true, true, true]),
+        ];
+
+        for (granted, expected) in matrix {
+            for (requested, expected) in [Read, Append, Modify, ForceUnlock]
+                .into_iter()
+                .zip(expected)
+            {
+                assert_eq!(
+                    granted.grants(requested),
+                    expected,
+                    "{granted:?} granting {requested:?}"
+                );
+            }
+        }
+    }
+
+    #[rstest]
+    // reading locks and creating a lock or removing one's own need `Read`
+    #[case(AccessType::NoAccess, [false, false, false])]
+    // removing the lock of someone else needs `ForceUnlock`
+    #[case(AccessType::ForceUnlock, [true, true, true])]
+    #[case(Read, [true, true, false])]
+    #[case(Append, [true, true, false])]
+    #[case(Modify, [true, true, true])]
+    fn test_lock_access_matrix_passes(#[case] granted: AccessType, #[case] expected: [bool; 3]) {
+        let mut acl = Acl::default();
+        let mut repo_acl = RepoAcl::new();
+        _ = repo_acl.insert("bob".to_string(), granted);
+        _ = acl.repos.insert("repo".to_string(), repo_acl);
+
+        // the requests of the handlers: list or get, create or remove own, remove other's
+        let requested = [Read, Append, AccessType::ForceUnlock];
+        for (requested, expected) in requested.into_iter().zip(expected) {
+            assert_eq!(
+                acl.is_allowed("bob", "repo", Some(TpeKind::Locks), requested),
+                expected,
+                "{granted:?} granting {requested:?} to locks"
+            );
+        }
+
+        // `ForceUnlock` doesn't allow to change other files
+        let expected = granted >= Append;
+        assert_eq!(
+            acl.is_allowed("bob", "repo", Some(TpeKind::Data), Append),
+            expected
+        );
+        assert_eq!(
+            acl.is_allowed("bob", "repo", Some(TpeKind::Data), Modify),
+            granted == Modify
+        );
+
+        // without an ACL entry, only the owner of a namespace removes the locks of others
+        assert!(acl.is_allowed("bob", "bob", Some(TpeKind::Locks), AccessType::ForceUnlock));
+        acl.private_repo = false;
+        assert!(!acl.is_allowed("sam", "bob", Some(TpeKind::Locks), AccessType::ForceUnlock));
+        assert!(acl.is_allowed("sam", "bob", Some(TpeKind::Locks), Append));
+    }
+
+    #[test]
+    fn test_acl_quota_section_passes() {
+        let dir = PathBuf::from("tests/generated/test_acl_quota");
+        fs::create_dir_all(&dir).unwrap();
+        let acl_path = dir.join("acl.toml");
+
+        fs::write(
+            &acl_path,
+            r#"
+[quota.repos]
+gold = 1000
+
+[quota.users]
+restic = 500
+
+[gold]
+restic = "Append"
+"#,
+        )
+        .unwrap();
+
+        let acl = Acl::from_file(false, true, Some(acl_path.clone())).unwrap();
+        assert_eq!(acl.quota().repos.get("gold"), Some(&1000));
+        assert_eq!(acl.quota().users.get("restic"), Some(&500));
+        assert!(!acl.repos.contains_key("quota"));
+        assert!(acl.is_allowed("restic", "gold", Some(TpeKind::Data), Append));
+
+        // the quota section survives a roundtrip
+        acl.to_file(&acl_path).unwrap();
+        let reread = Acl::from_file(false, true, Some(acl_path)).unwrap();
+        assert_eq!(reread.quota(), acl.quota());
+        assert_eq!(reread.repos.len(), acl.repos.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
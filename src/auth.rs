@@ -1,33 +1,202 @@
-use std::{borrow::Borrow, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use abscissa_core::SecretString;
-use axum::{extract::FromRequestParts, http::request::Parts};
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
 use axum_auth::AuthBasic;
+use rand::{thread_rng, RngCore};
 use serde_derive::Deserialize;
-use std::sync::OnceLock;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::{info, warn};
 
 use crate::{
+    acl::{AnonymousAccess, ANONYMOUS_USER},
     config::HtpasswdSettings,
-    error::{ApiErrorKind, ApiResult, AppResult},
-    htpasswd::{CredentialMap, Htpasswd},
+    error::{ApiErrorKind, ApiResult, AppResult, ErrorKind},
+    htpasswd::{Credential, CredentialMap, HashAlgorithm, Htpasswd},
+    jwt::{bearer_token, replace_jwt, JwtIdentity, JWT},
+    lockout::{check_lockout, lockout_client_ip, record_login_failure, record_login_success},
+    metrics::{record_auth_failure, AuthFailure},
+    proxy_auth::proxy_auth_user,
+    tls::ClientCertificate,
+    tokens::{verify_token, TokenScope},
 };
 
 // Static storage of our credentials
-pub static AUTH: OnceLock<Auth> = OnceLock::new();
+pub static AUTH: OnceLock<ArcSwap<Auth>> = OnceLock::new();
 
 pub(crate) fn init_auth(auth: Auth) -> AppResult<()> {
-    let _ = AUTH.get_or_init(|| auth);
+    let _ = AUTH.get_or_init(|| ArcSwap::from_pointee(auth));
     Ok(())
 }
 
+/// Replaces the credentials of the running server, e.g. after the htpasswd file has been changed
+pub fn replace_auth(auth: Auth) {
+    if let Some(current) = AUTH.get() {
+        current.store(Arc::new(auth));
+    }
+}
+
+// Serializes rewriting the htpasswd file when upgrading hashes
+static UPGRADE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Default time a successful verification is cached
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Maximum number of cached verifications
+const CACHE_CAPACITY: usize = 1024;
+
+/// Cache of successful verifications, so slow hashes aren't recomputed for every request
+///
+/// Passwords aren't kept, only a digest of the password, the user and the stored hash,
+/// keyed with a random secret. As the stored hash is part of the digest, changed
+/// credentials never match an old entry. Besides that, every new `Auth` starts with
+/// an empty cache, so it is dropped whenever the credential store changes.
+struct VerificationCache {
+    ttl: Duration,
+    capacity: usize,
+    secret: [u8; 32],
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+struct CacheEntry {
+    digest: [u8; 32],
+    inserted: Instant,
+    groups: Vec<String>,
+}
+
+impl VerificationCache {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        let mut secret = [0; 32];
+        thread_rng().fill_bytes(&mut secret);
+
+        Self {
+            ttl,
+            capacity,
+            secret,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn digest(&self, user: &str, stored: &str, passwd: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in [&self.secret, user.as_bytes(), stored.as_bytes()] {
+            hasher.update(part);
+            hasher.update([0]);
+        }
+        hasher.update(passwd.as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn contains(&self, user: &str, credential: &Credential, passwd: &str) -> bool {
+        self.lookup(user, credential.hash(), passwd).is_some()
+    }
+
+    fn insert(&self, user: &str, credential: &Credential, passwd: &str) {
+        self.store(user, credential.hash(), passwd, Vec::new());
+    }
+
+    /// Returns the cached groups of `user`, if `passwd` has been verified against `stored`
+    fn lookup(&self, user: &str, stored: &str, passwd: &str) -> Option<Vec<String>> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        let digest = self.digest(user, stored, passwd);
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(user)
+            .filter(|entry| entry.digest == digest && entry.inserted.elapsed() < self.ttl)
+            .map(|entry| entry.groups.clone())
+    }
+
+    fn store(&self, user: &str, stored: &str, passwd: &str, groups: Vec<String>) {
+        if self.ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+
+        let digest = self.digest(user, stored, passwd);
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity && !entries.contains_key(user) {
+            entries.retain(|_, entry| entry.inserted.elapsed() < self.ttl);
+
+            // Still full, so drop the oldest entry
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.inserted)
+                    .map(|(user, _)| user.clone());
+                if let Some(oldest) = oldest {
+                    let _ = entries.remove(&oldest);
+                }
+            }
+        }
+
+        let _ = entries.insert(
+            user.to_string(),
+            CacheEntry {
+                digest,
+                inserted: Instant::now(),
+                groups,
+            },
+        );
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+impl Default for VerificationCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_TTL, CACHE_CAPACITY)
+    }
+}
+
+impl std::fmt::Debug for VerificationCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerificationCache")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A source of credentials other than the htpasswd file, e.g. an LDAP directory
+#[async_trait::async_trait]
+pub trait AuthBackend: Send + Sync + std::fmt::Debug + 'static {
+    /// Returns the groups of `user`, if `passwd` is correct
+    async fn authenticate(&self, user: &str, passwd: &str) -> AppResult<Option<Vec<String>>>;
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Auth {
     users: Option<CredentialMap>,
+    path: Option<PathBuf>,
+    upgrade_hashes: bool,
+    cache: Arc<VerificationCache>,
+    backend: Option<Arc<dyn AuthBackend>>,
+    anonymous: Option<AnonymousAccess>,
 }
 
 impl From<CredentialMap> for Auth {
     fn from(users: CredentialMap) -> Self {
-        Self { users: Some(users) }
+        Self {
+            users: Some(users),
+            ..Default::default()
+        }
     }
 }
 
@@ -35,6 +204,8 @@ impl From<Htpasswd> for Auth {
     fn from(htpasswd: Htpasswd) -> Self {
         Self {
             users: Some(htpasswd.credentials),
+            path: Some(htpasswd.path),
+            ..Default::default()
         }
     }
 }
@@ -48,67 +219,400 @@ impl Auth {
         })
     }
 
+    /// Verifies credentials with `backend` instead of the htpasswd file
+    pub fn from_backend(backend: impl AuthBackend) -> Self {
+        Self {
+            backend: Some(Arc::new(backend)),
+            ..Default::default()
+        }
+    }
+
     pub fn from_config(settings: &HtpasswdSettings, path: PathBuf) -> AppResult<Self> {
-        Self::from_file(settings.is_disabled(), &path)
+        Ok(Self::from_file(settings.is_disabled(), &path)?.set_settings(settings))
+    }
+
+    /// Applies the settings which don't concern loading the credentials
+    pub fn set_settings(self, settings: &HtpasswdSettings) -> Self {
+        self.set_upgrade_hashes(settings.upgrade_hashes)
+            .set_cache_ttl(
+                settings
+                    .auth_cache_ttl
+                    .map_or(DEFAULT_CACHE_TTL, Duration::from_secs),
+            )
+            .set_anonymous_access(settings.anonymous_access_or_default())
+    }
+
+    /// Rehash weak hashes with bcrypt after a successful login
+    pub fn set_upgrade_hashes(mut self, upgrade_hashes: bool) -> Self {
+        self.upgrade_hashes = upgrade_hashes;
+        self
+    }
+
+    /// Cache successful verifications for `ttl`, a zero `ttl` disables the cache
+    pub fn set_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache = Arc::new(VerificationCache::new(ttl, CACHE_CAPACITY));
+        self
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        self.cache.ttl
+    }
+
+    /// Let clients without credentials in as the anonymous user, `None` requires credentials
+    pub fn set_anonymous_access(mut self, anonymous: Option<AnonymousAccess>) -> Self {
+        self.anonymous = anonymous;
+        self
+    }
+
+    pub const fn anonymous_access(&self) -> Option<AnonymousAccess> {
+        self.anonymous
     }
 
     // verify verifies user/passwd against the credentials saved in users.
-    // returns true if Auth::users is None.
+    // returns false if authentication is disabled or a backend verifies credentials.
+    // Password hashes are slow on purpose, so async callers should verify on a blocking thread.
     pub fn verify(&self, user: impl Into<String>, passwd: impl Into<String>) -> bool {
         let user = user.into();
         let passwd = passwd.into();
 
-        self.users.as_ref().map_or(true, |users| matches!(users.get(&user), Some(passwd_data) if htpasswd_verify::Htpasswd::from(passwd_data.to_string().borrow()).check(user, passwd)))
+        self.users.as_ref().is_some_and(|users| {
+            let Some(credential) = users.get(&user) else {
+                return false;
+            };
+
+            if self.cache.contains(&user, credential, &passwd) {
+                return true;
+            }
+
+            let verified = credential.verify(&passwd);
+            if verified {
+                self.cache.insert(&user, credential, &passwd);
+            }
+
+            verified
+        })
+    }
+
+    /// Returns the groups of `user` if `passwd` is correct, asking the backend if there is one
+    pub async fn authenticate(&self, user: &str, passwd: &str) -> AppResult<Option<Vec<String>>> {
+        let Some(backend) = &self.backend else {
+            return Ok(self.verify(user, passwd).then(Vec::new));
+        };
+
+        if let Some(groups) = self.cache.lookup(user, "", passwd) {
+            return Ok(Some(groups));
+        }
+
+        let groups = backend.authenticate(user, passwd).await?;
+        if let Some(groups) = &groups {
+            self.cache.store(user, "", passwd, groups.clone());
+        }
+
+        Ok(groups)
     }
 
     pub const fn is_disabled(&self) -> bool {
-        self.users.is_none()
+        self.users.is_none() && self.backend.is_none()
+    }
+
+    /// `true` if a backend verifies credentials instead of the htpasswd file
+    pub const fn has_backend(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// `true` if the hash of `user` should be upgraded after a successful login
+    pub fn needs_upgrade(&self, user: &str) -> bool {
+        self.upgrade_hashes
+            && self.path.is_some()
+            && self
+                .users
+                .as_ref()
+                .and_then(|users| users.get(user))
+                .is_some_and(Credential::is_weak)
+    }
+
+    /// Rehashes the verified password of `user` with bcrypt and writes it to the htpasswd file
+    ///
+    /// Returns the credentials including the new hash, or `None` if there was nothing to upgrade.
+    pub fn upgrade_hash(&self, user: &str, passwd: &str) -> AppResult<Option<Self>> {
+        let Some(path) = self.path.as_ref().filter(|_| self.needs_upgrade(user)) else {
+            return Ok(None);
+        };
+
+        let _lock = UPGRADE_LOCK.lock().unwrap();
+
+        // The file might have been changed since it has been loaded
+        let mut htpasswd = Htpasswd::from_file_strict(path)?;
+        if !htpasswd
+            .read(user)
+            .is_some_and(|credential| credential.is_weak() && credential.verify(passwd))
+        {
+            return Ok(None);
+        }
+
+        htpasswd.update(user, passwd, HashAlgorithm::default())?;
+        htpasswd
+            .to_file()
+            .map_err(|err| ErrorKind::Io.context(err.to_string()))?;
+
+        Ok(Some(
+            Self::from(htpasswd)
+                .set_upgrade_hashes(true)
+                .set_cache_ttl(self.cache_ttl())
+                .set_anonymous_access(self.anonymous),
+        ))
+    }
+}
+
+/// User a request has been authenticated as
+///
+/// A middleware can insert this into a request before it is routed, the
+/// authentication extractor then fills it in. This way, e.g. the access log
+/// knows the user once the handler has run.
+#[derive(Debug, Clone, Default)]
+pub struct AuthenticatedUser(Arc<OnceLock<String>>);
+
+impl AuthenticatedUser {
+    pub fn get(&self) -> Option<&str> {
+        self.0.get().map(String::as_str)
+    }
+
+    fn set_for(parts: &Parts, user: &str) {
+        if let Some(authenticated) = parts.extensions.get::<Self>() {
+            let _ = authenticated.0.set(user.to_string());
+        }
     }
 }
 
+/// User a request is authenticated as
+///
+/// The extractor supports several schemes, which are tried in this order: a
+/// verified TLS client certificate, the header of a trusted proxy, a bearer
+/// token (JWT) and basic authentication with a password or an API token.
 #[derive(Deserialize, Debug)]
-pub struct BasicAuthFromRequest {
+pub struct AuthFromRequest {
     pub(crate) user: String,
     pub(crate) _password: SecretString,
+    /// Limits of the API token the user has authenticated with, `None` for a password
+    #[serde(skip)]
+    pub(crate) scope: Option<TokenScope>,
+    /// Groups of the user, as known to the authentication backend
+    #[serde(skip)]
+    pub(crate) groups: Vec<String>,
+    /// Policy for a client without credentials, `None` for an authenticated user
+    #[serde(skip)]
+    pub(crate) anonymous: Option<AnonymousAccess>,
 }
 
-#[async_trait::async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for BasicAuthFromRequest {
-    type Rejection = ApiErrorKind;
+impl AuthFromRequest {
+    /// A user authenticated by other means than a password
+    fn without_password(user: String, groups: Vec<String>) -> Self {
+        Self {
+            user,
+            _password: String::new().into(),
+            scope: None,
+            groups,
+            anonymous: None,
+        }
+    }
 
-    // FIXME: We also have a configuration flag do run without authentication
-    // This must be handled here too ... otherwise we get an Auth header missing error.
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> ApiResult<Self> {
-        let checker = AUTH.get().unwrap();
+    /// A client without credentials
+    pub(crate) fn anonymous(policy: AnonymousAccess) -> Self {
+        Self {
+            user: ANONYMOUS_USER.to_string(),
+            _password: String::new().into(),
+            scope: None,
+            groups: Vec::new(),
+            anonymous: Some(policy),
+        }
+    }
+}
+
+/// Verifies `password` as an API token of `user` and then against the password hash
+///
+/// Both hash the password and block, so they run together on a blocking thread.
+/// Returns the scope of a matching token and whether the password is correct.
+async fn verify_locally(
+    checker: Arc<Auth>,
+    user: String,
+    password: String,
+) -> ApiResult<(Option<TokenScope>, bool)> {
+    tokio::task::spawn_blocking(move || {
+        let scope = verify_token(&user, &password);
+        let verified = scope.is_none() && checker.verify(user, password);
+        (scope, verified)
+    })
+    .await
+    .map_err(|err| ApiErrorKind::InternalError(err.to_string()))
+}
+
+/// Refuses a real user with the name of clients without credentials, who would get their access
+fn refuse_anonymous_name(user: &str) -> ApiResult<()> {
+    if user == ANONYMOUS_USER {
+        warn!(%user, "[AUTH] refused user with the name of anonymous clients");
+        record_auth_failure(AuthFailure::InvalidCredentials);
+        return Err(ApiErrorKind::UserAuthenticationError(user.to_string()));
+    }
+    Ok(())
+}
+
+impl AuthFromRequest {
+    /// Authenticates a request with the credentials of `checker`
+    async fn from_parts_with<S: Send + Sync>(
+        parts: &mut Parts,
+        state: &S,
+        checker: Arc<Auth>,
+    ) -> ApiResult<Self> {
+        // A verified client certificate authenticates the user without a password
+        if let Some(user) = parts
+            .extensions
+            .get::<ClientCertificate>()
+            .and_then(|cert| cert.user.clone())
+        {
+            tracing::debug!(%user, "[AUTH] client certificate");
+            refuse_anonymous_name(&user)?;
+            AuthenticatedUser::set_for(parts, &user);
+            return Ok(Self::without_password(user, Vec::new()));
+        }
+
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        // A trusted proxy has authenticated the user already
+        if let Some(user) = proxy_auth_user(&parts.headers, ip)? {
+            tracing::debug!(%user, "[AUTH] proxy authentication");
+            refuse_anonymous_name(&user)?;
+            AuthenticatedUser::set_for(parts, &user);
+            return Ok(Self::without_password(user, Vec::new()));
+        }
 
-        let auth_result = AuthBasic::from_request_parts(parts, state).await;
+        // A bearer token of the identity provider
+        if let Some(token) = bearer_token(&parts.headers) {
+            if let Some(mut jwt) = JWT.get().map(ArcSwap::load_full) {
+                // The issuer may have rotated its keys
+                if let Some(refreshed) = jwt.refresh_for(token).await {
+                    jwt = Arc::new(refreshed);
+                    replace_jwt((*jwt).clone());
+                }
+
+                return match jwt.verify(token) {
+                    Ok(JwtIdentity { user, groups }) => {
+                        tracing::debug!(%user, ?groups, "[AUTH] bearer token");
+                        refuse_anonymous_name(&user)?;
+                        AuthenticatedUser::set_for(parts, &user);
+                        Ok(Self::without_password(user, groups))
+                    }
+                    Err(err) => {
+                        warn!(%err, "[AUTH] invalid bearer token");
+                        record_auth_failure(AuthFailure::InvalidToken);
+                        Err(ApiErrorKind::InvalidBearerToken(err))
+                    }
+                };
+            }
+        }
+
+        // Without authentication there is nothing to check credentials against,
+        // so they are ignored and every client is anonymous
+        let auth_result = if checker.is_disabled() {
+            None
+        } else {
+            AuthBasic::from_request_parts(parts, state).await.ok()
+        };
 
         tracing::debug!(?auth_result, "[AUTH]");
 
-        return match auth_result {
-            Ok(auth) => {
+        match auth_result {
+            Some(auth) => {
                 let AuthBasic((user, passw)) = auth;
                 let password = passw.unwrap_or_else(String::new);
-                if checker.verify(user.as_str(), password.as_str()) {
+                refuse_anonymous_name(&user)?;
+
+                // Behind a trusted proxy, the proxy's address is not the client's
+                let client_ip = lockout_client_ip(&parts.headers, ip);
+
+                if let Some(retry_after) = check_lockout(&user, client_ip) {
+                    return Err(ApiErrorKind::TooManyAuthenticationFailures(
+                        retry_after.as_secs().max(1),
+                    ));
+                }
+
+                let (scope, verified) =
+                    verify_locally(Arc::clone(&checker), user.clone(), password.clone()).await?;
+                let groups = if scope.is_some() || verified {
+                    Some(Vec::new())
+                } else if checker.has_backend() {
+                    checker
+                        .authenticate(&user, &password)
+                        .await
+                        .map_err(|err| {
+                            // The details stay in the log, they may name internal hosts
+                            warn!(%user, %err, "[AUTH] authentication backend failed");
+                            ApiErrorKind::AuthenticationBackendUnavailable
+                        })?
+                } else {
+                    None
+                };
+
+                if let Some(groups) = groups {
+                    record_login_success(&user, client_ip);
+                    if scope.is_none() && checker.needs_upgrade(&user) {
+                        // Hashing and rewriting the htpasswd file block
+                        let (upgrade_user, upgrade_password) = (user.clone(), password.clone());
+                        let upgraded = tokio::task::spawn_blocking(move || {
+                            checker.upgrade_hash(&upgrade_user, &upgrade_password)
+                        })
+                        .await;
+
+                        match upgraded {
+                            Ok(Ok(Some(upgraded))) => {
+                                replace_auth(upgraded);
+                                info!(%user, "[AUTH] upgraded password hash");
+                            }
+                            Ok(Ok(None)) => {}
+                            Ok(Err(err)) => {
+                                warn!(%user, %err, "[AUTH] could not upgrade password hash")
+                            }
+                            Err(err) => {
+                                warn!(%user, %err, "[AUTH] could not upgrade password hash")
+                            }
+                        }
+                    }
+                    AuthenticatedUser::set_for(parts, &user);
                     Ok(Self {
                         user,
                         _password: password.into(),
+                        scope,
+                        groups,
+                        anonymous: None,
                     })
                 } else {
+                    record_auth_failure(AuthFailure::InvalidCredentials);
+                    record_login_failure(&user, client_ip);
                     Err(ApiErrorKind::UserAuthenticationError(user))
                 }
             }
-            Err(_) => {
-                let user = String::new();
-                if checker.verify("", "") {
-                    return Ok(Self {
-                        user,
-                        _password: String::new().into(),
-                    });
+            None => {
+                // Without credentials, e.g. with `--no-auth` or for public repositories
+                if let Some(policy) = checker.anonymous_access() {
+                    tracing::debug!(%policy, "[AUTH] anonymous");
+                    return Ok(Self::anonymous(policy));
                 }
+                record_auth_failure(AuthFailure::MissingHeader);
                 Err(ApiErrorKind::AuthenticationHeaderError)
             }
-        };
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthFromRequest {
+    type Rejection = ApiErrorKind;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> ApiResult<Self> {
+        let checker = AUTH.get().unwrap().load_full();
+        Self::from_parts_with(parts, state, checker).await
     }
 }
 
@@ -116,14 +620,24 @@ impl<S: Send + Sync> FromRequestParts<S> for BasicAuthFromRequest {
 mod test {
     use super::*;
 
-    use crate::testing::{basic_auth_header_value, init_test_environment, server_config};
+    use std::path::Path;
+
+    use crate::{
+        acl::AccessType,
+        handlers::access_check::check_auth_and_acl,
+        jwt::{init_jwt, test::Issuer, JwtAuth},
+        proxy_auth::{init_proxy_auth, ProxyAuth},
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+        tokens::{replace_tokens, TokenStore},
+        typed_path::TpeKind,
+    };
 
     use anyhow::Result;
     use axum::{
         body::Body,
         http::{Method, Request, StatusCode},
         routing::get,
-        Router,
+        Extension, Router,
     };
     use http_body_util::BodyExt;
     use rstest::{fixture, rstest};
@@ -143,11 +657,111 @@ mod test {
         Ok(())
     }
 
+    #[rstest]
+    fn test_verification_cache_passes(auth: Auth) {
+        assert!(auth.verify("rustic", "rustic"));
+        assert!(!auth.verify("restic", "_restic"));
+        // only successful verifications are cached
+        assert_eq!(auth.cache.len(), 1);
+
+        // cached passwords are still checked
+        assert!(auth.verify("rustic", "rustic"));
+        assert!(!auth.verify("rustic", "_rustic"));
+
+        // a changed credential store starts with an empty cache
+        let reloaded =
+            Auth::from_file(false, &PathBuf::from("tests/fixtures/test_data/.htpasswd")).unwrap();
+        assert_eq!(reloaded.cache.len(), 0);
+
+        let disabled = auth.clone().set_cache_ttl(Duration::ZERO);
+        assert!(disabled.verify("rustic", "rustic"));
+        assert_eq!(disabled.cache.len(), 0);
+    }
+
+    #[test]
+    fn test_verification_cache_expires_and_is_bounded_passes() {
+        let credential = Credential::new("rustic", "rustic", HashAlgorithm::Md5).unwrap();
+        let changed = Credential::new("rustic", "rustic", HashAlgorithm::Md5).unwrap();
+
+        let cache = VerificationCache::new(Duration::from_millis(50), 2);
+        cache.insert("rustic", &credential, "rustic");
+        assert!(cache.contains("rustic", &credential, "rustic"));
+        assert!(!cache.contains("rustic", &credential, "_rustic"));
+        // a new hash for the same password doesn't match
+        assert!(!cache.contains("rustic", &changed, "rustic"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!cache.contains("rustic", &credential, "rustic"));
+
+        let cache = VerificationCache::new(Duration::from_secs(60), 2);
+        for user in ["a", "b", "c"] {
+            cache.insert(user, &credential, "rustic");
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains("a", &credential, "rustic"));
+        assert!(cache.contains("c", &credential, "rustic"));
+    }
+
+    /// Knows `rustic` in group `backup` and counts its lookups
+    #[derive(Debug, Default)]
+    struct CountingBackend(std::sync::atomic::AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl AuthBackend for CountingBackend {
+        async fn authenticate(&self, user: &str, passwd: &str) -> AppResult<Option<Vec<String>>> {
+            let _ = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok((user == "rustic" && passwd == "rustic").then(|| vec!["backup".to_string()]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_backend_passes() {
+        let backend = Arc::new(CountingBackend::default());
+        let auth = Auth {
+            backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        assert!(!auth.is_disabled());
+        assert!(!auth.verify("rustic", "rustic"));
+
+        let groups = auth.authenticate("rustic", "rustic").await.unwrap();
+        assert_eq!(groups, Some(vec!["backup".to_string()]));
+        assert_eq!(auth.authenticate("rustic", "_rustic").await.unwrap(), None);
+
+        // the groups of a successful login are cached
+        let groups = auth.authenticate("rustic", "rustic").await.unwrap();
+        assert_eq!(groups, Some(vec!["backup".to_string()]));
+        assert_eq!(backend.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_anonymous_access_passes() {
+        let htpasswd = PathBuf::from("tests/fixtures/test_data/.htpasswd");
+
+        let settings = HtpasswdSettings::default();
+        let auth = Auth::from_config(&settings, htpasswd.clone()).unwrap();
+        assert_eq!(auth.anonymous_access(), None);
+
+        let settings = HtpasswdSettings {
+            anonymous_access: Some(AnonymousAccess::Acl),
+            ..Default::default()
+        };
+        let auth = Auth::from_config(&settings, htpasswd.clone()).unwrap();
+        assert_eq!(auth.anonymous_access(), Some(AnonymousAccess::Acl));
+
+        let settings = HtpasswdSettings {
+            disable_auth: true,
+            ..Default::default()
+        };
+        let auth = Auth::from_config(&settings, htpasswd).unwrap();
+        assert_eq!(auth.anonymous_access(), Some(AnonymousAccess::Full));
+    }
+
     #[rstest]
     fn test_auth_from_file_passes(auth: Auth) {
         init_auth(auth).unwrap();
 
-        let auth = AUTH.get().unwrap();
+        let auth = AUTH.get().unwrap().load();
         assert!(auth.verify("rustic", "rustic"));
         assert!(!auth.verify("rustic", "_rustic"));
     }
@@ -156,7 +770,7 @@ mod test {
         format!("Got {} and {:?}", id, password)
     }
 
-    async fn format_handler_from_auth_request(auth: BasicAuthFromRequest) -> String {
+    async fn format_handler_from_auth_request(auth: AuthFromRequest) -> String {
         format!("User = {}", auth.user)
     }
 
@@ -215,6 +829,241 @@ mod test {
         assert_eq!(body_str, String::from("User = rustic"));
     }
 
+    async fn check_token_scope(auth: AuthFromRequest) -> String {
+        let allowed = |repo: &str, access| {
+            check_auth_and_acl(&auth, TpeKind::Data, Path::new(repo), access).is_ok()
+        };
+
+        format!(
+            "{} {} {}",
+            allowed("test_repo", AccessType::Read),
+            allowed("test_repo", AccessType::Append),
+            allowed("ci_repo", AccessType::Read),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_token_authentication_passes() {
+        init_test_environment(server_config());
+
+        let mut tokens = TokenStore::default();
+        let scope = TokenScope {
+            repos: vec!["test_repo".to_string()],
+            access: AccessType::Read,
+        };
+        let (token, _) = tokens.create("rustic", scope, None, String::new());
+        replace_tokens(tokens);
+
+        let app = Router::new().route("/rustic_server", get(check_token_scope));
+
+        // the token is limited to reading `test_repo`
+        let request = Request::builder()
+            .uri("/rustic_server")
+            .method(Method::GET)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some(&token)),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "true false false");
+
+        // a token is only valid for its user
+        let request = Request::builder()
+            .uri("/rustic_server")
+            .method(Method::GET)
+            .header(
+                "Authorization",
+                basic_auth_header_value("restic", Some(&token)),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_authentication_passes() {
+        init_test_environment(server_config());
+
+        // The acceptor adds the user of a verified client certificate to every request
+        let app = Router::new()
+            .route("/rustic_server", get(format_handler_from_auth_request))
+            .layer(Extension(ClientCertificate {
+                user: Some("rustic".to_string()),
+            }));
+
+        let request = Request::builder()
+            .uri("/rustic_server")
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "User = rustic");
+
+        // Without a usable name, the password is still required
+        let app = Router::new()
+            .route("/rustic_server", get(format_handler_from_auth_request))
+            .layer(Extension(ClientCertificate::default()));
+
+        let request = Request::builder()
+            .uri("/rustic_server")
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_authentication_passes() {
+        init_test_environment(server_config());
+        init_proxy_auth(ProxyAuth::new("Remote-User", vec!["127.0.0.1".parse().unwrap()]).unwrap())
+            .unwrap();
+
+        let request = || {
+            Request::builder()
+                .uri("/rustic_server")
+                .method(Method::GET)
+                .header("Remote-User", "restic")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // The header of a trusted proxy names the user
+        let app = Router::new()
+            .route("/rustic_server", get(format_handler_from_auth_request))
+            .layer(Extension(ConnectInfo(SocketAddr::from((
+                [127, 0, 0, 1],
+                4242,
+            )))));
+
+        let resp = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "User = restic");
+
+        // A header set more than once is refused
+        let mut duplicated = request();
+        let _ = duplicated
+            .headers_mut()
+            .append("Remote-User", "rustic".parse().unwrap());
+        let resp = app.clone().oneshot(duplicated).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // A user can't pose as a client without credentials
+        let mut anonymous = request();
+        let _ = anonymous
+            .headers_mut()
+            .insert("Remote-User", ANONYMOUS_USER.parse().unwrap());
+        let resp = app.oneshot(anonymous).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Anyone else has to log in
+        let app = Router::new()
+            .route("/rustic_server", get(format_handler_from_auth_request))
+            .layer(Extension(ConnectInfo(SocketAddr::from((
+                [192, 0, 2, 1],
+                4242,
+            )))));
+
+        let resp = app.oneshot(request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_auth_ignores_credentials_passes() {
+        init_test_environment(server_config());
+
+        let disabled = Arc::new(Auth::default().set_anonymous_access(Some(AnonymousAccess::Full)));
+        let (mut parts, ()) = Request::builder()
+            .uri("/test_repo/data")
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("x")),
+            )
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Naming a user of the ACL doesn't give its access without authentication
+        let auth = AuthFromRequest::from_parts_with(&mut parts, &(), disabled)
+            .await
+            .unwrap();
+        assert_eq!(auth.user, ANONYMOUS_USER);
+        assert_eq!(auth.anonymous, Some(AnonymousAccess::Full));
+        let path = Path::new("test_repo");
+        assert!(check_auth_and_acl(&auth, TpeKind::Data, path, AccessType::Append).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bearer_authentication_passes() {
+        init_test_environment(server_config());
+
+        let issuer = Issuer::new();
+        let dir = PathBuf::from("tests/generated/test_bearer_authentication");
+        std::fs::create_dir_all(&dir).unwrap();
+        let jwks = dir.join("jwks.json");
+        std::fs::write(&jwks, issuer.jwks().to_string()).unwrap();
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some(jwks.display().to_string()),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        init_jwt(jwt.reload().await.unwrap()).unwrap();
+
+        async fn format_groups(auth: AuthFromRequest) -> String {
+            format!("User = {}, groups = {:?}", auth.user, auth.groups)
+        }
+
+        let request = |token: &str| {
+            Request::builder()
+                .uri("/rustic_server")
+                .method(Method::GET)
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let app = Router::new().route("/rustic_server", get(format_groups));
+
+        let token = issuer.token(&serde_json::json!({
+            "sub": "restic",
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "groups": ["backup"],
+        }));
+        let resp = app.clone().oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"User = restic, groups = ["backup"]"#);
+
+        // the name of clients without credentials is refused
+        let anonymous = issuer.token(&serde_json::json!({
+            "sub": ANONYMOUS_USER,
+            "exp": chrono::Utc::now().timestamp() + 300,
+        }));
+        let resp = app.clone().oneshot(request(&anonymous)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // the signature covers the claims
+        let (message, _) = token.rsplit_once('.').unwrap();
+        let resp = app
+            .oneshot(request(&format!("{message}.AAAA")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[tokio::test]
     async fn test_fail_authentication_passes() {
         init_test_environment(server_config());
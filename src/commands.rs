@@ -11,10 +11,15 @@
 //! application's configuration file.
 
 mod auth;
+mod janitor;
+mod quota;
 mod serve;
+mod token;
 
 use crate::{
-    commands::{auth::AuthCmd, serve::ServeCmd},
+    commands::{
+        auth::AuthCmd, janitor::JanitorCmd, quota::QuotaCmd, serve::ServeCmd, token::TokenCmd,
+    },
     config::RusticServerConfig,
 };
 use abscissa_core::{
@@ -36,8 +41,17 @@ pub enum RusticServerCmd {
     /// Authentication for users. Add, update, delete, or list users.
     Auth(AuthCmd),
 
+    /// Remove stale uploads and empty repositories from the data directory
+    Janitor(JanitorCmd),
+
+    /// Show the usage and quota of all repositories
+    Quota(QuotaCmd),
+
     /// Start a server with the specified configuration
     Serve(ServeCmd),
+
+    /// API tokens for users. Create, list, or revoke tokens.
+    Token(TokenCmd),
 }
 
 fn styles() -> Styles {
@@ -109,6 +123,8 @@ impl Configurable<RusticServerConfig> for EntryPoint {
         config: RusticServerConfig,
     ) -> Result<RusticServerConfig, FrameworkError> {
         match &self.cmd {
+            RusticServerCmd::Janitor(cmd) => cmd.override_config(config),
+            RusticServerCmd::Quota(cmd) => cmd.override_config(config),
             RusticServerCmd::Serve(cmd) => cmd.override_config(config),
             _ => Ok(config),
         }
@@ -6,7 +6,10 @@ use abscissa_core::{status_err, Application, Command, Runnable, Shutdown};
 use anyhow::{bail, Result};
 use clap::{Args, Parser, Subcommand};
 
-use crate::{htpasswd::Htpasswd, prelude::RUSTIC_SERVER_APP};
+use crate::{
+    htpasswd::{HashAlgorithm, Htpasswd},
+    prelude::RUSTIC_SERVER_APP,
+};
 
 /// `auth` subcommand
 ///
@@ -55,6 +58,9 @@ struct AddArg {
     /// Password.
     #[arg(short = 'p')]
     password: String,
+    /// Algorithm used to hash the password.
+    #[arg(long, value_enum, default_value_t)]
+    algorithm: HashAlgorithm,
 }
 
 #[derive(Args, Debug)]
@@ -153,7 +159,7 @@ fn add(arg: &AddArg) -> Result<()> {
         );
     }
 
-    let _ = ht_access.update(arg.user.as_str(), arg.password.as_str());
+    ht_access.update(arg.user.as_str(), arg.password.as_str(), arg.algorithm)?;
 
     ht_access.to_file()?;
     Ok(())
@@ -170,7 +176,7 @@ fn update(arg: &AddArg) -> Result<()> {
             arg.user.as_str()
         );
     }
-    let _ = ht_access.update(arg.user.as_str(), arg.password.as_str());
+    ht_access.update(arg.user.as_str(), arg.password.as_str(), arg.algorithm)?;
     ht_access.to_file()?;
     Ok(())
 }
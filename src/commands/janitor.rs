@@ -0,0 +1,103 @@
+//! `janitor` subcommand
+
+use abscissa_core::{
+    config::Override, status_err, Application, Command, FrameworkError, Runnable, Shutdown,
+};
+use anyhow::Result;
+use clap::Parser;
+use conflate::Merge;
+
+use crate::{
+    config::{default_data_dir, RusticServerConfig},
+    prelude::RUSTIC_SERVER_APP,
+    storage::janitor::Janitor,
+};
+
+/// `janitor` subcommand
+///
+/// Removes stale uploads and empty repositories from the data directory.
+#[derive(Command, Debug, Parser)]
+pub struct JanitorCmd {
+    /// Only report what would be removed
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Server settings
+    #[clap(flatten)]
+    context: RusticServerConfig,
+}
+
+impl Override<RusticServerConfig> for JanitorCmd {
+    fn override_config(
+        &self,
+        mut config: RusticServerConfig,
+    ) -> Result<RusticServerConfig, FrameworkError> {
+        config.merge(self.context.clone());
+
+        Ok(config)
+    }
+}
+
+impl Runnable for JanitorCmd {
+    /// Start the application.
+    fn run(&self) {
+        if let Err(err) = self.inner_run() {
+            status_err!("{}", err);
+            RUSTIC_SERVER_APP.shutdown(Shutdown::Crash);
+        }
+    }
+}
+
+impl JanitorCmd {
+    pub fn inner_run(&self) -> Result<()> {
+        let server_config = RUSTIC_SERVER_APP.config();
+
+        let data_dir = server_config
+            .storage
+            .data_dir
+            .clone()
+            .unwrap_or_else(default_data_dir);
+
+        let janitor = Janitor::from_settings(&server_config.storage, &data_dir);
+
+        let report = if self.dry_run {
+            janitor.dry_run()
+        } else {
+            janitor.run()
+        };
+
+        let action = if self.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        };
+
+        println!("Cleaning up the data directory of a rustic_server.");
+        println!("\tData directory used: {}", data_dir.display());
+        println!(
+            "\tGrace period: {} seconds",
+            janitor.grace_period().as_secs()
+        );
+        println!("{action} stale uploads:");
+        for file in &report.staging_files {
+            println!("\t{}", file.display());
+        }
+        println!("{action} empty directories:");
+        for dir in &report.empty_dirs {
+            println!("\t{}", dir.display());
+        }
+        println!("Done.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_janitor() {
+        JanitorCmd::command().debug_assert();
+    }
+}
@@ -0,0 +1,113 @@
+//! `quota` subcommand
+
+use abscissa_core::{
+    config::Override, status_err, Application, Command, FrameworkError, Runnable, Shutdown,
+};
+use anyhow::Result;
+use clap::Parser;
+use conflate::Merge;
+
+use crate::{
+    config::RusticServerConfig,
+    context::ServerRuntimeContext,
+    prelude::RUSTIC_SERVER_APP,
+    quota::Quota,
+    storage::{LocalStorage, Storage},
+};
+
+/// `quota` subcommand
+///
+/// Prints the usage and the quota of all repositories in the data directory,
+/// as well as the configured per-user quotas.
+#[derive(Command, Debug, Parser)]
+pub struct QuotaCmd {
+    /// Server settings
+    #[clap(flatten)]
+    context: RusticServerConfig,
+}
+
+impl Override<RusticServerConfig> for QuotaCmd {
+    fn override_config(
+        &self,
+        mut config: RusticServerConfig,
+    ) -> Result<RusticServerConfig, FrameworkError> {
+        config.merge(self.context.clone());
+
+        Ok(config)
+    }
+}
+
+impl Runnable for QuotaCmd {
+    /// Start the application.
+    fn run(&self) {
+        if let Err(err) = self.inner_run() {
+            status_err!("{}", err);
+            RUSTIC_SERVER_APP.shutdown(Shutdown::Crash);
+        }
+    }
+}
+
+impl QuotaCmd {
+    pub fn inner_run(&self) -> Result<()> {
+        let server_config = RUSTIC_SERVER_APP.config();
+
+        let ServerRuntimeContext {
+            acl,
+            quota,
+            storage,
+            ..
+        }: ServerRuntimeContext<LocalStorage> =
+            ServerRuntimeContext::from_config(server_config.clone())?;
+
+        let quota = Quota::new(quota).with_limits(acl.quota().clone());
+
+        // Every directory holding a `config` file is a repository
+        for repo in storage.repositories() {
+            let _ = quota.usage(&storage, &repo);
+        }
+
+        println!("Repository quotas for a rustic_server.");
+        println!("\tData directory used: {}", storage.path().display());
+        println!("Repositories:");
+        for entry in quota.report() {
+            match entry.limit {
+                Some(limit) => println!(
+                    "\t{}: {} of {} bytes used, {} bytes left",
+                    entry.repo,
+                    entry.usage,
+                    limit,
+                    limit.saturating_sub(entry.usage)
+                ),
+                None => println!("\t{}: {} bytes used, unlimited", entry.repo, entry.usage),
+            }
+        }
+
+        let users = quota.user_report(&storage);
+        if !users.is_empty() {
+            println!("Users:");
+            for entry in users {
+                println!(
+                    "\t{}: {} of {} bytes used, {} bytes left",
+                    entry.user,
+                    entry.usage,
+                    entry.limit,
+                    entry.limit.saturating_sub(entry.usage)
+                );
+            }
+        }
+
+        println!("Done.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_quota() {
+        QuotaCmd::command().debug_assert();
+    }
+}
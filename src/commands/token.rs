@@ -0,0 +1,172 @@
+//! `token` subcommand
+
+use std::path::PathBuf;
+
+use abscissa_core::{status_err, Application, Command, Runnable, Shutdown};
+use anyhow::{bail, Result};
+use chrono::{Duration, Local};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    acl::AccessType,
+    prelude::RUSTIC_SERVER_APP,
+    tokens::{TokenScope, TokenStore},
+};
+
+/// `token` subcommand
+///
+/// Manages the API tokens, which clients can use as the password of a user.
+#[derive(Command, Debug, Parser)]
+pub struct TokenCmd {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+impl Runnable for TokenCmd {
+    /// Start the application.
+    fn run(&self) {
+        if let Err(err) = self.inner_run() {
+            status_err!("{}", err);
+            RUSTIC_SERVER_APP.shutdown(Shutdown::Crash);
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Create a new token and print it. It can't be shown again.
+    Create(CreateArg),
+    /// List all tokens, without their secrets.
+    List(ListArg),
+    /// Revoke a token by its id.
+    Revoke(RevokeArg),
+}
+
+#[derive(Args, Debug)]
+struct CreateArg {
+    /// Path to the token file
+    #[arg(short = 'f')]
+    pub token_path: PathBuf,
+    /// Name of the user the token belongs to.
+    #[arg(short = 'u')]
+    user: String,
+    /// Repository the token is limited to, can be given multiple times. All repositories if not given.
+    #[arg(short = 'r', long = "repo")]
+    repos: Vec<String>,
+    /// Highest access the token grants.
+    #[arg(short = 'a', long, value_enum, default_value = "append")]
+    access: AccessType,
+    /// Days until the token expires, `0` for a token that never expires.
+    #[arg(long, default_value_t = 90)]
+    expires_in: i64,
+    /// Description of the token, e.g. the host using it.
+    #[arg(short = 'd', long, default_value = "")]
+    description: String,
+}
+
+#[derive(Args, Debug)]
+struct ListArg {
+    /// Path to the token file
+    #[arg(short = 'f')]
+    pub token_path: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct RevokeArg {
+    /// Path to the token file
+    #[arg(short = 'f')]
+    pub token_path: PathBuf,
+    /// Id of the token to be revoked.
+    id: String,
+}
+
+impl TokenCmd {
+    pub fn inner_run(&self) -> Result<()> {
+        match &self.command {
+            Commands::Create(arg) => create(arg)?,
+            Commands::List(arg) => list(arg)?,
+            Commands::Revoke(arg) => revoke(arg)?,
+        };
+        Ok(())
+    }
+}
+
+fn create(arg: &CreateArg) -> Result<()> {
+    if arg.access <= AccessType::NoAccess {
+        bail!("A token needs at least `force-unlock` access.");
+    }
+
+    let expires = match arg.expires_in {
+        0 => None,
+        days if days > 0 => Some(Local::now() + Duration::days(days)),
+        _ => bail!("`--expires-in` must not be negative."),
+    };
+
+    let mut store = TokenStore::from_file(&arg.token_path)?;
+    let scope = TokenScope {
+        repos: arg.repos.clone(),
+        access: arg.access,
+    };
+    let (token, id) = store.create(&arg.user, scope, expires, arg.description.clone());
+    store.to_file()?;
+
+    println!("Created token `{id}` for user {}.", arg.user);
+    println!(
+        "Use it as the password of {}, it can't be shown again:",
+        arg.user
+    );
+    println!("{token}");
+    Ok(())
+}
+
+fn list(arg: &ListArg) -> Result<()> {
+    let store = TokenStore::from_file(&arg.token_path)?;
+    let now = Local::now();
+
+    println!("Listing tokens in {}.", arg.token_path.to_string_lossy());
+    for (id, token) in store.tokens() {
+        let repos = if token.scope.repos.is_empty() {
+            "all repositories".to_string()
+        } else {
+            token.scope.repos.join(", ")
+        };
+        let expires = match token.expires {
+            Some(expires) if token.is_expired(now) => format!("expired {}", expires.to_rfc3339()),
+            Some(expires) => format!("expires {}", expires.to_rfc3339()),
+            None => "never expires".to_string(),
+        };
+
+        println!(
+            "\t{id}\t{}\t{:?}\t{repos}\t{expires}\t{}",
+            token.user, token.scope.access, token.description
+        );
+    }
+    println!("Done.");
+    Ok(())
+}
+
+fn revoke(arg: &RevokeArg) -> Result<()> {
+    let mut store = TokenStore::from_file(&arg.token_path)?;
+
+    if store.revoke(&arg.id).is_some() {
+        store.to_file()?;
+        println!("Revoked token {}.", arg.id);
+    } else {
+        println!(
+            "Could not find a token with id {}. No changes were made.",
+            arg.id
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_token() {
+        TokenCmd::command().debug_assert();
+    }
+}
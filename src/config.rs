@@ -15,7 +15,13 @@ use conflate::Merge;
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
-use crate::error::{AppResult, ErrorKind};
+use crate::{
+    acl::AnonymousAccess,
+    error::{AppResult, ErrorKind},
+    proxy_auth::IpNetwork,
+    tls::ClientCertField,
+    tokens::DEFAULT_TOKEN_FILE,
+};
 
 /// `RusticServer` Configuration
 #[derive(Clone, Debug, Deserialize, Serialize, Default, Merge, Parser)]
@@ -44,6 +50,10 @@ pub struct RusticServerConfig {
     /// Optional Logging settings
     #[command(flatten)]
     pub log: LogSettings,
+
+    /// Optional Metrics settings
+    #[command(flatten)]
+    pub metrics: MetricsSettings,
 }
 
 /// Overwrite the left value with the right value unconditionally.
@@ -59,12 +69,21 @@ pub struct ConnectionSettings {
     #[arg(long, env = "RUSTIC_SERVER_LISTEN")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub listen: Option<SocketAddr>,
+
+    /// Optional interval in seconds to check the htpasswd, ACL and TLS files for changes
+    ///
+    /// Changed files are reloaded without a restart. They are always reloaded on `SIGHUP`.
+    #[arg(long, env = "RUSTIC_SERVER_RELOAD_INTERVAL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub reload_interval: Option<u64>,
 }
 
 impl Default for ConnectionSettings {
     fn default() -> Self {
         Self {
             listen: Some(default_socket_address()),
+            reload_interval: None,
         }
     }
 }
@@ -91,6 +110,12 @@ pub struct LogSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub log_file: Option<PathBuf>,
+
+    /// Optional number of bytes of every request and response body to log at debug level
+    #[arg(long, env = "RUSTIC_SERVER_LOG_BODY_PREFIX")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub log_body_prefix: Option<usize>,
 }
 
 impl LogSettings {
@@ -99,6 +124,42 @@ impl LogSettings {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize, Debug, Default, Merge, Parser)]
+#[serde(deny_unknown_fields, default, rename_all = "kebab-case")]
+pub struct MetricsSettings {
+    /// Enable Prometheus metrics at `/metrics`
+    #[arg(long, env = "RUSTIC_SERVER_PROMETHEUS")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub prometheus: bool,
+
+    /// Disable authentication for `/metrics`, otherwise only the user `metrics` may access it
+    #[arg(
+        long,
+        requires = "prometheus",
+        env = "RUSTIC_SERVER_PROMETHEUS_NO_AUTH"
+    )]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub prometheus_no_auth: bool,
+
+    /// Don't label metrics with repository names
+    #[arg(
+        long,
+        requires = "prometheus",
+        env = "RUSTIC_SERVER_PROMETHEUS_NO_REPO_LABEL"
+    )]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub prometheus_no_repo_label: bool,
+}
+
+impl MetricsSettings {
+    pub const fn is_disabled(&self) -> bool {
+        !self.prometheus
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, Merge, Parser)]
 #[serde(deny_unknown_fields, default, rename_all = "kebab-case")]
 pub struct StorageSettings {
@@ -119,6 +180,48 @@ pub struct StorageSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub quota: Option<usize>,
+
+    /// Disable the janitor removing stale uploads and empty repositories
+    #[arg(long = "no-janitor", env = "RUSTIC_SERVER_DISABLE_JANITOR")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub disable_janitor: bool,
+
+    /// Optional interval between two janitor runs in seconds (default: 3600)
+    #[arg(long, env = "RUSTIC_SERVER_JANITOR_INTERVAL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub janitor_interval: Option<u64>,
+
+    /// Optional age in seconds after which the janitor removes leftovers (default: 86400)
+    #[arg(long, env = "RUSTIC_SERVER_JANITOR_GRACE_PERIOD")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub janitor_grace_period: Option<u64>,
+
+    /// Disable the verification of uploaded files against the SHA-256 hash in their name
+    #[arg(long = "no-verify-upload", env = "RUSTIC_SERVER_DISABLE_VERIFY_UPLOAD")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub disable_verify_upload: bool,
+
+    /// Optional interval between two scrubs of all repositories in seconds, disabled if not set
+    #[arg(long, env = "RUSTIC_SERVER_SCRUB_INTERVAL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub scrub_interval: Option<u64>,
+
+    /// Optional maximum read rate of the scrubber in bytes per second (default: 10485760)
+    #[arg(long, env = "RUSTIC_SERVER_SCRUB_RATE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub scrub_rate: Option<u64>,
+
+    /// Optional minimum free disk space in bytes for the server to be ready (default: 1073741824)
+    #[arg(long, env = "RUSTIC_SERVER_MIN_FREE_SPACE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub min_free_space: Option<u64>,
 }
 
 pub(crate) fn default_data_dir() -> PathBuf {
@@ -130,6 +233,13 @@ impl Default for StorageSettings {
         Self {
             data_dir: Some(default_data_dir()),
             quota: None,
+            disable_janitor: false,
+            janitor_interval: None,
+            janitor_grace_period: None,
+            disable_verify_upload: false,
+            scrub_interval: None,
+            scrub_rate: None,
+            min_free_space: None,
         }
     }
 }
@@ -167,6 +277,31 @@ pub struct TlsSettings {
     #[arg(long, requires = "disable_tls", env = "RUSTIC_SERVER_TLS_CERT")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub tls_cert: Option<PathBuf>,
+
+    /// Optional path to CA certificates to verify client certificates with
+    #[arg(long, requires = "disable_tls", env = "RUSTIC_SERVER_TLS_CLIENT_CA")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Name of a client certificate to use as user [default: cn]
+    #[arg(
+        long,
+        value_enum,
+        requires = "tls_client_ca",
+        env = "RUSTIC_SERVER_TLS_CLIENT_USER"
+    )]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub tls_client_user: Option<ClientCertField>,
+
+    /// Authenticate clients only by their certificate, instead of the htpasswd file
+    #[arg(
+        long,
+        requires = "tls_client_ca",
+        env = "RUSTIC_SERVER_TLS_CLIENT_AUTH_ONLY"
+    )]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub tls_client_auth_only: bool,
 }
 
 impl TlsSettings {
@@ -181,6 +316,9 @@ impl Default for TlsSettings {
             disable_tls: true,
             tls_cert: None,
             tls_key: None,
+            tls_client_ca: None,
+            tls_client_user: None,
+            tls_client_auth_only: false,
         }
     }
 }
@@ -200,9 +338,191 @@ pub struct HtpasswdSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub htpasswd_file: Option<PathBuf>,
+
+    /// Rehash passwords stored with a weak algorithm (e.g. MD5) with bcrypt after a successful login
+    #[arg(long, env = "RUSTIC_SERVER_UPGRADE_HASHES")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub upgrade_hashes: bool,
+
+    /// Optional time in seconds successful logins are cached, `0` disables the cache (default: 300)
+    ///
+    /// The cache is cleared whenever the .htpasswd file changes.
+    #[arg(long, env = "RUSTIC_SERVER_AUTH_CACHE_TTL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub auth_cache_ttl: Option<u64>,
+
+    /// Optional number of failed logins of a user from a client IP, or of a client IP, before it is locked out,
+    /// `0` disables lockouts (default: 5)
+    #[arg(long, env = "RUSTIC_SERVER_LOCKOUT_THRESHOLD")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub lockout_threshold: Option<u32>,
+
+    /// Optional duration in seconds of the first lockout, doubling with every further failure
+    /// (default: 60)
+    #[arg(long, env = "RUSTIC_SERVER_LOCKOUT_DURATION")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub lockout_duration: Option<u64>,
+
+    /// Optional file to persist lockouts in, so they survive a restart
+    #[arg(long, env = "RUSTIC_SERVER_LOCKOUT_STATE_FILE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub lockout_state_file: Option<PathBuf>,
+
+    /// Optional location of the API token file (default: "<data directory>/.tokens.toml")
+    #[arg(long, env = "RUSTIC_SERVER_TOKEN_FILE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub token_file: Option<PathBuf>,
+
+    /// Optional header to take the username from, set by an authenticating proxy (e.g. `Remote-User`)
+    #[arg(
+        long,
+        requires = "trusted_proxies",
+        env = "RUSTIC_SERVER_PROXY_AUTH_USERNAME"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub proxy_auth_username: Option<String>,
+
+    /// Addresses or networks of the proxies trusted to set the username and `X-Forwarded-For` headers, e.g. `10.0.0.0/8`
+    #[arg(long, value_delimiter = ',', env = "RUSTIC_SERVER_TRUSTED_PROXIES")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = conflate::vec::overwrite_empty)]
+    pub trusted_proxies: Vec<IpNetwork>,
+
+    /// Optional LDAP server to authenticate users with instead of the htpasswd file, e.g. `ldaps://ldap.example.org`
+    #[arg(long, env = "RUSTIC_SERVER_LDAP_URL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_url: Option<String>,
+
+    /// DN to bind as, `{user}` is replaced by the user (e.g. "uid={user},ou=people,dc=example,dc=org")
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_USER_DN")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_user_dn: Option<String>,
+
+    /// DN to search users below, instead of binding with `--ldap-user-dn`
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_BASE_DN")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_base_dn: Option<String>,
+
+    /// Filter to search users with [default: "(uid={user})"]
+    #[arg(
+        long,
+        requires = "ldap_base_dn",
+        env = "RUSTIC_SERVER_LDAP_USER_FILTER"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_user_filter: Option<String>,
+
+    /// Optional filter the entry of a user has to match, e.g. "(memberOf=cn=backup,ou=groups,dc=example,dc=org)"
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_GROUP_FILTER")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_group_filter: Option<String>,
+
+    /// DN the groups of users are directly below, e.g. "ou=groups,dc=example,dc=org"; LDAP users have no groups without it
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_GROUP_BASE_DN")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_group_base_dn: Option<String>,
+
+    /// Optional DN to bind as to search users, anonymous if not set
+    #[arg(long, requires = "ldap_base_dn", env = "RUSTIC_SERVER_LDAP_BIND_DN")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_bind_dn: Option<String>,
+
+    /// Password of `--ldap-bind-dn`
+    #[arg(
+        long,
+        requires = "ldap_bind_dn",
+        env = "RUSTIC_SERVER_LDAP_BIND_PASSWORD"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_bind_password: Option<String>,
+
+    /// CA certificate of the LDAP server, required for `ldaps://` and `--ldap-starttls`
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_CA_CERT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ldap_ca_cert: Option<PathBuf>,
+
+    /// Upgrade `ldap://` connections to TLS with StartTLS
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_STARTTLS")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub ldap_starttls: bool,
+
+    /// Allow sending passwords over unencrypted `ldap://` connections
+    #[arg(long, requires = "ldap_url", env = "RUSTIC_SERVER_LDAP_ALLOW_INSECURE")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub ldap_allow_insecure: bool,
+
+    /// JWKS file or `https://` URL with the keys of the issuer of bearer tokens (JWTs)
+    #[arg(long, env = "RUSTIC_SERVER_JWT_JWKS")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub jwt_jwks: Option<String>,
+
+    /// Issuer of bearer tokens, its keys are discovered at `<issuer>/.well-known/openid-configuration`
+    /// unless `--jwt-jwks` is set
+    #[arg(long, env = "RUSTIC_SERVER_JWT_ISSUER")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub jwt_issuer: Option<String>,
+
+    /// Optional audience bearer tokens have to be issued for
+    #[arg(long, env = "RUSTIC_SERVER_JWT_AUDIENCE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub jwt_audience: Option<String>,
+
+    /// Optional CA certificate of the issuer, in addition to the public root certificates
+    #[arg(long, env = "RUSTIC_SERVER_JWT_CA_CERT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub jwt_ca_cert: Option<PathBuf>,
+
+    /// Claim of bearer tokens holding the user [default: "sub"]
+    #[arg(long, env = "RUSTIC_SERVER_JWT_USER_CLAIM")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub jwt_user_claim: Option<String>,
+
+    /// Claim of bearer tokens holding the groups of the user [default: "groups"]
+    #[arg(long, env = "RUSTIC_SERVER_JWT_GROUPS_CLAIM")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub jwt_groups_claim: Option<String>,
+
+    /// Optional access of clients without credentials: `acl` for the repositories the ACL grants
+    /// `anonymous` access to, `read-only` for reading all repositories, or `full`
+    /// (default: none, or `full` with `--no-auth`)
+    #[arg(long, value_enum, env = "RUSTIC_SERVER_ANONYMOUS_ACCESS")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub anonymous_access: Option<AnonymousAccess>,
 }
 
 impl HtpasswdSettings {
+    /// Returns the token file, it doesn't have to exist
+    pub fn token_file_or_default(&self, data_dir: PathBuf) -> PathBuf {
+        self.token_file
+            .clone()
+            .unwrap_or_else(|| data_dir.join(DEFAULT_TOKEN_FILE))
+    }
+
     pub fn htpasswd_file_or_default(&self, data_dir: PathBuf) -> AppResult<PathBuf> {
         let default_file_name = ".htpasswd";
         let path = self.htpasswd_file.clone().unwrap_or_else(|| {
@@ -240,6 +560,12 @@ impl HtpasswdSettings {
     pub const fn is_disabled(&self) -> bool {
         self.disable_auth
     }
+
+    /// Access of clients without credentials, `None` if they have to authenticate
+    pub fn anonymous_access_or_default(&self) -> Option<AnonymousAccess> {
+        self.anonymous_access
+            .or_else(|| self.is_disabled().then_some(AnonymousAccess::Full))
+    }
 }
 
 // This assumes that it makes no sense to have one but not the other
@@ -254,7 +580,7 @@ pub struct AclSettings {
     #[merge(strategy = conflate::bool::overwrite_false)]
     pub disable_acl: bool,
 
-    /// Users can only access their private repositories
+    /// Users can only access their private repositories, i.e. `/<user>` and everything below `/<user>/`
     #[arg(long, default_value = "true", env = "RUSTIC_SERVER_PRIVATE_REPOS")]
     #[serde(skip)]
     #[merge(strategy = conflate::bool::overwrite_false)]
@@ -3,6 +3,7 @@ use std::{
     net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use abscissa_core::prelude::{debug, info};
@@ -10,14 +11,27 @@ use serde::{Deserialize, Serialize};
 use tracing::warn;
 
 use crate::{
-    acl::Acl,
+    acl::{Acl, ANONYMOUS_USER},
     auth::Auth,
     config::{
         default_data_dir, default_socket_address, AclSettings, HtpasswdSettings, LogSettings,
-        RusticServerConfig, TlsSettings,
+        MetricsSettings, RusticServerConfig, StorageSettings, TlsSettings,
     },
     error::{AppResult, ErrorKind},
-    storage::Storage,
+    handlers::health::DEFAULT_MIN_FREE_SPACE,
+    htpasswd::CredentialMap,
+    jwt::JwtAuth,
+    ldap::LdapAuth,
+    lockout::Lockout,
+    proxy_auth::ProxyAuth,
+    reload::Reloader,
+    storage::{
+        janitor::Janitor,
+        scrubber::{Scrubber, DEFAULT_RATE},
+        Storage,
+    },
+    tls::ClientCertField,
+    tokens::{migrate_legacy_token_file, TokenStore},
 };
 
 #[derive(Clone, Serialize, Deserialize, Default, Debug)]
@@ -28,6 +42,15 @@ pub struct TlsOptions {
 
     /// Optional path to the TLS certificate file
     pub tls_cert: PathBuf,
+
+    /// Optional path to the CA certificates client certificates are verified with
+    pub client_ca: Option<PathBuf>,
+
+    /// Name of a client certificate which is the user
+    pub client_user: ClientCertField,
+
+    /// Authenticate clients only by their certificate
+    pub client_auth_only: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -35,12 +58,24 @@ pub struct ServerRuntimeContext<S>
 where
     S: Storage + Clone + std::fmt::Debug,
 {
+    pub(crate) access_log: Option<PathBuf>,
     pub(crate) acl: Acl,
     pub(crate) auth: Auth,
-    pub(crate) _quota: usize,
+    pub(crate) janitor: Option<Janitor>,
+    pub(crate) jwt: Option<JwtAuth>,
+    pub(crate) lockout: Option<Lockout>,
+    pub(crate) log_body_prefix: usize,
+    pub(crate) metrics: Option<MetricsSettings>,
+    pub(crate) min_free_space: u64,
+    pub(crate) proxy_auth: Option<ProxyAuth>,
+    pub(crate) quota: Option<u64>,
+    pub(crate) reloader: Reloader,
+    pub(crate) scrubber: Option<Scrubber>,
     pub(crate) socket_address: SocketAddr,
     pub(crate) storage: S,
     pub(crate) tls: Option<TlsOptions>,
+    pub(crate) tokens: Option<TokenStore>,
+    pub(crate) verify_upload: bool,
 }
 
 impl<S> ServerRuntimeContext<S>
@@ -61,26 +96,159 @@ where
 
         let quota = Self::quota(config.storage.quota);
 
-        let acl = Self::acl(config.acl.clone(), storage_dir.clone())?;
+        let janitor = Self::janitor(&config.storage, storage_dir.clone());
+
+        let verify_upload = Self::verify_upload(&config.storage);
 
-        let auth = Self::auth(config.auth.clone(), storage_dir.clone())?;
+        let scrubber = Self::scrubber(&config.storage);
+
+        let min_free_space = config
+            .storage
+            .min_free_space
+            .unwrap_or(DEFAULT_MIN_FREE_SPACE);
+
+        let metrics = Self::metrics(config.metrics.clone());
+
+        let access_log = Self::access_log(config.log.clone());
+
+        let log_body_prefix = config.log.log_body_prefix.unwrap_or_default();
+
+        let acl = Self::acl(config.acl.clone(), storage_dir.clone())?;
 
         let tls = Self::tls(config.tls.clone())?;
 
+        let client_auth_only = tls.as_ref().is_some_and(|tls| tls.client_auth_only);
+
+        let auth = Self::auth(config.auth.clone(), client_auth_only, storage_dir.clone())?;
+
+        let lockout = Self::lockout(&config.auth)?;
+
+        let proxy_auth = Self::proxy_auth(&config.auth)?;
+
+        let tokens = Self::tokens(&config.auth, client_auth_only, storage_dir.clone())?;
+
+        let jwt = Self::jwt(&config.auth, client_auth_only)?;
+
+        let reloader = Self::reloader(&config, storage_dir.clone());
+
         let storage = Self::storage(storage_dir)?;
 
         Ok(Self {
+            access_log,
             acl,
             auth,
-            _quota: quota,
+            janitor,
+            jwt,
+            lockout,
+            log_body_prefix,
+            metrics,
+            min_free_space,
+            proxy_auth,
+            quota,
+            reloader,
+            scrubber,
             socket_address,
             storage,
             tls,
+            tokens,
+            verify_upload,
         })
     }
 
-    fn quota(quota: Option<usize>) -> usize {
-        quota.unwrap_or(0)
+    fn quota(quota: Option<usize>) -> Option<u64> {
+        let quota = quota
+            .filter(|quota| *quota > 0)
+            .and_then(|quota| u64::try_from(quota).ok());
+
+        match quota {
+            Some(quota) => info!("Repository quota is enabled: `{quota}` bytes."),
+            None => info!("Repository quota is disabled."),
+        }
+
+        quota
+    }
+
+    fn janitor(storage_settings: &StorageSettings, data_dir: PathBuf) -> Option<Janitor> {
+        if storage_settings.disable_janitor {
+            info!("Janitor is disabled.");
+            return None;
+        }
+
+        let janitor = Janitor::from_settings(storage_settings, data_dir);
+
+        info!(
+            "Janitor is enabled: running every `{}` seconds, removing leftovers older than `{}` seconds.",
+            janitor.interval().as_secs(),
+            janitor.grace_period().as_secs()
+        );
+
+        Some(janitor)
+    }
+
+    fn verify_upload(storage_settings: &StorageSettings) -> bool {
+        if storage_settings.disable_verify_upload {
+            warn!("Upload verification is disabled, corrupted uploads will not be detected.");
+        } else {
+            info!("Upload verification is enabled.");
+        }
+
+        !storage_settings.disable_verify_upload
+    }
+
+    fn scrubber(storage_settings: &StorageSettings) -> Option<Scrubber> {
+        let Some(interval) = storage_settings
+            .scrub_interval
+            .filter(|interval| *interval > 0)
+        else {
+            info!("Scrubber is disabled.");
+            return None;
+        };
+
+        let scrubber = Scrubber::new(
+            Duration::from_secs(interval),
+            storage_settings.scrub_rate.unwrap_or(DEFAULT_RATE),
+        );
+
+        info!(
+            "Scrubber is enabled: running every `{}` seconds, reading at most `{}` bytes per second.",
+            scrubber.interval().as_secs(),
+            scrubber.rate()
+        );
+
+        Some(scrubber)
+    }
+
+    fn metrics(metrics_settings: MetricsSettings) -> Option<MetricsSettings> {
+        if metrics_settings.is_disabled() {
+            info!("Prometheus metrics are disabled.");
+            return None;
+        }
+
+        if metrics_settings.prometheus_no_auth {
+            warn!("Prometheus metrics are enabled at `/metrics` without authentication.");
+        } else {
+            info!("Prometheus metrics are enabled at `/metrics` for the user `metrics`.");
+        }
+
+        Some(metrics_settings)
+    }
+
+    fn reloader(config: &RusticServerConfig, data_dir: PathBuf) -> Reloader {
+        let interval = config
+            .server
+            .reload_interval
+            .filter(|interval| *interval > 0)
+            .map(Duration::from_secs);
+
+        match interval {
+            Some(interval) => info!(
+                "Reloading files on `SIGHUP` and when they change, checking every `{}` seconds.",
+                interval.as_secs()
+            ),
+            None => info!("Reloading files on `SIGHUP`."),
+        }
+
+        Reloader::new(config.auth.clone(), config.acl.clone(), data_dir, interval)
     }
 
     fn storage(data_dir: PathBuf) -> AppResult<S> {
@@ -96,6 +264,11 @@ where
     fn tls(tls_settings: TlsSettings) -> AppResult<Option<TlsOptions>> {
         // TODO: Do we need to validate the TLS settings?
         let tls = if tls_settings.is_disabled() {
+            if tls_settings.tls_client_ca.is_some() {
+                return Err(ErrorKind::Config
+                    .context("A TLS client CA was provided, but TLS is disabled.")
+                    .into());
+            }
             info!("TLS is disabled.");
             None
         } else {
@@ -107,7 +280,27 @@ where
             };
             info!("TLS is enabled.");
 
-            Some(TlsOptions { tls_key, tls_cert })
+            if tls_settings.tls_client_auth_only && tls_settings.tls_client_ca.is_none() {
+                return Err(ErrorKind::Config
+                    .context("Authenticating clients only by their certificate requires a TLS client CA.")
+                    .into());
+            }
+
+            let client_user = tls_settings.tls_client_user.unwrap_or_default();
+            if let Some(client_ca) = &tls_settings.tls_client_ca {
+                info!(
+                    "Client certificates issued by `{}` are accepted, using their `{client_user}` as user.",
+                    client_ca.display()
+                );
+            }
+
+            Some(TlsOptions {
+                tls_key,
+                tls_cert,
+                client_ca: tls_settings.tls_client_ca,
+                client_user,
+                client_auth_only: tls_settings.tls_client_auth_only,
+            })
         };
 
         debug!(?tls, "Loaded TLS settings.");
@@ -116,11 +309,25 @@ where
     }
 
     #[allow(clippy::cognitive_complexity)]
-    fn auth(htpasswd_settings: HtpasswdSettings, data_dir: PathBuf) -> AppResult<Auth> {
-        let auth = if htpasswd_settings.is_disabled() {
+    fn auth(
+        htpasswd_settings: HtpasswdSettings,
+        client_auth_only: bool,
+        data_dir: PathBuf,
+    ) -> AppResult<Auth> {
+        let auth = if client_auth_only {
+            info!("Clients are authenticated by their TLS certificate only, the htpasswd file is not used.");
+            Auth::from(CredentialMap::default())
+        } else if htpasswd_settings.is_disabled() {
             info!("Authentication is disabled.");
             warn!("This allows anyone to push to your repositories. This should be considered insecure and is not recommended for production use.");
-            Auth::default()
+            Auth::default().set_anonymous_access(htpasswd_settings.anonymous_access_or_default())
+        } else if let Some(ldap) = LdapAuth::from_settings(&htpasswd_settings)? {
+            info!(
+                "Authentication is enabled: verifying users against `{}`.",
+                ldap.url()
+            );
+
+            Auth::from_backend(ldap).set_settings(&htpasswd_settings)
         } else {
             info!(
                 "Authentication is enabled by default. If you want to disable it, add `--no-auth`."
@@ -134,11 +341,90 @@ where
             })?
         };
 
+        if let Some(policy) = auth.anonymous_access() {
+            info!("Clients without credentials are allowed as `{ANONYMOUS_USER}` with `{policy}` access.");
+        }
+
         debug!(?auth, "Loaded Auth.");
 
         Ok(auth)
     }
 
+    fn proxy_auth(htpasswd_settings: &HtpasswdSettings) -> AppResult<Option<ProxyAuth>> {
+        let proxy_auth = ProxyAuth::from_settings(htpasswd_settings)?;
+
+        if let Some(proxy_auth) = &proxy_auth {
+            info!(
+                "Proxy authentication is enabled: taking the user from `{}` for requests from `{}`.",
+                proxy_auth.header(),
+                proxy_auth
+                    .trusted_proxies()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(proxy_auth)
+    }
+
+    fn lockout(htpasswd_settings: &HtpasswdSettings) -> AppResult<Option<Lockout>> {
+        if htpasswd_settings.is_disabled() {
+            return Ok(None);
+        }
+
+        let lockout = Lockout::from_settings(htpasswd_settings)?;
+
+        match &lockout {
+            Some(lockout) => info!(
+                "Lockout is enabled: locking out users and addresses for `{}` seconds after `{}` failed logins.",
+                lockout.duration().as_secs(),
+                lockout.threshold()
+            ),
+            None => warn!("Lockout is disabled, failed logins are not limited."),
+        }
+
+        Ok(lockout)
+    }
+
+    fn tokens(
+        htpasswd_settings: &HtpasswdSettings,
+        client_auth_only: bool,
+        data_dir: PathBuf,
+    ) -> AppResult<Option<TokenStore>> {
+        if htpasswd_settings.is_disabled() || client_auth_only {
+            return Ok(None);
+        }
+
+        if htpasswd_settings.token_file.is_none() {
+            migrate_legacy_token_file(&data_dir)?;
+        }
+
+        let path = htpasswd_settings.token_file_or_default(data_dir);
+        let tokens = TokenStore::from_file(&path)?;
+
+        info!(
+            "API tokens are enabled: loaded `{}` tokens from `{}`.",
+            tokens.tokens().count(),
+            path.display()
+        );
+
+        Ok(Some(tokens))
+    }
+
+    fn jwt(
+        htpasswd_settings: &HtpasswdSettings,
+        client_auth_only: bool,
+    ) -> AppResult<Option<JwtAuth>> {
+        if htpasswd_settings.is_disabled() || client_auth_only {
+            return Ok(None);
+        }
+
+        // The keys are loaded when the server starts
+        JwtAuth::from_settings(htpasswd_settings)
+    }
+
     fn data_dir(data_dir: impl Into<PathBuf>) -> AppResult<PathBuf> {
         let data_dir = data_dir.into();
 
@@ -185,17 +471,15 @@ where
         Ok(acl)
     }
 
-    fn _log(log_settings: LogSettings) -> AppResult<LogSettings> {
-        let log = if log_settings.is_disabled() {
-            info!("Logging is set to default.");
-            LogSettings::default()
-        } else {
-            log_settings
-        };
+    fn access_log(log_settings: LogSettings) -> Option<PathBuf> {
+        let access_log = log_settings.log_file;
 
-        debug!(?log, "Loaded LogSettings.");
+        match &access_log {
+            Some(path) => info!("Writing access log to: `{}`", path.display()),
+            None => info!("Access log is disabled."),
+        }
 
-        Ok(log)
+        access_log
     }
 
     pub fn storage_path(&self) -> &Path {
@@ -1,7 +1,7 @@
 //! Error types
 
 use abscissa_core::error::{BoxError, Context};
-use axum::http::StatusCode;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use std::{
     fmt::{self, Display},
@@ -85,14 +85,32 @@ pub enum ApiErrorKind {
     AuthenticationHeaderError,
     /// Failed to authenticate user: `{0}`
     UserAuthenticationError(String),
+    /// Not authenticated as the metrics user
+    MetricsUnauthorized,
+    /// Too many failed logins, retry after `{0}` seconds
+    TooManyAuthenticationFailures(u64),
+    /// Authentication backend unavailable
+    AuthenticationBackendUnavailable,
+    /// Invalid bearer token: `{0}`
+    InvalidBearerToken(String),
     /// General Storage error: `{0}`
     GeneralStorageError(String),
     /// Invalid API version: `{0}`
     InvalidApiVersion(String),
+    /// Repository quota exceeded: `{0}`
+    QuotaExceeded(String),
+    /// Content does not match its hash: `{0}`
+    HashMismatch(String),
 }
 
 impl IntoResponse for ApiErrorKind {
     fn into_response(self) -> Response {
+        let retry_after = match &self {
+            Self::TooManyAuthenticationFailures(seconds) => Some(*seconds),
+            _ => None,
+        };
+        let is_unauthorized = matches!(self, Self::MetricsUnauthorized);
+
         let response = match self {
             Self::InvalidApiVersion(err) => (
                 StatusCode::BAD_REQUEST,
@@ -187,13 +205,50 @@ impl IntoResponse for ApiErrorKind {
                 StatusCode::FORBIDDEN,
                 format!("Failed to authenticate user: {:?}", err),
             ),
+            Self::MetricsUnauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "Not authenticated as the metrics user".to_string(),
+            ),
+            Self::TooManyAuthenticationFailures(seconds) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Too many failed logins, retry after {seconds} seconds"),
+            ),
+            Self::AuthenticationBackendUnavailable => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Authentication backend unavailable".to_string(),
+            ),
+            Self::InvalidBearerToken(err) => (
+                StatusCode::FORBIDDEN,
+                format!("Invalid bearer token: {err}"),
+            ),
             Self::GeneralStorageError(err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Storage error: {:?}", err),
             ),
+            Self::QuotaExceeded(err) => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("Repository quota exceeded: {err}"),
+            ),
+            Self::HashMismatch(err) => (
+                StatusCode::BAD_REQUEST,
+                format!("Content does not match its hash: {err}"),
+            ),
         };
 
-        response.into_response()
+        let mut response = response.into_response();
+        if let Some(seconds) = retry_after {
+            let _ = response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        if is_unauthorized {
+            let _ = response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"metrics\""),
+            );
+        }
+
+        response
     }
 }
 
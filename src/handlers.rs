@@ -4,8 +4,9 @@ pub(crate) mod file_exchange;
 pub(crate) mod file_length;
 pub(crate) mod files_list;
 pub(crate) mod health;
+pub(crate) mod metrics;
 pub(crate) mod repository;
 
 // Support modules
-mod access_check;
+pub(crate) mod access_check;
 pub(crate) mod file_helpers;
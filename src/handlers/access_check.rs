@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Component, Path};
 
 use axum::{http::StatusCode, response::IntoResponse};
 use tracing::debug;
@@ -8,18 +8,33 @@ use strum::VariantNames;
 
 use crate::{
     acl::{AccessType, AclChecker, ACL},
+    auth::AuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     typed_path::TpeKind,
 };
 
 pub fn check_auth_and_acl(
-    user: String,
+    auth: &AuthFromRequest,
     tpe: impl Into<Option<TpeKind>>,
     path: &Path,
     access_type: AccessType,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = tpe.into();
 
+    // only allow plain relative paths, e.g. `org/team/repo`, hidden
+    // directories are reserved for the server itself
+    for component in path.components() {
+        let is_valid = matches!(
+            component,
+            Component::Normal(part) if !part.to_string_lossy().starts_with('.')
+        );
+
+        if !is_valid {
+            debug!("InvalidPath: {:?}", path);
+            return Err(ApiErrorKind::InvalidPath(path.display().to_string()));
+        }
+    }
+
     // don't allow paths that includes any of the defined types
     for part in path.iter() {
         //FIXME: Rewrite to?? -> if TYPES.contains(part) {}
@@ -33,14 +48,21 @@ pub fn check_auth_and_acl(
         }
     }
 
-    let acl = ACL.get().unwrap();
+    let acl = ACL.get().unwrap().load();
     let path = if let Some(path) = path.to_str() {
         path
     } else {
         return Err(ApiErrorKind::NonUnicodePath(path.display().to_string()));
     };
-    let allowed = acl.is_allowed(&user, path, tpe, access_type);
-    tracing::debug!(name: "auth", %user, %path, "type" = ?tpe, allowed);
+    let user = &auth.user;
+    let allowed = match auth.anonymous {
+        Some(policy) => acl.is_allowed_anonymous(policy, path, tpe, access_type),
+        None => acl.is_allowed_in_groups(user, &auth.groups, path, tpe, access_type),
+    } && auth
+        .scope
+        .as_ref()
+        .map_or(true, |scope| scope.allows(path, tpe, access_type));
+    tracing::debug!(name: "auth", %user, %path, "type" = ?tpe, token = auth.scope.is_some(), allowed);
 
     match allowed {
         true => Ok(StatusCode::OK),
@@ -1,19 +1,26 @@
 use std::path::{Path, PathBuf};
 
 use axum::{extract::Request, http::header, response::IntoResponse};
-use axum_extra::{headers::Range, TypedHeader};
+use axum_extra::{
+    headers::{ContentLength, Range},
+    TypedHeader,
+};
 use axum_macros::debug_handler;
 use axum_range::{KnownSize, Ranged};
 
 use crate::typed_path::PathParts;
 use crate::{
     acl::AccessType,
-    auth::BasicAuthFromRequest,
+    auth::AuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     handlers::{
         access_check::check_auth_and_acl,
-        file_exchange::{check_name, get_save_file, save_body},
+        file_exchange::{
+            check_name, file_size, get_save_file, record_response_download, save_body, update_quota,
+        },
     },
+    metrics::record_upload,
+    quota::{Reservation, QUOTA},
     storage::STORAGE,
     typed_path::{RepositoryConfigPath, TpeKind},
 };
@@ -23,7 +30,7 @@ use crate::{
 #[debug_handler]
 pub async fn has_config(
     RepositoryConfigPath { repo }: RepositoryConfigPath,
-    BasicAuthFromRequest { user, .. }: BasicAuthFromRequest,
+    auth: AuthFromRequest,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = TpeKind::Config;
 
@@ -31,7 +38,7 @@ pub async fn has_config(
 
     let path = Path::new(&repo);
 
-    let _ = check_auth_and_acl(user, tpe, path, AccessType::Read)?;
+    let _ = check_auth_and_acl(&auth, tpe, path, AccessType::Read)?;
 
     let storage = STORAGE.get().unwrap();
 
@@ -57,7 +64,7 @@ pub async fn has_config(
 /// Interface: GET {repo}/config
 pub async fn get_config<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
     range: Option<TypedHeader<Range>>,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = TpeKind::Config;
@@ -69,7 +76,7 @@ pub async fn get_config<P: PathParts>(
     let _ = check_name(tpe, None)?;
     let path = Path::new(&repo);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let _ = check_auth_and_acl(&auth, tpe, path, AccessType::Read)?;
 
     let storage = STORAGE.get().unwrap();
     let file = storage.open_file(path, tpe.into_str(), None).await?;
@@ -78,25 +85,33 @@ pub async fn get_config<P: PathParts>(
         .await
         .map_err(|err| ApiErrorKind::GettingFileMetadataFailed(format!("{err:?}")))?;
     let range = range.map(|TypedHeader(range)| range);
-    Ok(Ranged::new(range, body).into_response())
+    let response = Ranged::new(range, body).into_response();
+
+    Ok(record_response_download(response, path, &auth.user))
 }
 
 /// `add_config`
 /// Interface: POST {repo}/config
 pub async fn add_config<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
+    content_length: Option<TypedHeader<ContentLength>>,
     request: Request,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = TpeKind::Config;
     let repo = path.repo().unwrap();
     tracing::debug!("[add_config] repository path: {repo}, tpe: {tpe}");
     let path = PathBuf::from(&repo);
-    let file = get_save_file(auth.user, path, Some(tpe), None).await?;
+    let content_length = content_length.map(|TypedHeader(ContentLength(length))| length);
+    let (file, reservation) =
+        get_save_file(&auth, path.clone(), Some(tpe), None, content_length).await?;
 
     let stream = request.into_body().into_data_stream();
-    let _ = save_body(file, stream).await?;
-    Ok(())
+    let limit = reservation.as_ref().and_then(Reservation::limit);
+    let written = save_body(file, stream, limit).await?;
+    record_upload(&path, &auth.user, written);
+
+    Ok(update_quota(&auth.user, &path, written, reservation))
 }
 
 /// `delete_config`
@@ -104,7 +119,7 @@ pub async fn add_config<P: PathParts>(
 #[allow(dead_code)]
 pub async fn delete_config<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = TpeKind::Config;
     let repo = path.repo().unwrap();
@@ -112,13 +127,15 @@ pub async fn delete_config<P: PathParts>(
 
     let _ = check_name(tpe, None)?;
     let path = Path::new(&repo);
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Append)?;
+    let _ = check_auth_and_acl(&auth, tpe, path, AccessType::Append)?;
 
     let storage = STORAGE.get().unwrap();
+    let length = file_size(storage.filename(path, tpe.into_str(), None)).await;
     storage
         .remove_file(path, tpe.into_str(), None)
         .await
         .map_err(|err| ApiErrorKind::RemovingFileFailed(format!("{err:?}")))?;
+    QUOTA.get().unwrap().remove_usage(path, length);
     Ok(())
 }
 
@@ -4,20 +4,34 @@ use std::{
     result::Result,
 };
 
-use axum::{body::Bytes, extract::Request, http::StatusCode, response::IntoResponse, BoxError};
-use axum_extra::{headers::Range, TypedHeader};
+use axum::{
+    body::Bytes,
+    extract::Request,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use axum_extra::{
+    headers::{ContentLength, Range},
+    TypedHeader,
+};
 use axum_range::{KnownSize, Ranged};
 use futures::{Stream, TryStreamExt};
 use futures_util::pin_mut;
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncReadExt, AsyncWrite};
 use tokio_util::io::StreamReader;
 
 use crate::{
     acl::AccessType,
-    auth::BasicAuthFromRequest,
+    auth::AuthFromRequest,
     error::{ApiErrorKind, ApiResult},
-    handlers::{access_check::check_auth_and_acl, file_helpers::Finalizer},
-    storage::STORAGE,
+    handlers::{
+        access_check::check_auth_and_acl,
+        file_helpers::{Finalizer, HashingWriter},
+    },
+    metrics::{record_download, record_upload},
+    quota::{Reservation, QUOTA},
+    storage::{STORAGE, VERIFY_UPLOAD},
     typed_path::{PathParts, TpeKind},
 };
 
@@ -27,7 +41,8 @@ use crate::{
 /// Future on ranges: <https://www.rfc-editor.org/rfc/rfc9110.html#name-partial-put>
 pub async fn add_file<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
+    content_length: Option<TypedHeader<ContentLength>>,
     request: Request,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, name) = path.parts();
@@ -35,22 +50,39 @@ pub async fn add_file<P: PathParts>(
     tracing::debug!("[get_file] path: {path:?}, tpe: {tpe:?}, name: {name:?}");
     let path_str = path.unwrap_or_default();
 
-    //credential & access check executed in get_save_file()
+    //credential, access & quota check executed in get_save_file()
     let path = PathBuf::from(&path_str);
-    let file = get_save_file(auth.user, path, tpe, name).await?;
+    let content_length = content_length.map(|TypedHeader(ContentLength(length))| length);
+    let (file, reservation) =
+        get_save_file(&auth, path.clone(), tpe, name.clone(), content_length).await?;
 
     let stream = request.into_body().into_data_stream();
-    let _ = save_body(file, stream).await?;
+    let limit = reservation.as_ref().and_then(Reservation::limit);
+    let written = save_body(file, stream, limit).await?;
+
+    // Only the upload that has linked the lock into place records its owner, and
+    // never over an existing record. Without its owner, the lock is removed again.
+    // Anonymous clients own their locks as `anonymous`, which no real user can be called.
+    if let (Some(TpeKind::Locks), Some(name)) = (tpe, &name) {
+        let storage = STORAGE.get().unwrap();
+        if let Err(err) = storage.set_lock_owner(&path, name, &auth.user).await {
+            let _ = storage
+                .remove_file(&path, TpeKind::Locks.into_str(), Some(name))
+                .await;
+            return Err(err);
+        }
+    }
+    record_upload(&path, &auth.user, written);
 
     //FIXME: Do we need to check if the file exists here? (For now it seems we should get an error if NOK)
-    Ok(())
+    Ok(update_quota(&auth.user, &path, written, reservation))
 }
 
 /// `delete_file`
 /// Interface: DELETE {path}/{type}/{name}
 pub async fn delete_file<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, name) = path.parts();
 
@@ -59,18 +91,40 @@ pub async fn delete_file<P: PathParts>(
     let path = Path::new(&path_str);
 
     let _ = check_name(tpe, name.as_deref())?;
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Append)?;
 
+    let storage = STORAGE.get().unwrap();
+
+    // Removing one's own lock needs no more than creating it, removing the lock
+    // of someone else needs `ForceUnlock`. So does a lock without an owner, whose
+    // creator can't be told, except for anonymous clients, which can't tell
+    // themselves apart anyway.
+    let access_type = match (tpe, &name) {
+        (Some(TpeKind::Locks), Some(name)) => match storage.lock_owner(path, name).await {
+            Some(owner) if owner == auth.user => AccessType::Append,
+            None if auth.anonymous.is_some() => AccessType::Append,
+            _ => AccessType::ForceUnlock,
+        },
+        _ => AccessType::Append,
+    };
+    let _ = check_auth_and_acl(&auth, tpe, path, access_type)?;
+
+    let is_lock = tpe == Some(TpeKind::Locks);
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
     } else {
         return Err(ApiErrorKind::InternalError("tpe is not valid".to_string()));
     };
 
-    let storage = STORAGE.get().unwrap();
+    let length = file_size(storage.filename(path, tpe, name.as_deref())).await;
 
     storage.remove_file(path, tpe, name.as_deref()).await?;
 
+    if let (true, Some(name)) = (is_lock, &name) {
+        storage.remove_lock_owner(path, name).await;
+    }
+
+    QUOTA.get().unwrap().remove_usage(path, length);
+
     Ok(())
 }
 
@@ -78,7 +132,7 @@ pub async fn delete_file<P: PathParts>(
 /// Interface: GET {path}/{type}/{name}
 pub async fn get_file<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
     range: Option<TypedHeader<Range>>,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, name) = path.parts();
@@ -91,7 +145,7 @@ pub async fn get_file<P: PathParts>(
 
     let path = Path::new(&path_str);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let _ = check_auth_and_acl(&auth, tpe, path, AccessType::Read)?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
@@ -115,7 +169,9 @@ pub async fn get_file<P: PathParts>(
         StatusCode::OK
     };
 
-    Ok((status_code, Ranged::new(range, body)).into_response())
+    let response = (status_code, Ranged::new(range, body)).into_response();
+
+    Ok(record_response_download(response, path, &auth.user))
 }
 
 //==============================================================================
@@ -124,16 +180,27 @@ pub async fn get_file<P: PathParts>(
 //==============================================================================
 
 /// Returns a stream for the given path in the repository.
+///
+/// Space for the upload of `content_length` bytes is reserved against the quota
+/// that applies to the user and the repository. Returns the stream together with
+/// the reservation, or `None` if quotas are disabled.
+///
+/// Unless disabled, the stream only finalizes if the content matches the hash in its name.
 pub async fn get_save_file(
-    user: String,
+    auth: &AuthFromRequest,
     path: PathBuf,
     tpe: Option<TpeKind>,
     name: Option<String>,
-) -> ApiResult<impl AsyncWrite + Unpin + Finalizer> {
+    content_length: Option<u64>,
+) -> ApiResult<(
+    impl AsyncWrite + Unpin + Finalizer,
+    Option<Reservation<'static>>,
+)> {
     tracing::debug!("[get_save_file] path: {path:?}, tpe: {tpe:?}, name: {name:?}");
 
     let _ = check_name(tpe, name.as_deref())?;
-    let _ = check_auth_and_acl(user, tpe, path.as_path(), AccessType::Append)?;
+    let _ = check_auth_and_acl(auth, tpe, path.as_path(), AccessType::Append)?;
+    let reservation = reserve_quota(&auth.user, &path, content_length).await?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
@@ -141,15 +208,100 @@ pub async fn get_save_file(
         return Err(ApiErrorKind::InternalError("tpe is not valid".to_string()));
     };
 
+    // content-addressed files are named after the SHA-256 hash of their content
+    let expected_hash = name
+        .as_deref()
+        .filter(|name| *VERIFY_UPLOAD.get().unwrap() && is_sha256_hash(name))
+        .map(str::to_string);
+
+    let storage = STORAGE.get().unwrap();
+    let file = storage.create_file(&path, tpe, name.as_deref()).await?;
+
+    Ok((HashingWriter::new(file, expected_hash), reservation))
+}
+
+/// Reserves space for `user` to upload `content_length` bytes into the repository.
+///
+/// Returns `None` if quotas are disabled.
+pub async fn reserve_quota(
+    user: &str,
+    path: &Path,
+    content_length: Option<u64>,
+) -> ApiResult<Option<Reservation<'static>>> {
+    let quota = QUOTA.get().unwrap();
+
+    if quota.is_disabled() {
+        return Ok(None);
+    }
+
     let storage = STORAGE.get().unwrap();
-    storage.create_file(&path, tpe, name.as_deref()).await
+
+    // the first upload to a repository walks it, which must not block the runtime
+    let (tracked_user, tracked_path) = (user.to_string(), path.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        quota.track(storage.as_ref(), &tracked_user, &tracked_path);
+    })
+    .await
+    .map_err(|err| ApiErrorKind::InternalError(err.to_string()))?;
+
+    quota
+        .reserve(storage.as_ref(), user, path, content_length)
+        .map(Some)
+}
+
+/// Adds the written bytes to the usage of the repository, replacing the reservation
+/// of the upload, and returns the quota headers for the response.
+pub fn update_quota(
+    user: &str,
+    path: &Path,
+    written: u64,
+    reservation: Option<Reservation<'_>>,
+) -> impl IntoResponse {
+    let quota = QUOTA.get().unwrap();
+    let storage = STORAGE.get().unwrap();
+
+    match reservation {
+        Some(reservation) => reservation.commit(written),
+        None => quota.add_usage(path, written),
+    }
+
+    quota.headers(storage.as_ref(), user, path)
+}
+
+/// Records the length of a response sending a file as downloaded by `user`
+pub fn record_response_download(response: Response, path: &Path, user: &str) -> Response {
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok());
+
+    if let Some(length) = length {
+        record_download(path, user, length);
+    }
+
+    response
+}
+
+/// Returns the size of a file, or `0` if it can't be determined
+pub async fn file_size(path: PathBuf) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or_default()
 }
 
 /// saves the content in the HTML request body to a file stream.
+///
+/// If a `limit` is given, the upload is aborted and the file is removed
+/// as soon as the body exceeds `limit` bytes.
+///
+/// Returns the number of bytes written.
 pub async fn save_body<S, E>(
     mut write_stream: impl AsyncWrite + Unpin + Finalizer + Send,
     stream: S,
-) -> ApiResult<impl IntoResponse>
+    limit: Option<u64>,
+) -> ApiResult<u64>
 where
     S: Stream<Item = Result<Bytes, E>> + Send,
     E: Into<BoxError>,
@@ -158,15 +310,31 @@ where
     let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
     let body_reader = StreamReader::new(body_with_io_error);
     pin_mut!(body_reader);
+
+    // Read at most one byte more than allowed, so we can detect if the limit was exceeded
+    let mut body_reader = body_reader.take(limit.map_or(u64::MAX, |limit| limit.saturating_add(1)));
+
     let byte_count = match tokio::io::copy(&mut body_reader, &mut write_stream).await {
         Ok(b) => b,
         Err(err) => return Err(ApiErrorKind::FinalizingFileFailed(format!("{:?}", err))),
     };
 
+    if let Some(limit) = limit {
+        if byte_count > limit {
+            // `write_stream` is dropped without being finalized, so the file is removed
+            return Err(ApiErrorKind::QuotaExceeded(format!(
+                "upload exceeds the remaining {limit} bytes"
+            )));
+        }
+    }
+
     tracing::debug!("[file written] bytes: {byte_count}");
-    write_stream.finalize().await.map_err(|err| {
-        ApiErrorKind::FinalizingFileFailed(format!("Could not finalize file: {}", err))
-    })
+    write_stream.finalize().await.map_err(|err| match err {
+        ApiErrorKind::HashMismatch(_) => err,
+        err => ApiErrorKind::FinalizingFileFailed(format!("Could not finalize file: {}", err)),
+    })?;
+
+    Ok(byte_count)
 }
 
 #[cfg(test)]
@@ -176,6 +344,11 @@ const fn check_string_sha256(_name: &str) -> bool {
 
 #[cfg(not(test))]
 fn check_string_sha256(name: &str) -> bool {
+    is_sha256_hash(name)
+}
+
+/// Returns if `name` is a lowercase hex encoded SHA-256 hash
+pub(crate) fn is_sha256_hash(name: &str) -> bool {
     if name.len() != 64 {
         return false;
     }
@@ -205,18 +378,25 @@ pub fn check_name(
 #[cfg(test)]
 mod test {
     use crate::{
-        handlers::file_exchange::{add_file, delete_file, get_file},
+        acl::AnonymousAccess,
+        auth::AuthFromRequest,
+        error::ApiErrorKind,
+        handlers::{
+            file_exchange::{add_file, delete_file, get_file, save_body},
+            file_helpers::{HashingWriter, WriteOrDeleteFile},
+        },
         log::print_request_response,
+        storage::{LOCK_OWNER_DIR, STAGING_DIR},
         testing::{
             basic_auth_header_value, init_test_environment, request_uri_for_test, server_config,
         },
-        typed_path::RepositoryTpeNamePath,
+        typed_path::{RepositoryTpeNamePath, TpeKind},
     };
 
     use std::{fs, path::PathBuf};
 
     use axum::{
-        body::Body,
+        body::{Body, Bytes},
         http::{header, Method, Request, StatusCode},
         middleware, Router,
     };
@@ -224,6 +404,85 @@ mod test {
     use http_body_util::BodyExt;
     use tower::ServiceExt;
 
+    #[tokio::test]
+    async fn test_save_body_with_limit_passes() {
+        let path = PathBuf::new()
+            .join("tests")
+            .join("generated")
+            .join("test_storage")
+            .join("test_repo")
+            .join("keys")
+            .join("__save_body_test_limits_this_one__");
+
+        if path.exists() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let staging_dir = PathBuf::from("tests/generated/test_storage").join(STAGING_DIR);
+        let body = || futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from("Hello World"))]);
+
+        // Exceeding the limit removes the file
+        let file = WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        let result = save_body(file, body(), Some(5)).await;
+        assert!(matches!(result, Err(ApiErrorKind::QuotaExceeded(_))));
+        assert!(!path.exists());
+
+        // Exactly hitting the limit is fine
+        let file = WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        let written = save_body(file, body(), Some(11)).await.unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Hello World");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_body_verifies_hash_passes() {
+        // SHA-256 of "Hello World"
+        let hash = "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e";
+
+        let path = PathBuf::new()
+            .join("tests")
+            .join("generated")
+            .join("test_storage")
+            .join("test_repo")
+            .join("keys")
+            .join(hash);
+
+        if path.exists() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let staging_dir = PathBuf::from("tests/generated/test_storage").join(STAGING_DIR);
+        let body = |content: &'static str| {
+            futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from(content))])
+        };
+
+        // Corrupted content is rejected and removed
+        let file = WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        let file = HashingWriter::new(file, Some(hash.to_string()));
+        let result = save_body(file, body("Hello Wörld"), None).await;
+        assert!(matches!(result, Err(ApiErrorKind::HashMismatch(_))));
+        assert!(!path.exists());
+
+        // Matching content is saved
+        let file = WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        let file = HashingWriter::new(file, Some(hash.to_string()));
+        let written = save_body(file, body("Hello World"), None).await.unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Hello World");
+
+        fs::remove_file(&path).unwrap();
+    }
+
     #[tokio::test]
     async fn test_add_delete_file_passes() {
         init_test_environment(server_config());
@@ -296,6 +555,120 @@ mod test {
         assert!(!path.exists());
     }
 
+    #[tokio::test]
+    async fn test_delete_lock_passes() {
+        init_test_environment(server_config());
+
+        let lock_name = "__delete_lock_test_adds_this_one__";
+        let locks = PathBuf::from("tests/generated/test_storage/test_repo/locks");
+        fs::create_dir_all(&locks).unwrap();
+        let owner_record = PathBuf::from("tests/generated/test_storage")
+            .join(LOCK_OWNER_DIR)
+            .join("test_repo")
+            .join(lock_name);
+
+        let request = |method: Method, user: &str| {
+            Request::builder()
+                .uri(["/test_repo/locks/", lock_name].concat())
+                .method(method)
+                .header("Authorization", basic_auth_header_value(user, Some(user)))
+                .body(Body::new("lock".to_string()))
+                .unwrap()
+        };
+        let app = Router::new()
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .typed_delete(delete_file::<RepositoryTpeNamePath>);
+
+        // creating a lock records its owner
+        let resp = app
+            .clone()
+            .oneshot(request(Method::POST, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(fs::read_to_string(&owner_record).unwrap(), "rustic");
+
+        // a second lock of the same name neither replaces the lock nor its owner
+        let resp = app
+            .clone()
+            .oneshot(request(Method::POST, "restic"))
+            .await
+            .unwrap();
+        assert_ne!(resp.status(), StatusCode::OK);
+        assert_eq!(fs::read_to_string(&owner_record).unwrap(), "rustic");
+
+        // `Append` doesn't allow to remove the lock of someone else
+        let resp = app
+            .clone()
+            .oneshot(request(Method::DELETE, "restic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(locks.join(lock_name).exists());
+
+        // but the owner can remove it
+        let resp = app
+            .clone()
+            .oneshot(request(Method::DELETE, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!locks.join(lock_name).exists());
+        assert!(!owner_record.exists());
+
+        // a lock without an owner record, e.g. from an older version, needs `ForceUnlock`
+        fs::write(locks.join(lock_name), "lock").unwrap();
+        let resp = app
+            .oneshot(request(Method::DELETE, "restic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(locks.join(lock_name).exists());
+        fs::remove_file(locks.join(lock_name)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_anonymous_lock_passes() {
+        init_test_environment(server_config());
+
+        // a repository without ACL entries, which `full` anonymous access allows
+        let lock_name = "__delete_anonymous_lock_test_adds_this_one__";
+        let locks = PathBuf::from("tests/generated/test_storage/anonymous_repo/locks");
+        fs::create_dir_all(&locks).unwrap();
+        let path = || RepositoryTpeNamePath {
+            repo: "anonymous_repo".to_string(),
+            tpe: TpeKind::Locks,
+            name: lock_name.to_string(),
+        };
+        let anonymous = || AuthFromRequest::anonymous(AnonymousAccess::Full);
+
+        let request = Request::new(Body::new("lock".to_string()));
+        assert!(add_file(path(), anonymous(), None, request).await.is_ok());
+        assert!(locks.join(lock_name).exists());
+
+        // an authenticated user can't remove it with `Append`
+        let resp = Router::new()
+            .typed_delete(delete_file::<RepositoryTpeNamePath>)
+            .oneshot(
+                Request::builder()
+                    .uri(["/anonymous_repo/locks/", lock_name].concat())
+                    .method(Method::DELETE)
+                    .header(
+                        "Authorization",
+                        basic_auth_header_value("rustic", Some("rustic")),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // but the anonymous client can
+        assert!(delete_file(path(), anonymous()).await.is_ok());
+        assert!(!locks.join(lock_name).exists());
+    }
+
     #[tokio::test]
     async fn test_get_file_passes() {
         init_test_environment(server_config());
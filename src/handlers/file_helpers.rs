@@ -1,27 +1,34 @@
 use std::{
     cell::RefCell,
     fs,
-    io::Result as IoResult,
-    path::PathBuf,
+    io::{self, Result as IoResult},
+    path::{Path, PathBuf},
     pin::Pin,
     result::Result,
     task::{Context, Poll},
 };
 
 use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::{File, OpenOptions},
-    io::AsyncWrite,
+    io::{AsyncWrite, AsyncWriteExt},
 };
+use uuid::Uuid;
 
 use crate::error::{ApiErrorKind, ApiResult};
 
-// helper struct which is like a async_std|tokio::fs::File but removes the file
-// if finalize() was not called.
+/// Extension of the temporary files in the staging directory
+pub const STAGING_FILE_EXTENSION: &str = "tmp";
+
+// helper struct which is like a async_std|tokio::fs::File but writes to a
+// temporary file in the staging directory. finalize() links it atomically to
+// its final path, otherwise the temporary file is removed.
 #[derive(Debug)]
 pub struct WriteOrDeleteFile {
     file: File,
     path: PathBuf,
+    staging_path: PathBuf,
     finalized: bool,
 }
 
@@ -31,23 +38,35 @@ pub trait Finalizer {
 }
 
 impl WriteOrDeleteFile {
-    pub async fn new(path: PathBuf) -> ApiResult<Self> {
+    /// Creates a temporary file in `staging_dir`, which becomes `path` when finalized
+    ///
+    /// `staging_dir` has to be on the same file system as `path`, so the file can be linked.
+    pub async fn new(path: PathBuf, staging_dir: &Path) -> ApiResult<Self> {
         tracing::debug!("[WriteOrDeleteFile] path: {path:?}");
 
-        if !path.exists() {
-            let parent = path.parent().ok_or_else(|| {
-                ApiErrorKind::WritingToFileFailed("Could not get parent directory".to_string())
-            })?;
+        if path.exists() {
+            return Err(ApiErrorKind::WritingToFileFailed(format!(
+                "File {} already exists",
+                path.display()
+            )));
+        }
+
+        let parent = path.parent().ok_or_else(|| {
+            ApiErrorKind::WritingToFileFailed("Could not get parent directory".to_string())
+        })?;
 
-            fs::create_dir_all(parent).map_err(|err| {
+        for dir in [parent, staging_dir] {
+            fs::create_dir_all(dir).map_err(|err| {
                 ApiErrorKind::WritingToFileFailed(format!("Could not create directory: {}", err))
             })?;
         }
 
+        let staging_path = staging_dir.join(format!("{}.{STAGING_FILE_EXTENSION}", Uuid::new_v4()));
+
         let file = OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(&path)
+            .open(&staging_path)
             .await
             .map_err(|err| {
                 ApiErrorKind::WritingToFileFailed(format!("Could not write to file: {}", err))
@@ -56,6 +75,7 @@ impl WriteOrDeleteFile {
         Ok(Self {
             file,
             path,
+            staging_path,
             finalized: false,
         })
     }
@@ -64,14 +84,59 @@ impl WriteOrDeleteFile {
 #[async_trait::async_trait]
 impl Finalizer for WriteOrDeleteFile {
     async fn finalize(&mut self) -> ApiResult<()> {
+        self.file.flush().await.map_err(|err| {
+            ApiErrorKind::FinalizingFileFailed(format!("Could not flush file: {}", err))
+        })?;
         self.file.sync_all().await.map_err(|err| {
             ApiErrorKind::FinalizingFileFailed(format!("Could not sync file: {}", err))
         })?;
+
+        // Unlike renaming, linking fails if the file exists, e.g. because a concurrent
+        // upload of the same object has finished in the meantime, so it is never overwritten
+        tokio::fs::hard_link(&self.staging_path, &self.path)
+            .await
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => ApiErrorKind::FinalizingFileFailed(format!(
+                    "File {} already exists",
+                    self.path.display()
+                )),
+                _ => ApiErrorKind::FinalizingFileFailed(format!("Could not link file: {}", err)),
+            })?;
+
+        // Persist the link, the file is not durable before its directory entry is
+        if let Some(parent) = self.path.parent() {
+            if let Err(err) = sync_dir(parent).await {
+                // ignore errors, the upload fails anyway
+                tokio::fs::remove_file(&self.path).await.unwrap_or(());
+                return Err(ApiErrorKind::FinalizingFileFailed(format!(
+                    "Could not sync directory: {}",
+                    err
+                )));
+            }
+        }
+
+        // ignore errors, the janitor removes stale staging files
+        tokio::fs::remove_file(&self.staging_path)
+            .await
+            .unwrap_or(());
         self.finalized = true;
+
         Ok(())
     }
 }
 
+#[cfg(unix)]
+async fn sync_dir(path: &Path) -> IoResult<()> {
+    File::open(path).await?.sync_all().await
+}
+
+// Directories can't be opened like files on other platforms
+#[cfg(not(unix))]
+#[allow(clippy::unused_async)]
+async fn sync_dir(_path: &Path) -> IoResult<()> {
+    Ok(())
+}
+
 impl AsyncWrite for WriteOrDeleteFile {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
         Pin::new(&mut self.get_mut().file).poll_write(cx, buf)
@@ -90,8 +155,67 @@ impl Drop for WriteOrDeleteFile {
     fn drop(&mut self) {
         if !self.finalized {
             // ignore errors
-            fs::remove_file(&self.path).unwrap_or(());
+            fs::remove_file(&self.staging_path).unwrap_or(());
+        }
+    }
+}
+
+// helper struct which hashes everything written to the inner writer and
+// only finalizes it if the SHA-256 hash matches the expected one.
+#[derive(Debug)]
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    expected: Option<String>,
+}
+
+impl<W> HashingWriter<W> {
+    /// Wraps `inner`, no verification takes place if `expected` is `None`
+    pub fn new(inner: W, expected: Option<String>) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            expected,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: Finalizer + Send> Finalizer for HashingWriter<W> {
+    async fn finalize(&mut self) -> ApiResult<()> {
+        if let Some(expected) = &self.expected {
+            let hash = format!("{:x}", self.hasher.finalize_reset());
+
+            if &hash != expected {
+                return Err(ApiErrorKind::HashMismatch(format!(
+                    "expected `{expected}`, got `{hash}`"
+                )));
+            }
         }
+
+        self.inner.finalize().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        // only hash what was actually written
+        if let Poll::Ready(Ok(written)) = poll {
+            this.hasher.update(&buf[..written]);
+        }
+
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
 
@@ -116,3 +240,69 @@ where
         serializer.collect_seq(self.0.borrow_mut().by_ref())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::handlers::file_helpers::{Finalizer, WriteOrDeleteFile};
+
+    #[tokio::test]
+    async fn test_write_or_delete_file_is_atomic_passes() {
+        let base = PathBuf::from("tests/generated/test_write_or_delete_file");
+        let staging_dir = base.join(".staging");
+        let path = base.join("keys").join("file");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+
+        // an unfinished upload is never visible under its final name
+        let mut file = WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        file.write_all(b"Hello World").await.unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(&staging_dir).unwrap().count(), 1);
+
+        // an aborted upload leaves nothing behind
+        drop(file);
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(&staging_dir).unwrap().count(), 0);
+
+        // a finalized upload is moved into place
+        let mut file = WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        file.write_all(b"Hello World").await.unwrap();
+        file.finalize().await.unwrap();
+        drop(file);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Hello World");
+        assert_eq!(fs::read_dir(&staging_dir).unwrap().count(), 0);
+
+        // existing objects are never overwritten
+        assert!(WriteOrDeleteFile::new(path.clone(), &staging_dir)
+            .await
+            .is_err());
+
+        // not even by a concurrent upload which started before the first one finished
+        let other_path = base.join("keys").join("other");
+        let mut first = WriteOrDeleteFile::new(other_path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        let mut second = WriteOrDeleteFile::new(other_path.clone(), &staging_dir)
+            .await
+            .unwrap();
+        first.write_all(b"first").await.unwrap();
+        second.write_all(b"second").await.unwrap();
+        first.finalize().await.unwrap();
+        assert!(second.finalize().await.is_err());
+        drop((first, second));
+        assert_eq!(fs::read_to_string(&other_path).unwrap(), "first");
+        assert_eq!(fs::read_dir(&staging_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
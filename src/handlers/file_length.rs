@@ -5,7 +5,7 @@ use axum::{http::header, response::IntoResponse};
 
 use crate::{
     acl::AccessType,
-    auth::BasicAuthFromRequest,
+    auth::AuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     handlers::access_check::check_auth_and_acl,
     storage::STORAGE,
@@ -16,7 +16,7 @@ use crate::{
 /// Interface: HEAD {path}/{type}/{name}
 pub async fn file_length<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, name) = path.parts();
 
@@ -26,7 +26,7 @@ pub async fn file_length<P: PathParts>(
 
     let path = Path::new(&path_str);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let _ = check_auth_and_acl(&auth, tpe, path, AccessType::Read)?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
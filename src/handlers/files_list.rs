@@ -13,7 +13,7 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::{
     acl::AccessType,
-    auth::BasicAuthFromRequest,
+    auth::AuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     handlers::{access_check::check_auth_and_acl, file_helpers::IteratorAdapter},
     storage::STORAGE,
@@ -66,7 +66,7 @@ struct RepoPathEntry {
 
 pub async fn list_files<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
     headers: HeaderMap,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, _) = path.parts();
@@ -77,7 +77,7 @@ pub async fn list_files<P: PathParts>(
 
     let path = Path::new(&path);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let _ = check_auth_and_acl(&auth, tpe, path, AccessType::Read)?;
 
     let storage = STORAGE.get().unwrap();
 
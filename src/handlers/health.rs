@@ -1,9 +1,21 @@
-use std::{sync::OnceLock, time::Instant};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
 use axum::{http::StatusCode, response::IntoResponse};
 use axum_extra::json;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_derive::Serialize;
+use tracing::warn;
+use uuid::Uuid;
 
-use crate::auth::BasicAuthFromRequest;
+use crate::{
+    error::AppResult,
+    storage::{scrubber::SCRUBBER, STORAGE},
+};
 
 // Global that stores the current when the server started
 // This is used to check if the server is running
@@ -24,14 +36,211 @@ pub async fn live_check() -> impl IntoResponse {
             "version": env!("CARGO_PKG_VERSION"),
             "uptime": uptime.as_secs(),
             "timestamp": chrono::Local::now().timestamp(),
+            "scrub": scrub_status(),
         }),
     )
         .into_response()
 }
 
+/// Summary of the last scrub, without any repository names
+#[derive(Serialize, Debug, Default)]
+struct ScrubHealth {
+    status: &'static str,
+    last_run: Option<i64>,
+    repositories: u64,
+    files_checked: u64,
+    mismatches: u64,
+    errors: u64,
+}
+
+fn scrub_status() -> ScrubHealth {
+    let Some(scrubber) = SCRUBBER.get() else {
+        return ScrubHealth {
+            status: "disabled",
+            ..ScrubHealth::default()
+        };
+    };
+
+    let status = scrubber.status();
+    let state = match (
+        status.running,
+        status.last_run,
+        status.mismatches + status.errors,
+    ) {
+        (true, _, _) => "running",
+        (false, None, _) => "pending",
+        (false, Some(_), 0) => "ok",
+        (false, Some(_), _) => "mismatch",
+    };
+
+    ScrubHealth {
+        status: state,
+        last_run: status.last_run.map(|last_run| last_run.timestamp()),
+        repositories: status.repositories,
+        files_checked: status.files_checked,
+        mismatches: status.mismatches,
+        errors: status.errors,
+    }
+}
+
+/// Default minimum free disk space in bytes for the server to be ready
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+
+/// TLS certificates expiring within this period make the server unready
+pub const CERT_EXPIRY_THRESHOLD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Prefix of the probe file written to the data directory
+const PROBE_FILE_PREFIX: &str = ".ready-probe";
+
+// Static storage of our readiness settings
+pub static READINESS: OnceLock<Readiness> = OnceLock::new();
+
+pub(crate) fn init_readiness(readiness: Readiness) -> AppResult<()> {
+    let _ = READINESS.get_or_init(|| readiness);
+    Ok(())
+}
+
+/// `Readiness` holds the settings of the readiness probe
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    min_free_space: u64,
+    tls_cert: Option<PathBuf>,
+}
+
+impl Readiness {
+    pub const fn new(min_free_space: u64, tls_cert: Option<PathBuf>) -> Self {
+        Self {
+            min_free_space,
+            tls_cert,
+        }
+    }
+
+    /// Checks the data directory, its free disk space and the TLS certificate
+    ///
+    /// The htpasswd and ACL files are validated when the server starts and only
+    /// replaced by valid files when reloading, so they are not checked here.
+    ///
+    /// This blocks; call it from the blocking thread pool.
+    pub fn check(&self, data_dir: &Path) -> ReadyDependencies {
+        let mut error_count = 0;
+        let mut status = |result: Result<&str, String>| {
+            result.map(ToString::to_string).unwrap_or_else(|err| {
+                error_count += 1;
+                err
+            })
+        };
+
+        let storage_status = status(check_storage(data_dir));
+
+        let available_disk_space = fs4::available_space(data_dir).ok();
+        let disk_status = status(match available_disk_space {
+            Some(available) if available >= self.min_free_space => Ok("ok"),
+            Some(available) => Err(format!(
+                "only {} free, at least {} required",
+                format_size(available),
+                format_size(self.min_free_space)
+            )),
+            None => Err("could not determine free disk space".to_string()),
+        });
+
+        let (tls_status, tls_not_after) = match &self.tls_cert {
+            Some(tls_cert) => match check_tls_cert(tls_cert) {
+                Ok(not_after) => (status(expiry_status(not_after)), Some(not_after)),
+                Err(err) => (status(Err(err)), None),
+            },
+            None => (status(Ok("disabled")), None),
+        };
+
+        ReadyDependencies {
+            error_count,
+            storage_status,
+            available_disk_space: available_disk_space.map(format_size),
+            min_free_space: format_size(self.min_free_space),
+            disk_status,
+            tls_status,
+            tls_not_after: tls_not_after
+                .and_then(|not_after| DateTime::from_timestamp(not_after, 0))
+                .map(rfc3339),
+        }
+    }
+}
+
+/// Result of the readiness checks, every status is either `ok`, `disabled` or an error
+#[derive(Serialize, Debug)]
+pub struct ReadyDependencies {
+    #[serde(skip)]
+    pub error_count: usize,
+    pub storage_status: String,
+    pub available_disk_space: Option<String>,
+    pub min_free_space: String,
+    pub disk_status: String,
+    pub tls_status: String,
+    pub tls_not_after: Option<String>,
+}
+
+/// Formats a size in bytes in the largest binary unit it fills, e.g. `120GB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+
+    format!("{size}{}", UNITS[unit])
+}
+
+/// Formats a point in time like `2024-11-16T12:34:56Z`
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Writes, reads back and removes a probe file in the data directory
+fn check_storage(data_dir: &Path) -> Result<&'static str, String> {
+    let probe = data_dir.join(format!("{PROBE_FILE_PREFIX}-{}", Uuid::new_v4()));
+    let content = b"ready";
+
+    let result = fs::write(&probe, content).and_then(|()| fs::read(&probe));
+    let _ = fs::remove_file(&probe);
+
+    match result {
+        Ok(read) if read == content => Ok("ok"),
+        Ok(_) => Err("probe file could not be read back".to_string()),
+        Err(err) => Err(format!("data directory is not writable: {err}")),
+    }
+}
+
+/// Returns the end of the validity period of the first certificate in a PEM file
+fn check_tls_cert(tls_cert: &Path) -> Result<i64, String> {
+    let pem = fs::read(tls_cert).map_err(|err| format!("could not read certificate: {err}"))?;
+
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem)
+        .map_err(|err| format!("could not parse certificate: {err}"))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|err| format!("could not parse certificate: {err}"))?;
+
+    Ok(cert.validity().not_after.timestamp())
+}
+
+fn expiry_status(not_after: i64) -> Result<&'static str, String> {
+    let remaining = not_after - chrono::Local::now().timestamp();
+
+    match u64::try_from(remaining) {
+        Ok(remaining) if remaining >= CERT_EXPIRY_THRESHOLD.as_secs() => Ok("ok"),
+        Ok(remaining) => Err(format!(
+            "certificate expires in {} days",
+            remaining / (24 * 60 * 60)
+        )),
+        Err(_) => Err("certificate has expired".to_string()),
+    }
+}
+
 // /health/ready
 //
-// Example response as an idea of what to return:
+// Returns “200 OK” if all checks pass, “503 Service Unavailable” otherwise:
 //
 // ```json
 // {
@@ -40,23 +249,132 @@ pub async fn live_check() -> impl IntoResponse {
 //   "uptime": 123456,
 //   "error_count": 0,
 //   "timestamp": "2024-11-16T12:34:56Z",
-//   "last_backup_status": "success",
-//   "last_backup_timestamp": "2024-11-15T23:59:59Z",
-//   "backup_queue_length": 0,
-//   "backup_size_last": "10GB",
 //   "dependencies": {
 //     "storage_status": "ok",
-//     "available_disk_space": "120GB"
+//     "available_disk_space": "120GB",
+//     "min_free_space": "1GB",
+//     "disk_status": "ok",
+//     "tls_status": "disabled",
+//     "tls_not_after": null
 //   },
-//   "performance": {
-//     "cpu_usage": "15%",
-//     "memory_usage": "300MB",
-//     "active_tasks": 2,
-//   }
+//   "scrub": { ... }
 // }
 // ```
-// TODO: Implement ready_check
-#[allow(dead_code)]
-pub async fn ready_check(_auth: BasicAuthFromRequest) -> impl IntoResponse {
-    StatusCode::NOT_IMPLEMENTED
+pub async fn ready_check() -> impl IntoResponse {
+    let start = START_TIME.get().expect("start time not initialized");
+    let uptime = Instant::now().duration_since(*start);
+
+    let readiness = READINESS.get().expect("readiness not initialized");
+    let storage = STORAGE.get().unwrap();
+
+    // The probe file, the disk space query and the certificate block
+    let dependencies = match tokio::task::spawn_blocking(|| readiness.check(storage.path())).await {
+        Ok(dependencies) => dependencies,
+        Err(err) => {
+            warn!(%err, "[health] readiness check failed");
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+
+    let (status_code, status) = if dependencies.error_count == 0 {
+        (StatusCode::OK, "ready")
+    } else {
+        warn!(?dependencies, "[health] server is not ready");
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    };
+
+    (
+        status_code,
+        json!({
+            "status": status,
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime": uptime.as_secs(),
+            "error_count": dependencies.error_count,
+            "timestamp": rfc3339(Utc::now()),
+            "dependencies": dependencies,
+            "scrub": scrub_status(),
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use crate::{
+        handlers::health::{format_size, init_readiness, init_start_time, ready_check, Readiness},
+        storage::STORAGE,
+        testing::{init_test_environment, server_config, test_data_path},
+    };
+
+    #[test]
+    fn test_readiness_checks_passes() {
+        init_test_environment(server_config());
+        let data_dir = STORAGE.get().unwrap().path();
+
+        let dependencies = Readiness::new(0, None).check(data_dir);
+        assert_eq!(dependencies.error_count, 0);
+        assert_eq!(dependencies.storage_status, "ok");
+        assert_eq!(dependencies.disk_status, "ok");
+        assert_eq!(dependencies.tls_status, "disabled");
+
+        // not enough free disk space
+        let dependencies = Readiness::new(u64::MAX, None).check(data_dir);
+        assert_eq!(dependencies.error_count, 1);
+        assert_ne!(dependencies.disk_status, "ok");
+
+        // the test certificate has expired
+        let tls_cert = test_data_path().join("certs").join("test.crt");
+        let dependencies = Readiness::new(0, Some(tls_cert)).check(data_dir);
+        assert_eq!(dependencies.error_count, 1);
+        assert_eq!(dependencies.tls_status, "certificate has expired");
+        assert!(dependencies.tls_not_after.is_some());
+
+        assert_eq!(format_size(0), "0B");
+        assert_eq!(format_size(1023), "1023B");
+        assert_eq!(format_size(1024 * 1024 * 1024), "1GB");
+        assert_eq!(format_size(120 * 1024 * 1024 * 1024 + 1), "120GB");
+
+        // the data directory does not exist
+        let dependencies =
+            Readiness::new(0, None).check(&PathBuf::from("tests/generated/does_not_exist"));
+        assert_eq!(dependencies.error_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_ready_check_passes() {
+        init_test_environment(server_config());
+        init_start_time();
+        init_readiness(Readiness::new(0, None)).unwrap();
+
+        let app = Router::new().route("/health/ready", get(ready_check));
+
+        let request = Request::builder()
+            .uri("/health/ready")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["error_count"], 0);
+        assert_eq!(body["dependencies"]["storage_status"], "ok");
+        assert_eq!(body["dependencies"]["min_free_space"], "0B");
+
+        let timestamp = body["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+    }
 }
@@ -0,0 +1,85 @@
+use axum::{http::header, response::IntoResponse};
+use prometheus::TEXT_FORMAT;
+
+use crate::{
+    auth::{AuthFromRequest, AUTH},
+    error::{ApiErrorKind, ApiResult},
+    metrics::{METRICS, METRICS_USER},
+    storage::STORAGE,
+};
+
+/// `get_metrics`
+/// Interface: GET /metrics
+///
+/// Only the user `metrics` may access the metrics, unless authentication is
+/// disabled for them or for the whole server. The repository usage is updated
+/// at most once per [`USAGE_REFRESH_INTERVAL`](crate::metrics::USAGE_REFRESH_INTERVAL).
+pub async fn get_metrics(
+    auth: Result<AuthFromRequest, ApiErrorKind>,
+) -> ApiResult<impl IntoResponse> {
+    let metrics = METRICS.get().unwrap();
+
+    if !metrics.no_auth() && !AUTH.get().unwrap().load().is_disabled() {
+        let auth = auth.map_err(|err| match err {
+            ApiErrorKind::AuthenticationHeaderError
+            | ApiErrorKind::UserAuthenticationError(_)
+            | ApiErrorKind::InvalidBearerToken(_) => ApiErrorKind::MetricsUnauthorized,
+            err => err,
+        })?;
+        if auth.user != METRICS_USER {
+            return Err(ApiErrorKind::MetricsUnauthorized);
+        }
+    }
+
+    if metrics.claim_usage_update() {
+        let storage = STORAGE.get().unwrap();
+        tokio::task::spawn_blocking(|| metrics.update_usage(storage.as_ref()))
+            .await
+            .map_err(|err| ApiErrorKind::InternalError(err.to_string()))?;
+    }
+
+    let body = metrics
+        .render()
+        .map_err(|err| ApiErrorKind::InternalError(err.to_string()))?;
+
+    Ok(([(header::CONTENT_TYPE, TEXT_FORMAT)], body))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        handlers::metrics::get_metrics,
+        metrics::{init_metrics, Metrics},
+        testing::{init_test_environment, request_uri_for_test, server_config},
+    };
+
+    #[tokio::test]
+    async fn test_get_metrics_requires_metrics_user_passes() {
+        init_test_environment(server_config());
+        init_metrics(Metrics::new(false, false).unwrap()).unwrap();
+
+        let app = Router::new().route("/metrics", get(get_metrics));
+
+        // authenticated, but not as `metrics`
+        let request = request_uri_for_test("/metrics", Method::GET);
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        // not authenticated
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
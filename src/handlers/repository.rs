@@ -4,8 +4,13 @@ use axum::{extract::Query, http::StatusCode, response::IntoResponse};
 use serde_derive::Deserialize;
 
 use crate::{
-    acl::AccessType, auth::BasicAuthFromRequest, error::ApiResult,
-    handlers::access_check::check_auth_and_acl, storage::STORAGE, typed_path::TpeKind,
+    acl::{AccessType, RESERVED_SECTIONS},
+    auth::AuthFromRequest,
+    error::{ApiErrorKind, ApiResult},
+    handlers::access_check::check_auth_and_acl,
+    quota::QUOTA,
+    storage::STORAGE,
+    typed_path::TpeKind,
 };
 
 // used for using auto-generated TpeKind variant names
@@ -22,7 +27,7 @@ pub struct Create {
 
 pub async fn create_repository<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
     Query(params): Query<Create>,
 ) -> ApiResult<impl IntoResponse> {
     tracing::debug!(
@@ -30,7 +35,16 @@ pub async fn create_repository<P: PathParts>(
         path.repo().unwrap()
     );
     let path = PathBuf::new().join(path.repo().unwrap());
-    let _ = check_auth_and_acl(auth.user, None, &path, AccessType::Append)?;
+
+    // the reserved sections of the ACL file couldn't hold the ACL of these repositories
+    if path
+        .to_str()
+        .is_some_and(|repo| RESERVED_SECTIONS.contains(&repo))
+    {
+        return Err(ApiErrorKind::AmbiguousPath(path.display().to_string()));
+    }
+
+    let _ = check_auth_and_acl(&auth, None, &path, AccessType::Append)?;
 
     let storage = STORAGE.get().unwrap();
     match params.create {
@@ -62,18 +76,20 @@ pub async fn create_repository<P: PathParts>(
 // FIXME: The input path should at least NOT point to a file in any repository
 pub async fn delete_repository<P: PathParts>(
     path: P,
-    auth: BasicAuthFromRequest,
+    auth: AuthFromRequest,
 ) -> ApiResult<impl IntoResponse> {
     tracing::debug!(
         "[delete_repository] repository path: {}",
         &path.repo().unwrap()
     );
     let path = PathBuf::new().join(path.repo().unwrap());
-    let _ = check_auth_and_acl(auth.user, None, &path, AccessType::Modify)?;
+    let _ = check_auth_and_acl(&auth, None, &path, AccessType::Modify)?;
 
     let storage = STORAGE.get().unwrap();
     storage.remove_repository(&path).await?;
 
+    QUOTA.get().unwrap().forget(&path);
+
     Ok(())
 }
 
@@ -81,7 +97,7 @@ pub async fn delete_repository<P: PathParts>(
 mod test {
     use crate::log::print_request_response;
     use crate::testing::{basic_auth_header_value, init_test_environment, request_uri_for_test};
-    use crate::typed_path::RepositoryPath;
+    use crate::typed_path::{nested_repository_path, RepositoryPath};
     use crate::{
         handlers::repository::{create_repository, delete_repository},
         testing::server_config,
@@ -96,7 +112,7 @@ mod test {
     use pretty_assertions::assert_eq;
     use std::path::PathBuf;
     use tokio::fs;
-    use tower::ServiceExt;
+    use tower::{Layer, ServiceExt};
 
     /// The acl.toml test allows the create of "repo_remove_me"
     /// for user test with the correct password
@@ -155,6 +171,17 @@ mod test {
         assert_eq!(resp.status(), StatusCode::FORBIDDEN);
         assert!(!not_allowed_path.exists());
 
+        // ------------------------------------------
+        // Create a repository named like a reserved section of the ACL file
+        // ------------------------------------------
+        let app = Router::new().typed_post(create_repository::<RepositoryPath>);
+
+        let request = request_uri_for_test("/quota/?create=true", Method::POST);
+        let resp = app.oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(!PathBuf::from("tests/generated/test_storage/quota").exists());
+
         // ------------------------------------------
         // Delete a repository WITHOUT ACL access
         // ------------------------------------------
@@ -193,4 +220,58 @@ mod test {
         assert_eq!(resp.status(), StatusCode::OK);
         assert!(!path.exists());
     }
+
+    /// The acl.toml test allows `nested_org` and all repositories below it
+    #[tokio::test]
+    async fn test_nested_repo_create_delete_passes() {
+        init_test_environment(server_config());
+
+        let base = PathBuf::new()
+            .join("tests")
+            .join("generated")
+            .join("test_storage")
+            .join("nested_org");
+        let path = base.join("team").join("host1");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).await.unwrap();
+        }
+
+        let app = || {
+            middleware::from_fn(nested_repository_path).layer(
+                Router::new()
+                    .typed_post(create_repository::<RepositoryPath>)
+                    .typed_delete(delete_repository::<RepositoryPath>),
+            )
+        };
+
+        // ------------------------------------
+        // Create a nested repository
+        // ------------------------------------
+        let request = request_uri_for_test("/nested_org/team/host1/?create=true", Method::POST);
+        let resp = app().oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(path.join("keys").exists());
+        assert!(path.join("data").join("00").exists());
+
+        // ------------------------------------------
+        // Paths escaping the data directory are rejected
+        // ------------------------------------------
+        let request = request_uri_for_test("/nested_org/%2E%2E/%2E%2E/?create=true", Method::POST);
+        let resp = app().oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // ------------------------------------------
+        // Delete the nested repository
+        // ------------------------------------------
+        let request = request_uri_for_test("/nested_org/team/host1/", Method::DELETE);
+        let resp = app().oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
 }
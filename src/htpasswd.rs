@@ -1,19 +1,104 @@
 use std::{
     collections::{btree_map::Entry, BTreeMap},
     fmt::{Display, Formatter},
-    fs::{self, read_to_string},
-    io::Write,
+    fs::read_to_string,
     path::PathBuf,
 };
 
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use clap::ValueEnum;
 use htpasswd_verify::md5::{format_hash, md5_apr1_encode};
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
 use serde::Serialize;
+use strum::Display;
 
-use crate::error::{ApiErrorKind, ApiResult, AppResult, ErrorKind};
+use crate::{
+    error::{ApiErrorKind, ApiResult, AppResult, ErrorKind},
+    storage::write_atomic,
+};
 
 pub mod constants {
     pub(super) const SALT_LEN: usize = 8;
+    pub(super) const ARGON2_SALT_LEN: usize = 16;
+}
+
+/// Algorithm used to hash new passwords
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// bcrypt, as created by `htpasswd -B`
+    #[default]
+    Bcrypt,
+    /// SHA-256-crypt
+    Sha256,
+    /// SHA-512-crypt
+    Sha512,
+    /// argon2id
+    Argon2,
+    /// MD5-APR1, only for compatibility with old tools as it is weak
+    Md5,
+}
+
+impl HashAlgorithm {
+    /// Detects the algorithm of a hash, `None` for weak or unknown hashes
+    /// (e.g. MD5-crypt other than APR1, SHA-1 or DES-crypt)
+    pub fn of_hash(hash: &str) -> Option<Self> {
+        if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+            Some(Self::Bcrypt)
+        } else if hash.starts_with("$5$") {
+            Some(Self::Sha256)
+        } else if hash.starts_with("$6$") {
+            Some(Self::Sha512)
+        } else if hash.starts_with("$argon2") {
+            Some(Self::Argon2)
+        } else if hash.starts_with("$apr1$") {
+            Some(Self::Md5)
+        } else {
+            None
+        }
+    }
+
+    /// `true` if hashes of this algorithm are too weak to be kept
+    pub const fn is_weak(self) -> bool {
+        matches!(self, Self::Md5)
+    }
+
+    fn hash(self, pass: &str) -> AppResult<String> {
+        let hash_error =
+            |err: &dyn Display| ErrorKind::Io.context(format!("Could not hash password: {err}"));
+
+        match self {
+            Self::Bcrypt => pwhash::bcrypt::hash(pass).map_err(|err| hash_error(&err).into()),
+            // pwhash discourages SHA-256-crypt for new passwords, but some tools only support it
+            #[allow(deprecated)]
+            Self::Sha256 => pwhash::sha256_crypt::hash(pass).map_err(|err| hash_error(&err).into()),
+            Self::Sha512 => pwhash::sha512_crypt::hash(pass).map_err(|err| hash_error(&err).into()),
+            Self::Argon2 => {
+                let mut salt = [0; constants::ARGON2_SALT_LEN];
+                thread_rng().fill_bytes(&mut salt);
+
+                let salt = SaltString::encode_b64(&salt).map_err(|err| hash_error(&err))?;
+                let hash = Argon2::default()
+                    .hash_password(pass.as_bytes(), &salt)
+                    .map_err(|err| hash_error(&err))?;
+
+                Ok(hash.to_string())
+            }
+            Self::Md5 => {
+                let salt: String = thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(constants::SALT_LEN)
+                    .map(char::from)
+                    .collect();
+                let hash = md5_apr1_encode(pass, salt.as_str());
+
+                Ok(format_hash(hash.as_str(), salt.as_str()))
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize)]
@@ -76,12 +161,39 @@ impl Htpasswd {
         })
     }
 
+    /// Reads a htpasswd file, failing if it doesn't exist or contains a malformed line
+    ///
+    /// Used when reloading the file of a running server, so a broken file doesn't lock out all users.
+    pub fn from_file_strict(pth: &PathBuf) -> AppResult<Self> {
+        let content = read_to_string(pth).map_err(|err| {
+            ErrorKind::Io.context(format!(
+                "Could not read htpasswd file: {} at {:?}",
+                err, pth
+            ))
+        })?;
+
+        let mut c = CredentialMap::new();
+        for line in content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            let cred = Credential::from_line(line.to_string())?;
+            let _ = c.insert(cred.name.clone(), cred);
+        }
+
+        Ok(Self {
+            path: pth.clone(),
+            credentials: c,
+        })
+    }
+
     pub fn users(&self) -> Vec<String> {
         self.credentials.keys().cloned().collect()
     }
 
-    pub fn create(&mut self, name: &str, pass: &str) -> AppResult<()> {
-        let cred = Credential::new(name, pass);
+    pub fn create(&mut self, name: &str, pass: &str, algorithm: HashAlgorithm) -> AppResult<()> {
+        let cred = Credential::new(name, pass, algorithm)?;
 
         self.insert(cred)?;
 
@@ -92,8 +204,8 @@ impl Htpasswd {
         self.credentials.get(name)
     }
 
-    pub fn update(&mut self, name: &str, pass: &str) -> AppResult<()> {
-        let cred = Credential::new(name, pass);
+    pub fn update(&mut self, name: &str, pass: &str, algorithm: HashAlgorithm) -> AppResult<()> {
+        let cred = Credential::new(name, pass, algorithm)?;
 
         let _ = self
             .credentials
@@ -124,28 +236,18 @@ impl Htpasswd {
         Ok(())
     }
 
+    /// Writes all credentials to the htpasswd file
+    ///
+    /// The file is replaced atomically, so a concurrent reader never sees it half written.
     pub fn to_file(&self) -> ApiResult<()> {
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .truncate(false)
-            .write(true)
-            .open(&self.path)
-            .map_err(|err| {
-                ApiErrorKind::OpeningFileFailed(format!(
-                    "Could not open htpasswd file: {} at {:?}",
-                    err, self.path
-                ))
-            })?;
+        let content: String = self.credentials.values().map(ToString::to_string).collect();
 
-        for (_n, c) in self.credentials.iter() {
-            let _e = file.write(c.to_string().as_bytes()).map_err(|err| {
-                ApiErrorKind::WritingToFileFailed(format!(
-                    "Could not write to htpasswd file: {} at {:?}",
-                    err, self.path
-                ))
-            });
-        }
-        Ok(())
+        write_atomic(&self.path, content.as_bytes()).map_err(|err| {
+            ApiErrorKind::WritingToFileFailed(format!(
+                "Could not write to htpasswd file: {} at {:?}",
+                err, self.path
+            ))
+        })
     }
 }
 
@@ -156,18 +258,42 @@ pub struct Credential {
 }
 
 impl Credential {
-    pub fn new(name: &str, pass: &str) -> Self {
-        let salt: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(constants::SALT_LEN)
-            .map(char::from)
-            .collect();
-        let hash = md5_apr1_encode(pass, salt.as_str());
-        let hash = format_hash(hash.as_str(), salt.as_str());
-
-        Self {
+    pub fn new(name: &str, pass: &str, algorithm: HashAlgorithm) -> AppResult<Self> {
+        Ok(Self {
             name: name.into(),
-            hash,
+            hash: algorithm.hash(pass)?,
+        })
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        HashAlgorithm::of_hash(&self.hash)
+    }
+
+    /// `true` if the password is hashed with a weak or unknown algorithm
+    pub fn is_weak(&self) -> bool {
+        self.algorithm().map_or(true, HashAlgorithm::is_weak)
+    }
+
+    /// Verifies a password against the hash
+    pub fn verify(&self, pass: &str) -> bool {
+        match self.algorithm() {
+            Some(HashAlgorithm::Bcrypt) => pwhash::bcrypt::verify(pass, &self.hash),
+            Some(HashAlgorithm::Sha256) => pwhash::sha256_crypt::verify(pass, &self.hash),
+            Some(HashAlgorithm::Sha512) => pwhash::sha512_crypt::verify(pass, &self.hash),
+            Some(HashAlgorithm::Argon2) => PasswordHash::new(&self.hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(pass.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            // MD5-APR1, SHA-1 and DES-crypt
+            Some(HashAlgorithm::Md5) | None => {
+                htpasswd_verify::Htpasswd::from(self.to_string().as_str())
+                    .check(self.name.as_str(), pass)
+            }
         }
     }
 
@@ -199,17 +325,20 @@ impl Display for Credential {
 
 #[cfg(test)]
 mod test {
+    use std::{fs, path::PathBuf};
+
     use crate::auth::Auth;
-    use crate::htpasswd::Htpasswd;
+    use crate::htpasswd::{Credential, HashAlgorithm, Htpasswd};
     use anyhow::Result;
     use insta::assert_toml_snapshot;
+    use rstest::rstest;
 
     #[test]
     fn test_htpasswd_passes() -> Result<()> {
         let mut htpasswd = Htpasswd::new();
 
-        let _ = htpasswd.update("Administrator", "stuff");
-        let _ = htpasswd.update("backup-user", "its_me");
+        htpasswd.update("Administrator", "stuff", HashAlgorithm::default())?;
+        htpasswd.update("backup-user", "its_me", HashAlgorithm::Md5)?;
 
         assert_toml_snapshot!(htpasswd, {
             ".credentials.*.hash" => "[hash]",
@@ -221,4 +350,80 @@ mod test {
 
         Ok(())
     }
+
+    #[rstest]
+    #[case(HashAlgorithm::Bcrypt, "$2")]
+    #[case(HashAlgorithm::Sha256, "$5$")]
+    #[case(HashAlgorithm::Sha512, "$6$")]
+    #[case(HashAlgorithm::Argon2, "$argon2id$")]
+    #[case(HashAlgorithm::Md5, "$apr1$")]
+    fn test_credential_algorithms_passes(
+        #[case] algorithm: HashAlgorithm,
+        #[case] prefix: &str,
+    ) -> Result<()> {
+        let credential = Credential::new("rustic", "its_me", algorithm)?;
+
+        assert!(credential.hash.starts_with(prefix), "{}", credential.hash);
+        assert_eq!(credential.algorithm(), Some(algorithm));
+        assert_eq!(credential.is_weak(), algorithm == HashAlgorithm::Md5);
+        assert!(credential.verify("its_me"));
+        assert!(!credential.verify("not_me"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_htpasswd_bcrypt_from_apache_passes() -> Result<()> {
+        // created by `htpasswd -B`
+        let htpasswd =
+            Htpasswd::from_file_strict(&PathBuf::from("tests/fixtures/test_data/.htpasswd"))?;
+
+        let credential = htpasswd.read("rustic").unwrap();
+        assert_eq!(credential.algorithm(), Some(HashAlgorithm::Bcrypt));
+        assert!(credential.verify("rustic"));
+        assert!(!credential.verify("restic"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_hash_passes() -> Result<()> {
+        let base = PathBuf::from("tests/generated/test_upgrade_hash");
+        if base.exists() {
+            fs::remove_dir_all(&base)?;
+        }
+        fs::create_dir_all(&base)?;
+
+        let path = base.join(".htpasswd");
+        let mut htpasswd = Htpasswd::from_file(&path)?;
+        htpasswd.update("rustic", "its_me", HashAlgorithm::Md5)?;
+        htpasswd.update("restic", "its_me", HashAlgorithm::Sha512)?;
+        htpasswd.to_file()?;
+
+        // hashes are only upgraded if requested
+        let auth = Auth::from_file(false, &path)?;
+        assert!(!auth.needs_upgrade("rustic"));
+
+        let auth = auth.set_upgrade_hashes(true);
+        assert!(auth.needs_upgrade("rustic"));
+        assert!(!auth.needs_upgrade("restic"));
+        assert!(auth.upgrade_hash("rustic", "not_me")?.is_none());
+
+        let upgraded = auth.upgrade_hash("rustic", "its_me")?.unwrap();
+        assert!(upgraded.verify("rustic", "its_me"));
+        assert!(!upgraded.needs_upgrade("rustic"));
+
+        let htpasswd = Htpasswd::from_file_strict(&path)?;
+        let credential = htpasswd.read("rustic").unwrap();
+        assert_eq!(credential.algorithm(), Some(HashAlgorithm::Bcrypt));
+        assert!(credential.verify("its_me"));
+        assert_eq!(
+            htpasswd.read("restic").unwrap().algorithm(),
+            Some(HashAlgorithm::Sha512)
+        );
+
+        fs::remove_dir_all(&base)?;
+
+        Ok(())
+    }
 }
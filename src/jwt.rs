@@ -0,0 +1,882 @@
+//! JWT bearer authentication
+//!
+//! Clients of an SSO like Keycloak or Dex can authenticate with an
+//! `Authorization: Bearer <jwt>` header instead of a password. The signature of
+//! a token is verified with the keys of its issuer, read from a JWKS file or URL
+//! (`--jwt-jwks`) or discovered from the OpenID Connect configuration of the
+//! issuer (`--jwt-issuer`). The user is taken from the `sub` claim and the
+//! groups, which ACL entries like `@backup` refer to, from the `groups` claim.
+//!
+//! Only asymmetric signatures are accepted: RS256, RS384, RS512, ES256, ES384
+//! and EdDSA (Ed25519). Keys are only fetched over HTTPS, verified with the
+//! public root certificates and the optional `--jwt-ca-cert`. A token signed
+//! with a key that is not known yet makes the server refetch the keys of a
+//! remote issuer, at most once a minute, so rotated keys are picked up without
+//! a reload.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwap;
+use axum::http::{
+    header::{ACCEPT, AUTHORIZATION},
+    HeaderMap,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey, VerificationAlgorithm};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    config::HtpasswdSettings,
+    error::{AppResult, Error, ErrorKind},
+};
+
+/// Default claim holding the user
+pub const DEFAULT_USER_CLAIM: &str = "sub";
+
+/// Default claim holding the groups of the user
+pub const DEFAULT_GROUPS_CLAIM: &str = "groups";
+
+/// Tolerated clock difference to the issuer when checking `exp` and `nbf`
+const LEEWAY: u64 = 60;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limit of the size of a fetched document
+const MAX_DOCUMENT_SIZE: usize = 1024 * 1024;
+
+/// Minimum time between refetches of the keys for tokens signed with unknown keys
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Static storage of our JWT authentication
+pub static JWT: OnceLock<ArcSwap<JwtAuth>> = OnceLock::new();
+
+pub(crate) fn init_jwt(jwt: JwtAuth) -> AppResult<()> {
+    let _ = JWT.get_or_init(|| ArcSwap::from_pointee(jwt));
+    Ok(())
+}
+
+/// Replaces the JWT authentication of the running server, e.g. after the keys have been rotated
+pub fn replace_jwt(jwt: JwtAuth) {
+    if let Some(current) = JWT.get() {
+        current.store(Arc::new(jwt));
+    }
+}
+
+/// Where the keys of the issuer come from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JwksSource {
+    /// A JWKS file
+    File(PathBuf),
+    /// A JWKS document at an `https://` URL
+    Url(String),
+    /// The `jwks_uri` of the OpenID Connect configuration of an issuer
+    Discovery(String),
+}
+
+impl JwksSource {
+    async fn load(&self, client: &reqwest::Client) -> AppResult<String> {
+        match self {
+            Self::File(path) => fs::read_to_string(path).map_err(|err| {
+                ErrorKind::Io
+                    .context(format!(
+                        "Could not read JWKS file `{}`: {err}",
+                        path.display()
+                    ))
+                    .into()
+            }),
+            Self::Url(url) => fetch(client, url).await,
+            Self::Discovery(issuer) => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    issuer.trim_end_matches('/')
+                );
+                let configuration: Value = serde_json::from_str(&fetch(client, &url).await?)
+                    .map_err(|err| {
+                        ErrorKind::Config.context(format!(
+                            "Invalid OpenID Connect configuration `{url}`: {err}"
+                        ))
+                    })?;
+                let jwks_uri = configuration["jwks_uri"].as_str().ok_or_else(|| {
+                    ErrorKind::Config.context(format!(
+                        "OpenID Connect configuration `{url}` has no `jwks_uri`"
+                    ))
+                })?;
+
+                fetch(client, jwks_uri).await
+            }
+        }
+    }
+}
+
+impl fmt::Display for JwksSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Url(url) => write!(f, "{url}"),
+            Self::Discovery(issuer) => write!(f, "{issuer}"),
+        }
+    }
+}
+
+/// A public key of the issuer
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    alg: Option<String>,
+    #[serde(default, rename = "use")]
+    usage: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+impl Jwk {
+    /// `true` if this key can verify a signature made with `alg`
+    fn supports(&self, alg: &str) -> bool {
+        let kty_matches = match alg {
+            "RS256" | "RS384" | "RS512" => self.kty == "RSA",
+            "ES256" => self.kty == "EC" && self.crv.as_deref() == Some("P-256"),
+            "ES384" => self.kty == "EC" && self.crv.as_deref() == Some("P-384"),
+            "EdDSA" => self.kty == "OKP" && self.crv.as_deref() == Some("Ed25519"),
+            _ => false,
+        };
+
+        kty_matches
+            && self.alg.as_deref().map_or(true, |key_alg| key_alg == alg)
+            && self.usage.as_deref().map_or(true, |usage| usage == "sig")
+    }
+
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        match alg {
+            "RS256" | "RS384" | "RS512" => {
+                let (Some(n), Some(e)) = (decode(self.n.as_deref()), decode(self.e.as_deref()))
+                else {
+                    return false;
+                };
+                let params = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+                };
+                // ring expects the modulus without leading zeros
+                let n = &n[n.iter().take_while(|byte| **byte == 0).count()..];
+
+                RsaPublicKeyComponents { n, e: &e }
+                    .verify(params, message, signature)
+                    .is_ok()
+            }
+            "ES256" | "ES384" => {
+                let (Some(x), Some(y)) = (decode(self.x.as_deref()), decode(self.y.as_deref()))
+                else {
+                    return false;
+                };
+                let algorithm: &'static dyn VerificationAlgorithm = if alg == "ES256" {
+                    &signature::ECDSA_P256_SHA256_FIXED
+                } else {
+                    &signature::ECDSA_P384_SHA384_FIXED
+                };
+                // An uncompressed point
+                let point = [&[4][..], &x, &y].concat();
+
+                UnparsedPublicKey::new(algorithm, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            "EdDSA" => decode(self.x.as_deref()).is_some_and(|x| {
+                UnparsedPublicKey::new(&signature::ED25519, x)
+                    .verify(message, signature)
+                    .is_ok()
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// Header of a token
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// Identity of a verified token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JwtIdentity {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+/// `JwtAuth` verifies bearer tokens with the keys of their issuer
+#[derive(Clone, Debug)]
+pub struct JwtAuth {
+    source: JwksSource,
+    client: reqwest::Client,
+    keys: Vec<Jwk>,
+    /// When the keys have last been refetched for an unknown key
+    refreshed: Arc<Mutex<Option<Instant>>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    user_claim: String,
+    groups_claim: String,
+}
+
+impl JwtAuth {
+    /// Creates the JWT authentication from the settings, `None` if it is disabled
+    ///
+    /// It has no keys until they are loaded with [`JwtAuth::reload`].
+    pub fn from_settings(settings: &HtpasswdSettings) -> AppResult<Option<Self>> {
+        let source = match (&settings.jwt_jwks, &settings.jwt_issuer) {
+            (Some(jwks), _) if jwks.starts_with("https://") => JwksSource::Url(jwks.clone()),
+            (Some(jwks), _) if jwks.starts_with("http://") => return Err(insecure_url(jwks)),
+            (Some(jwks), _) => JwksSource::File(PathBuf::from(jwks)),
+            (None, Some(issuer)) if issuer.starts_with("https://") => {
+                JwksSource::Discovery(issuer.clone())
+            }
+            (None, Some(issuer)) => return Err(insecure_url(issuer)),
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            source,
+            client: client(settings.jwt_ca_cert.as_deref())?,
+            keys: Vec::new(),
+            refreshed: Arc::default(),
+            issuer: settings.jwt_issuer.clone(),
+            audience: settings.jwt_audience.clone(),
+            user_claim: settings
+                .jwt_user_claim
+                .clone()
+                .unwrap_or_else(|| DEFAULT_USER_CLAIM.to_string()),
+            groups_claim: settings
+                .jwt_groups_claim
+                .clone()
+                .unwrap_or_else(|| DEFAULT_GROUPS_CLAIM.to_string()),
+        }))
+    }
+
+    /// Loads the keys from their source
+    pub async fn reload(&self) -> AppResult<Self> {
+        let jwks = self.source.load(&self.client).await?;
+        let JwkSet { keys } = serde_json::from_str(&jwks).map_err(|err| {
+            ErrorKind::Config.context(format!("Invalid JWKS `{}`: {err}", self.source))
+        })?;
+
+        if keys.is_empty() {
+            return Err(ErrorKind::Config
+                .context(format!("JWKS `{}` contains no keys", self.source))
+                .into());
+        }
+
+        Ok(Self {
+            keys,
+            ..self.clone()
+        })
+    }
+
+    /// Refetches the keys of a remote issuer if `token` is signed with a key that is not known
+    ///
+    /// Returns the JWT authentication with the new keys, or `None` if nothing has
+    /// been refetched. Refetches happen at most once per `REFRESH_INTERVAL`.
+    pub async fn refresh_for(&self, token: &str) -> Option<Self> {
+        let kid = token
+            .split('.')
+            .next()
+            .and_then(|header| decode(Some(header)))
+            .and_then(|header| serde_json::from_slice::<Header>(&header).ok())?
+            .kid?;
+
+        let is_known = self
+            .keys
+            .iter()
+            .any(|key| key.kid.as_deref() == Some(kid.as_str()));
+        if is_known || matches!(self.source, JwksSource::File(_)) {
+            return None;
+        }
+
+        {
+            let mut refreshed = self.refreshed.lock().unwrap();
+            if refreshed.is_some_and(|refreshed| refreshed.elapsed() < REFRESH_INTERVAL) {
+                return None;
+            }
+            *refreshed = Some(Instant::now());
+        }
+
+        match self.reload().await {
+            Ok(jwt) => {
+                info!(%kid, source = %self.source, "[JWT] refetched the keys of the issuer for an unknown key");
+                Some(jwt)
+            }
+            Err(err) => {
+                warn!(%kid, %err, "[JWT] could not refetch the keys of the issuer");
+                None
+            }
+        }
+    }
+
+    pub const fn source(&self) -> &JwksSource {
+        &self.source
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Verifies the signature and the claims of `token` and returns its identity
+    pub fn verify(&self, token: &str) -> Result<JwtIdentity, String> {
+        let (message, signature) = token.rsplit_once('.').ok_or("malformed token")?;
+        let Some((header, payload)) = message
+            .split_once('.')
+            .filter(|(_, payload)| !payload.contains('.'))
+        else {
+            return Err("malformed token".to_string());
+        };
+
+        let header: Header = decode(Some(header))
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or("malformed header")?;
+        let signature = decode(Some(signature)).ok_or("malformed signature")?;
+
+        let verified = self
+            .keys
+            .iter()
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .filter(|key| key.supports(&header.alg))
+            .any(|key| key.verify(&header.alg, message.as_bytes(), &signature));
+        if !verified {
+            return Err(format!("no key verifies the {} signature", header.alg));
+        }
+
+        let claims: Value = decode(Some(payload))
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or("malformed claims")?;
+
+        self.check_claims(&claims, now())
+    }
+
+    fn check_claims(&self, claims: &Value, now: u64) -> Result<JwtIdentity, String> {
+        let exp = claims["exp"].as_u64().ok_or("no expiry")?;
+        if exp.saturating_add(LEEWAY) <= now {
+            return Err("expired".to_string());
+        }
+        if claims["nbf"]
+            .as_u64()
+            .is_some_and(|nbf| nbf > now.saturating_add(LEEWAY))
+        {
+            return Err("not yet valid".to_string());
+        }
+
+        if let Some(issuer) = &self.issuer {
+            if claims["iss"].as_str() != Some(issuer.as_str()) {
+                return Err("wrong issuer".to_string());
+            }
+        }
+
+        if let Some(audience) = &self.audience {
+            let matches = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong audience".to_string());
+            }
+        }
+
+        let user = claims[&self.user_claim]
+            .as_str()
+            .filter(|user| !user.is_empty())
+            .ok_or_else(|| format!("no `{}` claim", self.user_claim))?
+            .to_string();
+
+        let groups = match &claims[&self.groups_claim] {
+            Value::String(group) => vec![group.clone()],
+            Value::Array(groups) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(ToString::to_string))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(JwtIdentity { user, groups })
+    }
+}
+
+/// Returns the token of an `Authorization: Bearer` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// Decodes base64url, tolerating padding
+fn decode(value: Option<&str>) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(value?.trim_end_matches('=')).ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn insecure_url(url: &str) -> Error {
+    ErrorKind::Config
+        .context(format!(
+            "`{url}` uses plain HTTP, the keys of the issuer have to be fetched with `https://`"
+        ))
+        .into()
+}
+
+/// HTTPS client verifying the issuer with the public roots and `ca_cert`
+fn client(ca_cert: Option<&Path>) -> AppResult<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().https_only(true).timeout(TIMEOUT);
+
+    if let Some(ca_cert) = ca_cert {
+        let cert = fs::read(ca_cert)
+            .ok()
+            .and_then(|pem| reqwest::Certificate::from_pem(&pem).ok())
+            .ok_or_else(|| {
+                ErrorKind::Config.context(format!(
+                    "Invalid JWT issuer CA certificate `{}`",
+                    ca_cert.display()
+                ))
+            })?;
+        builder = builder.add_root_certificate(cert);
+    }
+
+    Ok(builder.build().map_err(|err| {
+        ErrorKind::Config.context(format!("Could not create the HTTPS client: {err}"))
+    })?)
+}
+
+/// Fetches `url` with a GET
+async fn fetch(client: &reqwest::Client, url: &str) -> AppResult<String> {
+    let error = |err: String| ErrorKind::Io.context(format!("Could not fetch `{url}`: {err}"));
+
+    let mut response = client
+        .get(url)
+        .header(ACCEPT, "application/json")
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| error(err.to_string()))?;
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|err| error(err.to_string()))?
+    {
+        if body.len() + chunk.len() > MAX_DOCUMENT_SIZE {
+            return Err(error("document is too large".to_string()).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{path::PathBuf, sync::Arc};
+
+    use axum::http::{HeaderMap, HeaderValue};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ring::{
+        rand::SystemRandom,
+        signature::{
+            EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents,
+            ECDSA_P256_SHA256_FIXED_SIGNING, RSA_PKCS1_SHA256,
+        },
+    };
+    use rustls::pki_types::{pem::PemObject, PrivatePkcs8KeyDer};
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::{
+        config::HtpasswdSettings,
+        context::TlsOptions,
+        jwt::{bearer_token, now, JwksSource, JwtAuth, JwtIdentity},
+        testing::test_data_path,
+        tls::server_config,
+    };
+
+    /// A mock issuer signing tokens with an Ed25519 and a P-256 key
+    pub(crate) struct Issuer {
+        ed25519: Ed25519KeyPair,
+        p256: EcdsaKeyPair,
+    }
+
+    impl Issuer {
+        pub(crate) fn new() -> Self {
+            let rng = SystemRandom::new();
+            let ed25519 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            let p256 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+
+            Self {
+                ed25519: Ed25519KeyPair::from_pkcs8(ed25519.as_ref()).unwrap(),
+                p256: EcdsaKeyPair::from_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    p256.as_ref(),
+                    &rng,
+                )
+                .unwrap(),
+            }
+        }
+
+        pub(crate) fn jwks(&self) -> Value {
+            let point = self.p256.public_key().as_ref();
+
+            json!({"keys": [
+                {
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "kid": "ed",
+                    "x": URL_SAFE_NO_PAD.encode(self.ed25519.public_key()),
+                },
+                {
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "kid": "ec",
+                    "use": "sig",
+                    "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                    "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+                },
+            ]})
+        }
+
+        /// Signs `claims` with the Ed25519 key
+        pub(crate) fn token(&self, claims: &Value) -> String {
+            self.sign("EdDSA", "ed", claims)
+        }
+
+        fn sign(&self, alg: &str, kid: &str, claims: &Value) -> String {
+            let header = json!({"alg": alg, "kid": kid, "typ": "JWT"});
+            let message = format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(header.to_string()),
+                URL_SAFE_NO_PAD.encode(claims.to_string())
+            );
+            let signature = if alg == "EdDSA" {
+                self.ed25519.sign(message.as_bytes()).as_ref().to_vec()
+            } else {
+                self.p256
+                    .sign(&SystemRandom::new(), message.as_bytes())
+                    .unwrap()
+                    .as_ref()
+                    .to_vec()
+            };
+
+            format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature))
+        }
+    }
+
+    /// Serves the OpenID Connect configuration and the JWKS of an issuer over HTTPS
+    async fn serve_issuer(jwks: Value) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("https://{}", listener.local_addr().unwrap());
+        let configuration = json!({"issuer": issuer, "jwks_uri": format!("{issuer}/jwks.json")});
+
+        let certs = test_data_path().join("certs");
+        let tls = TlsOptions {
+            tls_key: certs.join("issuer.key"),
+            tls_cert: certs.join("issuer.crt"),
+            ..Default::default()
+        };
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&tls).unwrap()));
+
+        drop(tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let (reader, mut writer) = tokio::io::split(stream);
+                let mut lines = BufReader::new(reader).lines();
+                let request_line = lines.next_line().await.ok().flatten().unwrap_or_default();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.is_empty() {
+                        break;
+                    }
+                }
+
+                let response = if request_line.starts_with("GET /.well-known/openid-configuration ")
+                {
+                    format!(
+                        "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{configuration}"
+                    )
+                } else if request_line.starts_with("GET /jwks.json ") {
+                    format!("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{jwks}")
+                } else {
+                    "HTTP/1.0 404 Not Found\r\n\r\n".to_string()
+                };
+                let _ = writer.write_all(response.as_bytes()).await;
+                let _ = writer.shutdown().await;
+            }
+        }));
+
+        issuer
+    }
+
+    fn claims(issuer: &str) -> Value {
+        json!({
+            "iss": issuer,
+            "aud": ["rustic-server"],
+            "sub": "rustic",
+            "exp": now() + 300,
+            "groups": ["backup", "admins"],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_jwt_discovery_passes() {
+        let issuer = Issuer::new();
+        let url = serve_issuer(issuer.jwks()).await;
+        let ca_cert = test_data_path().join("certs/issuer_ca.crt");
+
+        let settings = HtpasswdSettings {
+            jwt_issuer: Some(url.clone()),
+            jwt_audience: Some("rustic-server".to_string()),
+            jwt_ca_cert: Some(ca_cert.clone()),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        let jwt = jwt.reload().await.unwrap();
+        assert_eq!(jwt.source(), &JwksSource::Discovery(url.clone()));
+        assert_eq!(jwt.key_count(), 2);
+
+        let identity = JwtIdentity {
+            user: "rustic".to_string(),
+            groups: vec!["backup".to_string(), "admins".to_string()],
+        };
+        assert_eq!(
+            jwt.verify(&issuer.token(&claims(&url))),
+            Ok(identity.clone())
+        );
+        assert_eq!(
+            jwt.verify(&issuer.sign("ES256", "ec", &claims(&url))),
+            Ok(identity)
+        );
+
+        // tokens of another issuer or for another audience are refused
+        assert!(jwt.verify(&issuer.token(&claims("http://other"))).is_err());
+        let mut other_audience = claims(&url);
+        other_audience["aud"] = json!("other");
+        assert!(jwt.verify(&issuer.token(&other_audience)).is_err());
+
+        // the keys are refetched for a token signed with an unknown key
+        let without_keys = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        let token = issuer.token(&claims(&url));
+        assert!(without_keys.verify(&token).is_err());
+        let refreshed = without_keys.refresh_for(&token).await.unwrap();
+        assert_eq!(refreshed.key_count(), 2);
+        assert!(refreshed.verify(&token).is_ok());
+        assert!(refreshed.refresh_for(&token).await.is_none());
+
+        // but only once per interval
+        let unknown = issuer.sign("EdDSA", "rotated", &claims(&url));
+        assert!(refreshed.refresh_for(&unknown).await.is_none());
+        assert!(without_keys.refresh_for(&token).await.is_none());
+
+        // the keys can also be fetched directly
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some(format!("{url}/jwks.json")),
+            jwt_ca_cert: Some(ca_cert.clone()),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        let jwt = jwt.reload().await.unwrap();
+        assert!(jwt.verify(&issuer.token(&claims(&url))).is_ok());
+
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some(format!("{url}/missing.json")),
+            jwt_ca_cert: Some(ca_cert),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        assert!(jwt.reload().await.is_err());
+
+        // the certificate of the issuer is verified
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some(format!("{url}/jwks.json")),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        assert!(jwt.reload().await.is_err());
+
+        // plain HTTP is refused
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some("http://127.0.0.1/jwks.json".to_string()),
+            ..Default::default()
+        };
+        assert!(JwtAuth::from_settings(&settings).is_err());
+        let settings = HtpasswdSettings {
+            jwt_issuer: Some("http://127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        assert!(JwtAuth::from_settings(&settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jwt_verification_fails() {
+        let issuer = Issuer::new();
+        let dir = PathBuf::from("tests/generated/test_jwt");
+        std::fs::create_dir_all(&dir).unwrap();
+        let jwks = dir.join("jwks.json");
+        std::fs::write(&jwks, issuer.jwks().to_string()).unwrap();
+
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some(jwks.display().to_string()),
+            jwt_user_claim: Some("preferred_username".to_string()),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        let jwt = jwt.reload().await.unwrap();
+
+        let mut claims = claims("http://issuer");
+        claims["preferred_username"] = json!("restic");
+        claims["groups"] = json!("backup");
+        let token = issuer.token(&claims);
+        assert_eq!(
+            jwt.verify(&token)
+                .map(|identity| (identity.user, identity.groups)),
+            Ok(("restic".to_string(), vec!["backup".to_string()]))
+        );
+
+        // a modified token doesn't verify
+        let (message, signature) = token.rsplit_once('.').unwrap();
+        let mut modified = claims.clone();
+        modified["preferred_username"] = json!("admin");
+        let (header, _) = message.split_once('.').unwrap();
+        let forged = format!(
+            "{header}.{}.{signature}",
+            URL_SAFE_NO_PAD.encode(modified.to_string())
+        );
+        assert!(jwt.verify(&forged).is_err());
+
+        // unsigned tokens are refused
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(json!({"alg": "none"}).to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        assert!(jwt.verify(&unsigned).is_err());
+
+        // a key can't be used with another algorithm
+        assert!(jwt.verify(&issuer.sign("ES256", "ed", &claims)).is_err());
+
+        let mut expired = claims.clone();
+        expired["exp"] = json!(now() - 3600);
+        assert!(jwt.verify(&issuer.token(&expired)).is_err());
+        // the leeway doesn't overflow
+        let mut far_future = claims.clone();
+        far_future["exp"] = json!(u64::MAX);
+        assert!(jwt.verify(&issuer.token(&far_future)).is_ok());
+
+        let mut no_user = claims;
+        _ = no_user
+            .as_object_mut()
+            .unwrap()
+            .remove("preferred_username");
+        assert!(jwt.verify(&issuer.token(&no_user)).is_err());
+
+        assert!(jwt.verify("not.a.token").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jwt_rs256_passes() {
+        let key = PrivatePkcs8KeyDer::from_pem_file("tests/fixtures/test_data/certs/jwt_rsa.key")
+            .unwrap();
+        let key = RsaKeyPair::from_pkcs8(key.secret_pkcs8_der()).unwrap();
+
+        let dir = PathBuf::from("tests/generated/test_jwt_rs256");
+        std::fs::create_dir_all(&dir).unwrap();
+        let jwks = dir.join("jwks.json");
+        let public: RsaPublicKeyComponents<Vec<u8>> = key.public().into();
+        let jwk = json!({
+            "kty": "RSA",
+            "alg": "RS256",
+            "n": URL_SAFE_NO_PAD.encode(public.n),
+            "e": URL_SAFE_NO_PAD.encode(public.e),
+        });
+        std::fs::write(&jwks, json!({"keys": [jwk]}).to_string()).unwrap();
+
+        let settings = HtpasswdSettings {
+            jwt_jwks: Some(jwks.display().to_string()),
+            ..Default::default()
+        };
+        let jwt = JwtAuth::from_settings(&settings).unwrap().unwrap();
+        let jwt = jwt.reload().await.unwrap();
+
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(json!({"alg": "RS256"}).to_string()),
+            URL_SAFE_NO_PAD.encode(claims("http://issuer").to_string())
+        );
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .unwrap();
+        let token = format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature));
+
+        assert_eq!(
+            jwt.verify(&token).map(|identity| identity.user),
+            Ok("rustic".to_string())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bearer_token_passes() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        let _ = headers.insert("authorization", HeaderValue::from_static("Basic cnVzdGlj"));
+        assert_eq!(bearer_token(&headers), None);
+
+        let _ = headers.insert(
+            "authorization",
+            HeaderValue::from_static("bearer abc.def.ghi"),
+        );
+        assert_eq!(bearer_token(&headers), Some("abc.def.ghi"));
+    }
+}
@@ -0,0 +1,810 @@
+//! LDAP
+//!
+//! Authenticates users against an LDAP directory like OpenLDAP or Active
+//! Directory with a simple bind. The DN of a user is either built from the
+//! `--ldap-user-dn` template, or searched below `--ldap-base-dn` with
+//! `--ldap-user-filter`. An optional `--ldap-group-filter` has to match the
+//! entry of the user as well, e.g. to require membership in a group.
+//!
+//! The groups in the `memberOf` attribute of the user directly below
+//! `--ldap-group-base-dn` are the groups of the user, named by their common
+//! name, which ACL entries like `@backup` refer to. Groups elsewhere in the
+//! directory are ignored, so equally named groups of other branches don't count.
+//!
+//! Passwords are only sent over TLS, either with `ldaps://` or with StartTLS,
+//! unless plain `ldap://` is explicitly allowed with `--ldap-allow-insecure`.
+
+use std::{fmt, path::Path, sync::Arc, time::Duration};
+
+use ldap3::{
+    dn_escape, ldap_escape, parse_filter, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope,
+    SearchEntry,
+};
+use ldap3_rustls::{Certificate, ClientConfig, RootCertStore};
+use rustls::pki_types::{pem::PemObject, CertificateDer};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::AuthBackend,
+    config::HtpasswdSettings,
+    error::{AppResult, Error, ErrorKind},
+};
+
+/// Default filter to search users with
+pub const DEFAULT_USER_FILTER: &str = "(uid={user})";
+
+/// Attribute listing the groups of a user
+const GROUP_ATTRIBUTE: &str = "memberOf";
+
+/// Time a connection, bind or search may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// LDAP result code of a bind with wrong credentials
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// How the DN of a user is found
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum UserLookup {
+    /// Bind directly as the DN built from a template
+    Bind { user_dn: String },
+    /// Search the user, binding as a service account first
+    Search {
+        base_dn: String,
+        user_filter: String,
+        bind_dn: String,
+        bind_password: String,
+    },
+}
+
+/// Address of the LDAP server
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct LdapUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+}
+
+impl LdapUrl {
+    fn parse(url: &str) -> AppResult<Self> {
+        let invalid =
+            |reason: &str| ErrorKind::Config.context(format!("Invalid LDAP URL `{url}`: {reason}"));
+
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("ldaps://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ldap://") {
+            (false, rest)
+        } else {
+            return Err(invalid("expected `ldap://` or `ldaps://`").into());
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let default_port = if tls { 636 } else { 389 };
+
+        // IPv6 addresses are written in brackets, e.g. `ldap://[::1]:389`
+        let (host, port) = match rest.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(|| invalid("missing `]`"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            },
+        };
+
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid("expected a host").into());
+        }
+
+        let port = port
+            .map(str::parse)
+            .transpose()
+            .map_err(|_| invalid("invalid port"))?
+            .unwrap_or(default_port);
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for LdapUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "ldaps" } else { "ldap" };
+        if self.host.contains(':') {
+            write!(f, "{scheme}://[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{scheme}://{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// `LdapAuth` verifies passwords with a bind to an LDAP server
+#[derive(Clone)]
+pub struct LdapAuth {
+    url: LdapUrl,
+    lookup: UserLookup,
+    group_filter: Option<String>,
+    group_base_dn: Option<Vec<(String, String)>>,
+    starttls: bool,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl fmt::Debug for LdapAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lookup = match &self.lookup {
+            UserLookup::Bind { user_dn } => format!("bind as `{user_dn}`"),
+            UserLookup::Search {
+                base_dn,
+                user_filter,
+                bind_dn,
+                ..
+            } => format!("search `{user_filter}` below `{base_dn}` as `{bind_dn}`"),
+        };
+
+        f.debug_struct("LdapAuth")
+            .field("url", &self.url.to_string())
+            .field("lookup", &lookup)
+            .field("group_filter", &self.group_filter)
+            .field("group_base_dn", &self.group_base_dn)
+            .field("starttls", &self.starttls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LdapAuth {
+    /// Creates the LDAP backend from the settings, `None` if no LDAP server is configured
+    pub fn from_settings(settings: &HtpasswdSettings) -> AppResult<Option<Self>> {
+        let Some(url) = &settings.ldap_url else {
+            return Ok(None);
+        };
+        let url = LdapUrl::parse(url)?;
+
+        let lookup = match (&settings.ldap_user_dn, &settings.ldap_base_dn) {
+            (Some(user_dn), None) => UserLookup::Bind {
+                user_dn: user_dn.clone(),
+            },
+            (None, Some(base_dn)) => UserLookup::Search {
+                base_dn: base_dn.clone(),
+                user_filter: settings
+                    .ldap_user_filter
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_USER_FILTER.to_string()),
+                bind_dn: settings.ldap_bind_dn.clone().unwrap_or_default(),
+                bind_password: settings.ldap_bind_password.clone().unwrap_or_default(),
+            },
+            _ => {
+                return Err(ErrorKind::Config
+                    .context("LDAP needs exactly one of `--ldap-user-dn` and `--ldap-base-dn`.")
+                    .into())
+            }
+        };
+
+        match (url.tls, settings.ldap_starttls) {
+            (true, true) => {
+                return Err(ErrorKind::Config
+                    .context("`--ldap-starttls` needs an `ldap://` URL, `ldaps://` already uses TLS.")
+                    .into())
+            }
+            (false, false) if !settings.ldap_allow_insecure => {
+                return Err(ErrorKind::Config
+                    .context("`ldap://` sends passwords in plain text, use `ldaps://` or `--ldap-starttls`, or allow it with `--ldap-allow-insecure`.")
+                    .into())
+            }
+            (false, false) => {
+                warn!(%url, "[LDAP] passwords are sent in plain text");
+            }
+            _ => {}
+        }
+
+        let tls = if url.tls || settings.ldap_starttls {
+            let Some(ca_cert) = &settings.ldap_ca_cert else {
+                return Err(ErrorKind::Config
+                    .context("TLS needs the CA certificate of the LDAP server in `--ldap-ca-cert`.")
+                    .into());
+            };
+            Some(Arc::new(client_config(ca_cert)?))
+        } else {
+            None
+        };
+
+        let ldap = Self {
+            url,
+            lookup,
+            group_filter: settings.ldap_group_filter.clone(),
+            group_base_dn: settings.ldap_group_base_dn.as_deref().map(rdns),
+            starttls: settings.ldap_starttls,
+            tls,
+        };
+        ldap.validate()?;
+
+        Ok(Some(ldap))
+    }
+
+    pub fn url(&self) -> String {
+        self.url.to_string()
+    }
+
+    /// Refuses invalid templates and filters on startup instead of on the first login
+    fn validate(&self) -> AppResult<()> {
+        let filters = match &self.lookup {
+            UserLookup::Bind { user_dn } => {
+                if !user_dn.contains("{user}") {
+                    return Err(ErrorKind::Config
+                        .context("`--ldap-user-dn` has to contain `{user}`.")
+                        .into());
+                }
+                vec![self.base_filter("user")]
+            }
+            UserLookup::Search { .. } => vec![self.search_filter("user")],
+        };
+
+        for filter in filters {
+            let _ = parse_filter(&filter).map_err(|()| {
+                ErrorKind::Config.context(format!("Invalid LDAP filter `{filter}`"))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Filter for the entry of a user found by its DN
+    fn base_filter(&self, user: &str) -> String {
+        self.group_filter.as_ref().map_or_else(
+            || "(objectClass=*)".to_string(),
+            |group_filter| substitute(group_filter, user),
+        )
+    }
+
+    /// Filter to search the entry of a user
+    fn search_filter(&self, user: &str) -> String {
+        let UserLookup::Search { user_filter, .. } = &self.lookup else {
+            return self.base_filter(user);
+        };
+
+        let user_filter = substitute(user_filter, user);
+        match &self.group_filter {
+            Some(group_filter) => format!("(&{user_filter}{})", substitute(group_filter, user)),
+            None => user_filter,
+        }
+    }
+
+    async fn connect(&self) -> AppResult<Ldap> {
+        let mut settings = LdapConnSettings::new()
+            .set_conn_timeout(TIMEOUT)
+            .set_starttls(self.starttls);
+        if let Some(config) = &self.tls {
+            settings = settings.set_config(config.clone());
+        }
+
+        let (connection, ldap) = LdapConnAsync::with_settings(settings, &self.url.to_string())
+            .await
+            .map_err(|err| {
+                ErrorKind::Io.context(format!("Could not connect to `{}`: {err}", self.url))
+            })?;
+
+        let url = self.url.clone();
+        drop(tokio::spawn(async move {
+            if let Err(err) = connection.drive().await {
+                debug!(%url, %err, "[LDAP] connection failed");
+            }
+        }));
+
+        Ok(ldap)
+    }
+
+    /// The common names of the groups of an entry directly below the group base DN,
+    /// e.g. `backup` for `cn=backup,ou=groups,dc=example,dc=org`
+    fn groups(&self, entry: &SearchEntry) -> Vec<String> {
+        let Some(base) = &self.group_base_dn else {
+            return Vec::new();
+        };
+
+        entry
+            .attrs
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(GROUP_ATTRIBUTE))
+            .flat_map(|(_, values)| values)
+            .filter_map(|dn| group_name(dn, base))
+            .collect()
+    }
+
+    /// Returns the groups of `user`, if `password` is correct and the group filter matches
+    async fn authenticate_on(
+        &self,
+        ldap: &mut Ldap,
+        user: &str,
+        password: &str,
+    ) -> AppResult<Option<Vec<String>>> {
+        match &self.lookup {
+            UserLookup::Bind { user_dn } => {
+                let dn = user_dn.replace("{user}", &dn_escape(user));
+                if !bind(ldap, &dn, password).await? {
+                    return Ok(None);
+                }
+
+                let entries = search(ldap, &dn, Scope::Base, &self.base_filter(user)).await?;
+
+                Ok(entries.first().map(|entry| self.groups(entry)))
+            }
+            UserLookup::Search {
+                base_dn,
+                bind_dn,
+                bind_password,
+                ..
+            } => {
+                if !bind(ldap, bind_dn, bind_password).await? {
+                    return Err(ErrorKind::Config
+                        .context(format!("Could not bind to LDAP as `{bind_dn}`"))
+                        .into());
+                }
+
+                let entries =
+                    search(ldap, base_dn, Scope::Subtree, &self.search_filter(user)).await?;
+
+                match entries.as_slice() {
+                    [] => Ok(None),
+                    [entry] => Ok(bind(ldap, &entry.dn, password)
+                        .await?
+                        .then(|| self.groups(entry))),
+                    _ => Err(ErrorKind::Config
+                        .context(format!("LDAP search for user `{user}` is ambiguous"))
+                        .into()),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AuthBackend for LdapAuth {
+    async fn authenticate(&self, user: &str, password: &str) -> AppResult<Option<Vec<String>>> {
+        // A simple bind with an empty password is an unauthenticated bind, which
+        // succeeds for any DN
+        if user.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        let result = timeout(TIMEOUT, async {
+            let mut ldap = self.connect().await?;
+            let result = self.authenticate_on(&mut ldap, user, password).await;
+            let _ = ldap.unbind().await;
+            result
+        })
+        .await
+        .map_err(|_| ErrorKind::Io.context(format!("LDAP server `{}` timed out", self.url)))?;
+
+        debug!(%user, groups = ?result.as_ref().ok(), "[LDAP] authenticated");
+
+        result
+    }
+}
+
+/// Binds as `dn`, `false` if the credentials are invalid
+async fn bind(ldap: &mut Ldap, dn: &str, password: &str) -> AppResult<bool> {
+    let result = ldap.simple_bind(dn, password).await.map_err(ldap_error)?;
+    match result.rc {
+        0 => Ok(true),
+        INVALID_CREDENTIALS => Ok(false),
+        _ => Err(ldap_error(LdapError::from(result))),
+    }
+}
+
+/// Searches the entries matching `filter` with their groups
+async fn search(
+    ldap: &mut Ldap,
+    base: &str,
+    scope: Scope,
+    filter: &str,
+) -> AppResult<Vec<SearchEntry>> {
+    let (entries, _) = ldap
+        .search(base, scope, filter, vec![GROUP_ATTRIBUTE])
+        .await
+        .and_then(ldap3::SearchResult::success)
+        .map_err(ldap_error)?;
+
+    // referrals to other servers are not followed
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !entry.is_ref())
+        .map(SearchEntry::construct)
+        .collect())
+}
+
+fn ldap_error(err: LdapError) -> Error {
+    ErrorKind::Io
+        .context(format!("LDAP request failed: {err}"))
+        .into()
+}
+
+fn client_config(ca_cert: &Path) -> AppResult<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| {
+            ErrorKind::Io.context(format!(
+                "Could not read LDAP CA certificate `{}`: {err}",
+                ca_cert.display()
+            ))
+        })?;
+
+    for cert in certs {
+        roots.add(&Certificate(cert.to_vec())).map_err(|err| {
+            ErrorKind::Config.context(format!(
+                "Invalid LDAP CA certificate `{}`: {err}",
+                ca_cert.display()
+            ))
+        })?;
+    }
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Replaces `{user}` in a filter with the escaped user
+fn substitute(filter: &str, user: &str) -> String {
+    filter.replace("{user}", &ldap_escape(user))
+}
+
+/// Returns the common name of the group `dn`, if it is directly below `base`
+fn group_name(dn: &str, base: &[(String, String)]) -> Option<String> {
+    let rdns = rdns(dn);
+    let ((attribute, name), parent) = rdns.split_first()?;
+
+    let is_below_base = parent.len() == base.len()
+        && parent
+            .iter()
+            .zip(base)
+            .all(|((attr, value), (base_attr, base_value))| {
+                attr.eq_ignore_ascii_case(base_attr) && value.eq_ignore_ascii_case(base_value)
+            });
+
+    (attribute.eq_ignore_ascii_case("cn") && is_below_base).then(|| name.clone())
+}
+
+/// The attributes and unescaped values of the RDNs of `dn`, e.g. `[("cn", "backup"), ("dc", "org")]`
+fn rdns(dn: &str) -> Vec<(String, String)> {
+    let mut rdns = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (index, c) in dn.char_indices().chain([(dn.len(), ',')]) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                let rdn = &dn[start..index];
+                let (attribute, value) = rdn.split_once('=').unwrap_or(("", rdn));
+                rdns.push((attribute.trim().to_string(), unescape(value.trim())));
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    rdns
+}
+
+/// Resolves `\,` and hex escapes like `\2C` in an attribute value
+fn unescape(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend(c.to_string().bytes());
+            continue;
+        }
+
+        let rest = chars.as_str();
+        let hex = rest
+            .get(..2)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(byte) = hex {
+            bytes.push(byte);
+            chars = rest[2..].chars();
+        } else if let Some(c) = chars.next() {
+            bytes.extend(c.to_string().bytes());
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use ldap3::asn1::{parse_tag, StructureTag, PL};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        auth::AuthBackend,
+        config::HtpasswdSettings,
+        ldap::{group_name, rdns, substitute, LdapAuth, LdapUrl},
+    };
+
+    const USER_DN: &str = "uid=rustic,ou=people,dc=example,dc=org";
+
+    #[test]
+    fn test_substitute_passes() {
+        assert_eq!(
+            substitute("(uid={user})", "a*(b)\\"),
+            "(uid=a\\2a\\28b\\29\\5c)"
+        );
+    }
+
+    #[test]
+    fn test_group_name_passes() {
+        let base = rdns("ou=groups,dc=example,dc=org");
+        assert_eq!(
+            group_name("cn=backup,ou=groups,dc=example,dc=org", &base),
+            Some("backup".to_string())
+        );
+        assert_eq!(
+            group_name("CN=Backup\\, Admins,OU=Groups,DC=Example,DC=org", &base),
+            Some("Backup, Admins".to_string())
+        );
+        assert_eq!(
+            group_name("cn=backup,ou=\\67roups,dc=example,dc=org", &base),
+            Some("backup".to_string())
+        );
+
+        // groups of the same name elsewhere don't count
+        assert_eq!(
+            group_name("cn=backup,ou=app,dc=example,dc=org", &base),
+            None
+        );
+        assert_eq!(
+            group_name("cn=backup,ou=team,ou=groups,dc=example,dc=org", &base),
+            None
+        );
+        assert_eq!(
+            group_name("ou=backup,ou=groups,dc=example,dc=org", &base),
+            None
+        );
+        assert_eq!(
+            group_name("cn=backup\\,ou=groups,dc=example,dc=org", &base),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ldap_url_passes() {
+        let url = LdapUrl::parse("ldap://localhost").unwrap();
+        assert_eq!((url.tls, url.port), (false, 389));
+        let url = LdapUrl::parse("ldaps://[::1]:3636/").unwrap();
+        assert_eq!((url.tls, url.host.as_str(), url.port), (true, "::1", 3636));
+        assert!(LdapUrl::parse("http://localhost").is_err());
+        assert!(LdapUrl::parse("ldap://localhost:x").is_err());
+    }
+
+    /// Encodes a BER element, short enough for the definite short form
+    fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+        assert!(content.len() < 128);
+        let mut element = vec![tag, u8::try_from(content.len()).unwrap()];
+        element.extend(content);
+        element
+    }
+
+    /// The values of all primitive elements below `tag`
+    fn primitives(tag: &StructureTag) -> Vec<&[u8]> {
+        match &tag.payload {
+            PL::P(value) => vec![value.as_slice()],
+            PL::C(tags) => tags.iter().flat_map(primitives).collect(),
+        }
+    }
+
+    /// Reads the next LDAP message, `None` if the client hung up
+    async fn read_message(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<StructureTag> {
+        loop {
+            let parsed = parse_tag(buffer)
+                .ok()
+                .map(|(rest, tag)| (buffer.len() - rest.len(), tag));
+            if let Some((length, tag)) = parsed {
+                let _ = buffer.drain(..length);
+                return Some(tag);
+            }
+
+            let mut chunk = [0; 1024];
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => buffer.extend(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Answers binds and searches for a single user in `groups`
+    async fn serve_directory(listener: TcpListener) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            drop(tokio::spawn(async move {
+                let mut buffer = Vec::new();
+                while let Some(message) = read_message(&mut stream, &mut buffer).await {
+                    let PL::C(elements) = message.payload else {
+                        panic!("invalid request");
+                    };
+                    let [id, request] = elements.as_slice() else {
+                        panic!("invalid request");
+                    };
+                    let PL::P(id) = &id.payload else {
+                        panic!("invalid message id");
+                    };
+                    let parts = match &request.payload {
+                        PL::C(parts) => parts.as_slice(),
+                        PL::P(_) => &[],
+                    };
+
+                    let respond = |tag: u8, content: Vec<u8>| {
+                        let mut message = encode(0x02, id);
+                        message.extend(encode(tag, &content));
+                        encode(0x30, &message)
+                    };
+                    let result = |code: u8| {
+                        let mut result = encode(0x0a, &[code]);
+                        result.extend(encode(0x04, b""));
+                        result.extend(encode(0x04, b""));
+                        result
+                    };
+
+                    let response = match request.id {
+                        // bind
+                        0 => {
+                            let credentials = (primitives(&parts[1])[0], primitives(&parts[2])[0]);
+                            let valid = matches!(
+                                credentials,
+                                (b"cn=admin,dc=example,dc=org", b"admin")
+                                    | (b"uid=rustic,ou=people,dc=example,dc=org", b"rustic")
+                            );
+                            respond(0x61, result(if valid { 0 } else { 49 }))
+                        }
+                        // search, with a crude stand-in for evaluating the filter
+                        3 => {
+                            let base = primitives(&parts[0])[0];
+                            let values = primitives(&parts[6]);
+                            let mut response = Vec::new();
+                            if (base == USER_DN.as_bytes()
+                                || values.contains(&b"rustic".as_slice()))
+                                && !values.iter().any(|value| value.starts_with(b"cn=admins"))
+                            {
+                                let mut entry = encode(0x04, USER_DN.as_bytes());
+                                let mut attribute = encode(0x04, b"memberOf");
+                                attribute.extend(encode(
+                                    0x31,
+                                    &encode(0x04, b"cn=backup,ou=groups,dc=example,dc=org"),
+                                ));
+                                entry.extend(encode(0x30, &encode(0x30, &attribute)));
+                                response.extend(respond(0x64, entry));
+                            }
+                            response.extend(respond(0x65, result(0)));
+                            response
+                        }
+                        // unbind
+                        2 => break,
+                        id => panic!("unexpected request {id}"),
+                    };
+
+                    stream.write_all(&response).await.unwrap();
+                }
+            }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ldap_authentication_passes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let _server = tokio::spawn(serve_directory(listener));
+
+        // search and bind
+        let ldap = LdapAuth::from_settings(&HtpasswdSettings {
+            ldap_url: Some(url.clone()),
+            ldap_base_dn: Some("ou=people,dc=example,dc=org".to_string()),
+            ldap_bind_dn: Some("cn=admin,dc=example,dc=org".to_string()),
+            ldap_bind_password: Some("admin".to_string()),
+            ldap_group_base_dn: Some("ou=groups,dc=example,dc=org".to_string()),
+            ldap_allow_insecure: true,
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            ldap.authenticate("rustic", "rustic").await.unwrap(),
+            Some(vec!["backup".to_string()])
+        );
+        assert_eq!(ldap.authenticate("rustic", "_rustic").await.unwrap(), None);
+        assert_eq!(ldap.authenticate("restic", "restic").await.unwrap(), None);
+        // an empty password would be an unauthenticated bind
+        assert_eq!(ldap.authenticate("rustic", "").await.unwrap(), None);
+
+        // bind with a DN template, requiring membership in a group
+        let settings = HtpasswdSettings {
+            ldap_url: Some(url),
+            ldap_user_dn: Some("uid={user},ou=people,dc=example,dc=org".to_string()),
+            ldap_allow_insecure: true,
+            ..Default::default()
+        };
+        let ldap = LdapAuth::from_settings(&settings).unwrap().unwrap();
+        // without a group base DN, there are no groups
+        assert_eq!(
+            ldap.authenticate("rustic", "rustic").await.unwrap(),
+            Some(Vec::new())
+        );
+
+        let ldap = LdapAuth::from_settings(&HtpasswdSettings {
+            ldap_group_filter: Some("(memberOf=cn=admins,ou=groups,dc=example,dc=org)".to_string()),
+            ..settings
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(ldap.authenticate("rustic", "rustic").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_ldap_settings_fails() {
+        let settings = |user_dn: Option<&str>, base_dn: Option<&str>, url: &str| HtpasswdSettings {
+            ldap_url: Some(url.to_string()),
+            ldap_user_dn: user_dn.map(ToString::to_string),
+            ldap_base_dn: base_dn.map(ToString::to_string),
+            ldap_allow_insecure: true,
+            ..Default::default()
+        };
+
+        // exactly one way to find users
+        assert!(LdapAuth::from_settings(&settings(None, None, "ldap://localhost")).is_err());
+        assert!(LdapAuth::from_settings(&settings(
+            Some("uid={user}"),
+            Some("dc=org"),
+            "ldap://localhost"
+        ))
+        .is_err());
+        // the template needs the user
+        assert!(
+            LdapAuth::from_settings(&settings(Some("uid=rustic"), None, "ldap://localhost"))
+                .is_err()
+        );
+        // ldaps needs a CA certificate
+        assert!(
+            LdapAuth::from_settings(&settings(Some("uid={user}"), None, "ldaps://localhost"))
+                .is_err()
+        );
+        // filters are validated
+        let invalid_filter = HtpasswdSettings {
+            ldap_group_filter: Some("(memberOf=cn=backup".to_string()),
+            ..settings(Some("uid={user}"), None, "ldap://localhost")
+        };
+        assert!(LdapAuth::from_settings(&invalid_filter).is_err());
+
+        // plain ldap needs an explicit opt-in
+        let insecure = HtpasswdSettings {
+            ldap_allow_insecure: false,
+            ..settings(Some("uid={user}"), None, "ldap://localhost")
+        };
+        assert!(LdapAuth::from_settings(&insecure).is_err());
+        // StartTLS needs a CA certificate, and is only for ldap://
+        let starttls = HtpasswdSettings {
+            ldap_starttls: true,
+            ..insecure
+        };
+        assert!(LdapAuth::from_settings(&starttls).is_err());
+        let starttls = HtpasswdSettings {
+            ldap_ca_cert: Some("tests/fixtures/test_data/certs/client_ca.crt".into()),
+            ..starttls
+        };
+        assert!(LdapAuth::from_settings(&starttls).unwrap().is_some());
+        let ldaps = HtpasswdSettings {
+            ldap_url: Some("ldaps://localhost".to_string()),
+            ..starttls
+        };
+        assert!(LdapAuth::from_settings(&ldaps).is_err());
+    }
+}
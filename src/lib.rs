@@ -6,6 +6,7 @@
 
 #![allow(non_local_definitions)]
 
+pub mod access_log;
 pub mod acl;
 pub mod application;
 pub mod auth;
@@ -15,9 +16,18 @@ pub mod context;
 pub mod error;
 pub mod handlers;
 pub mod htpasswd;
+pub mod jwt;
+pub mod ldap;
+pub mod lockout;
 pub mod log;
+pub mod metrics;
 pub mod prelude;
+pub mod proxy_auth;
+pub mod quota;
+pub mod reload;
 pub mod storage;
+pub mod tls;
+pub mod tokens;
 pub mod typed_path;
 /// Web module
 ///
@@ -0,0 +1,561 @@
+//! Lockout
+//!
+//! Counts failed logins per user and client IP address and per client IP
+//! address. Once a user has failed `--lockout-threshold` times from an address,
+//! or an address has failed that often for any users, further logins from that
+//! address are refused with `429 Too Many Requests` for `--lockout-duration`
+//! seconds, doubling with every further failure. To slow down guessing the
+//! password of one user from many addresses, the failures of a user from all
+//! addresses together lock it out after ten times as many failures, except from
+//! the addresses it has logged in from before. Behind one of the
+//! `--trusted-proxies`, the address of the client is taken from
+//! `X-Forwarded-For`. With `--lockout-state-file`, active lockouts survive a
+//! restart of the server.
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, Local, TimeDelta};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    config::HtpasswdSettings,
+    error::{AppResult, ErrorKind},
+    proxy_auth::{client_ip, IpNetwork},
+    storage::write_atomic,
+};
+
+/// Default number of failed logins before a lockout
+pub const DEFAULT_THRESHOLD: u32 = 5;
+
+/// Default duration of the first lockout
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+
+/// Maximum duration of a lockout
+const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Failed logins are forgotten after this time without a further failure
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of users and addresses tracked, so unknown user names can't exhaust memory
+const MAX_ENTRIES: usize = 100_000;
+
+/// The failures of a user from all addresses together lock it out after this many times the threshold
+const USER_THRESHOLD_FACTOR: u32 = 10;
+
+// Static storage of our lockout
+pub static LOCKOUT: OnceLock<Lockout> = OnceLock::new();
+
+pub(crate) fn init_lockout(lockout: Lockout) -> AppResult<()> {
+    let _ = LOCKOUT.get_or_init(|| lockout);
+    Ok(())
+}
+
+/// Who failed to log in
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockoutKey {
+    /// A user from an address, or from an unknown address behind a trusted proxy
+    Login {
+        user: String,
+        ip: Option<IpAddr>,
+    },
+    Ip(IpAddr),
+    /// A user from all addresses, except those it has logged in from
+    Account(String),
+    /// A user from any address, as persisted by earlier versions; dropped when restoring
+    #[serde(skip_serializing)]
+    User(String),
+}
+
+/// Failed logins of a user or an address
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Attempts {
+    key: LockoutKey,
+    failures: u32,
+    last_failure: DateTime<Local>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked_until: Option<DateTime<Local>>,
+}
+
+impl Attempts {
+    fn remaining(&self, now: DateTime<Local>) -> Option<Duration> {
+        self.locked_until
+            .and_then(|locked_until| (locked_until - now).to_std().ok())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn is_forgotten(&self, now: DateTime<Local>) -> bool {
+        self.remaining(now).is_none() && is_expired(self.last_failure, now)
+    }
+}
+
+/// Persisted lockouts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LockoutState {
+    #[serde(default)]
+    lockouts: Vec<Attempts>,
+}
+
+/// `Lockout` tracks failed logins and locks out users and addresses
+#[derive(Clone, Debug)]
+pub struct Lockout {
+    threshold: u32,
+    duration: Duration,
+    state_file: Option<PathBuf>,
+    trusted_proxies: Vec<IpNetwork>,
+    attempts: Arc<Mutex<HashMap<LockoutKey, Attempts>>>,
+    /// Addresses users have logged in from, which a lockout of the user from all addresses spares
+    logins: Arc<Mutex<HashMap<LockoutKey, DateTime<Local>>>>,
+    /// Serializes writes of the state file
+    persisting: Arc<Mutex<()>>,
+}
+
+impl Lockout {
+    /// Locks out after `threshold` failures for `duration`, doubling with every further failure
+    pub fn new(threshold: u32, duration: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            duration,
+            state_file: None,
+            trusted_proxies: Vec::new(),
+            attempts: Arc::default(),
+            logins: Arc::default(),
+            persisting: Arc::default(),
+        }
+    }
+
+    /// Takes the address of clients behind these proxies from `X-Forwarded-For`
+    pub fn with_trusted_proxies(self, trusted_proxies: Vec<IpNetwork>) -> Self {
+        Self {
+            trusted_proxies,
+            ..self
+        }
+    }
+
+    /// Creates the lockout from the settings, `None` if it is disabled
+    pub fn from_settings(settings: &HtpasswdSettings) -> AppResult<Option<Self>> {
+        let threshold = settings.lockout_threshold.unwrap_or(DEFAULT_THRESHOLD);
+        if threshold == 0 {
+            return Ok(None);
+        }
+
+        let lockout = Self::new(
+            threshold,
+            settings
+                .lockout_duration
+                .map_or(DEFAULT_DURATION, Duration::from_secs),
+        )
+        .with_trusted_proxies(settings.trusted_proxies.clone());
+
+        settings
+            .lockout_state_file
+            .clone()
+            .map_or(Ok(lockout.clone()), |path| lockout.with_state_file(path))
+            .map(Some)
+    }
+
+    /// Persists lockouts to `path` and restores the ones still active from it
+    pub fn with_state_file(mut self, path: PathBuf) -> AppResult<Self> {
+        if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            let state: LockoutState = toml::from_str(&content).map_err(|err| {
+                ErrorKind::Config.context(format!(
+                    "Could not parse lockout state file `{}`: {err}",
+                    path.display()
+                ))
+            })?;
+
+            let now = Local::now();
+            let mut attempts = self.attempts.lock().unwrap();
+            for entry in state.lockouts {
+                if !entry.is_forgotten(now) && !matches!(entry.key, LockoutKey::User(_)) {
+                    let _ = attempts.insert(entry.key.clone(), entry);
+                }
+            }
+        }
+
+        self.state_file = Some(path);
+        Ok(self)
+    }
+
+    pub const fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub const fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Returns the address of the client of a request from `peer`
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        client_ip(headers, peer, &self.trusted_proxies)
+    }
+
+    /// Returns how long `user` is still locked out from `ip`, if it is
+    pub fn check(&self, user: &str, ip: Option<IpAddr>) -> Option<Duration> {
+        let now = Local::now();
+        let known = self.is_known(user, ip, now);
+        let attempts = self.attempts.lock().unwrap();
+
+        keys(user, ip)
+            .filter(|key| !(known && matches!(key, LockoutKey::Account(_))))
+            .filter_map(|key| attempts.get(&key))
+            .filter_map(|entry| entry.remaining(now))
+            .max()
+    }
+
+    /// Counts a failed login of `user` from `ip`, returns the duration of a new lockout
+    pub fn record_failure(&self, user: &str, ip: Option<IpAddr>) -> Option<Duration> {
+        let now = Local::now();
+        let mut locked = None;
+
+        {
+            let mut attempts = self.attempts.lock().unwrap();
+
+            if attempts.len() >= MAX_ENTRIES {
+                attempts.retain(|_, entry| !entry.is_forgotten(now));
+            }
+
+            for key in keys(user, ip) {
+                if attempts.len() >= MAX_ENTRIES && !attempts.contains_key(&key) {
+                    warn!(?key, "[lockout] too many tracked failures, not tracking");
+                    continue;
+                }
+
+                let entry = attempts.entry(key.clone()).or_insert_with(|| Attempts {
+                    key,
+                    failures: 0,
+                    last_failure: now,
+                    locked_until: None,
+                });
+
+                if entry.is_forgotten(now) {
+                    entry.failures = 0;
+                }
+                entry.failures += 1;
+                entry.last_failure = now;
+
+                if let Some(duration) = self.lockout_duration(&entry.key, entry.failures) {
+                    entry.locked_until = TimeDelta::from_std(duration)
+                        .ok()
+                        .and_then(|delta| now.checked_add_signed(delta));
+
+                    warn!(
+                        key = ?entry.key,
+                        failures = entry.failures,
+                        seconds = duration.as_secs(),
+                        "[lockout] locked out after repeated authentication failures"
+                    );
+                    locked = locked.max(Some(duration));
+                }
+            }
+        }
+
+        if locked.is_some() {
+            self.persist();
+        }
+
+        locked
+    }
+
+    /// Forgets the failed logins of `user` from `ip` after a successful login
+    ///
+    /// The failures of the user from all addresses are kept, but no longer lock
+    /// out the user from `ip`.
+    pub fn record_success(&self, user: &str, ip: Option<IpAddr>) {
+        let now = Local::now();
+
+        if let Some(ip) = ip {
+            let mut logins = self.logins.lock().unwrap();
+            if logins.len() >= MAX_ENTRIES {
+                logins.retain(|_, last_login| !is_expired(*last_login, now));
+            }
+            if logins.len() < MAX_ENTRIES {
+                let key = LockoutKey::Login {
+                    user: user.to_string(),
+                    ip: Some(ip),
+                };
+                let _ = logins.insert(key, now);
+            }
+        }
+
+        let removed = self.attempts.lock().unwrap().remove(&LockoutKey::Login {
+            user: user.to_string(),
+            ip,
+        });
+
+        if removed.is_some_and(|entry| entry.locked_until.is_some()) {
+            self.persist();
+        }
+    }
+
+    /// `true` if `user` has logged in from `ip` recently
+    fn is_known(&self, user: &str, ip: Option<IpAddr>, now: DateTime<Local>) -> bool {
+        let key = LockoutKey::Login {
+            user: user.to_string(),
+            ip,
+        };
+
+        ip.is_some()
+            && self
+                .logins
+                .lock()
+                .unwrap()
+                .get(&key)
+                .is_some_and(|last_login| !is_expired(*last_login, now))
+    }
+
+    fn lockout_duration(&self, key: &LockoutKey, failures: u32) -> Option<Duration> {
+        let threshold = match key {
+            LockoutKey::Account(_) => self.threshold.saturating_mul(USER_THRESHOLD_FACTOR),
+            _ => self.threshold,
+        };
+        let exponent = failures.checked_sub(threshold)?;
+
+        Some(
+            2_u32
+                .checked_pow(exponent)
+                .and_then(|factor| self.duration.checked_mul(factor))
+                .unwrap_or(MAX_DURATION)
+                .min(MAX_DURATION),
+        )
+    }
+
+    /// Writes the active lockouts to the state file, on a blocking thread within the runtime
+    fn persist(&self) {
+        if self.state_file.is_none() {
+            return;
+        }
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let lockout = self.clone();
+                drop(runtime.spawn_blocking(move || lockout.write_state()));
+            }
+            Err(_) => self.write_state(),
+        }
+    }
+
+    fn write_state(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+
+        // The state is taken while writing, so the last write has the latest state
+        let _persisting = self.persisting.lock().unwrap();
+        let now = Local::now();
+        let state = LockoutState {
+            lockouts: self
+                .attempts
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|entry| entry.remaining(now).is_some())
+                .cloned()
+                .collect(),
+        };
+
+        let result = toml::to_string(&state)
+            .map_err(std::io::Error::other)
+            .and_then(|content| write_atomic(path, content.as_bytes()));
+
+        match result {
+            Ok(()) => info!(path = %path.display(), "[lockout] persisted lockouts"),
+            Err(err) => warn!(%err, path = %path.display(), "[lockout] could not persist lockouts"),
+        }
+    }
+}
+
+fn is_expired(time: DateTime<Local>, now: DateTime<Local>) -> bool {
+    (now - time)
+        .to_std()
+        .is_ok_and(|elapsed| elapsed >= FORGET_AFTER)
+}
+
+fn keys(user: &str, ip: Option<IpAddr>) -> impl Iterator<Item = LockoutKey> {
+    [
+        Some(LockoutKey::Login {
+            user: user.to_string(),
+            ip,
+        }),
+        ip.map(LockoutKey::Ip),
+        Some(LockoutKey::Account(user.to_string())),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Returns the address of the client of a request from `peer`, if lockouts are enabled
+pub fn lockout_client_ip(headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+    LOCKOUT
+        .get()
+        .and_then(|lockout| lockout.client_ip(headers, peer))
+}
+
+/// Returns how long `user` is still locked out from `ip`, if lockouts are enabled
+pub fn check_lockout(user: &str, ip: Option<IpAddr>) -> Option<Duration> {
+    LOCKOUT.get().and_then(|lockout| lockout.check(user, ip))
+}
+
+/// Counts a failed login, if lockouts are enabled
+pub fn record_login_failure(user: &str, ip: Option<IpAddr>) {
+    if let Some(lockout) = LOCKOUT.get() {
+        let _ = lockout.record_failure(user, ip);
+    }
+}
+
+/// Forgets the failed logins of `user` from `ip`, if lockouts are enabled
+pub fn record_login_success(user: &str, ip: Option<IpAddr>) {
+    if let Some(lockout) = LOCKOUT.get() {
+        lockout.record_success(user, ip);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        net::{IpAddr, Ipv4Addr},
+        path::PathBuf,
+        time::Duration,
+    };
+
+    use axum::{
+        http::{header, StatusCode},
+        response::IntoResponse,
+    };
+
+    use crate::{error::ApiErrorKind, lockout::Lockout};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn test_lockout_passes() {
+        let lockout = Lockout::new(3, Duration::from_secs(60));
+
+        assert_eq!(lockout.record_failure("rustic", Some(IP)), None);
+        assert_eq!(lockout.record_failure("rustic", Some(IP)), None);
+        assert_eq!(lockout.check("rustic", Some(IP)), None);
+
+        // the third failure locks out the user from the address and the address
+        assert_eq!(
+            lockout.record_failure("rustic", Some(IP)),
+            Some(Duration::from_secs(60))
+        );
+        assert!(lockout.check("rustic", Some(IP)).is_some());
+        assert!(lockout.check("restic", Some(IP)).is_some());
+
+        // but not the user from other addresses
+        assert_eq!(lockout.check("rustic", Some(OTHER_IP)), None);
+        assert_eq!(lockout.check("rustic", None), None);
+
+        // every further failure doubles the lockout
+        assert_eq!(
+            lockout.record_failure("rustic", Some(IP)),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            lockout.record_failure("rustic", Some(IP)),
+            Some(Duration::from_secs(240))
+        );
+
+        // without a known address, only the user is locked out
+        for _ in 0..3 {
+            let _ = lockout.record_failure("restic", None);
+        }
+        assert!(lockout.check("restic", None).is_some());
+        assert_eq!(lockout.check("restic", Some(OTHER_IP)), None);
+
+        // a successful login only resets the user
+        lockout.record_success("restic", None);
+        assert_eq!(lockout.check("restic", None), None);
+        lockout.record_success("rustic", Some(IP));
+        assert!(lockout.check("rustic", Some(IP)).is_some());
+    }
+
+    #[test]
+    fn test_lockout_from_many_addresses_passes() {
+        let lockout = Lockout::new(1, Duration::from_secs(60));
+        let address = |n| IpAddr::V4(Ipv4Addr::new(198, 51, 100, n));
+        lockout.record_success("rustic", Some(IP));
+
+        // every address fails once, which locks out only that address
+        for n in 1..10 {
+            let _ = lockout.record_failure("rustic", Some(address(n)));
+        }
+        assert_eq!(lockout.check("rustic", Some(OTHER_IP)), None);
+
+        // until the failures of the user together reach ten times the threshold
+        assert!(lockout
+            .record_failure("rustic", Some(address(10)))
+            .is_some());
+        assert!(lockout.check("rustic", Some(OTHER_IP)).is_some());
+        assert!(lockout.check("rustic", None).is_some());
+        assert_eq!(lockout.check("restic", Some(OTHER_IP)), None);
+
+        // the user can still log in from where it has before
+        assert_eq!(lockout.check("rustic", Some(IP)), None);
+    }
+
+    #[test]
+    fn test_lockout_response_passes() {
+        let resp = ApiErrorKind::TooManyAuthenticationFailures(42).into_response();
+
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "42");
+    }
+
+    #[test]
+    fn test_lockout_state_file_passes() {
+        let base = PathBuf::from("tests/generated/test_lockout");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&base).unwrap();
+        let path = base.join("lockout.toml");
+
+        let lockout = Lockout::new(1, Duration::from_secs(60))
+            .with_state_file(path.clone())
+            .unwrap();
+        assert!(lockout.record_failure("rustic", Some(IP)).is_some());
+        assert!(path.exists());
+
+        // active lockouts are restored after a restart
+        let restarted = Lockout::new(1, Duration::from_secs(60))
+            .with_state_file(path.clone())
+            .unwrap();
+        assert!(restarted.check("rustic", Some(IP)).is_some());
+        assert!(restarted.check("restic", Some(IP)).is_some());
+
+        // per-user lockouts of earlier versions are dropped
+        fs::write(
+            &path,
+            r#"
+[[lockouts]]
+failures = 5
+last_failure = "2099-01-01T00:00:00+00:00"
+locked_until = "2099-01-01T00:00:00+00:00"
+
+[lockouts.key]
+user = "rustic"
+"#,
+        )
+        .unwrap();
+        let upgraded = Lockout::new(1, Duration::from_secs(60))
+            .with_state_file(path.clone())
+            .unwrap();
+        assert_eq!(upgraded.check("rustic", Some(IP)), None);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
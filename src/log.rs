@@ -1,71 +1,265 @@
+use std::{
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+    time::Instant,
+};
+
 use axum::{
-    body::{Body, Bytes},
+    body::{Body, Bytes, HttpBody},
     extract::Request,
+    http::{header, HeaderMap, HeaderValue},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::Response,
 };
-use http_body_util::BodyExt;
+use http_body::{Frame, SizeHint};
+use pin_project::{pin_project, pinned_drop};
+
+use crate::error::AppResult;
+
+/// Headers whose values are never logged
+const REDACTED_HEADERS: [header::HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+];
 
-use crate::error::ApiErrorKind;
+// Static storage of the number of body bytes to log, bodies are not logged if not set
+pub static BODY_PREFIX_LIMIT: OnceLock<usize> = OnceLock::new();
+
+pub(crate) fn init_body_prefix_limit(limit: usize) -> AppResult<()> {
+    let _ = BODY_PREFIX_LIMIT.get_or_init(|| limit);
+    Ok(())
+}
 
 // Add the `#[debug_middleware]` attribute to the function to make debugging easier.
 // use axum_macros::debug_middleware;
 //
 // #[debug_middleware]
 /// Router middleware function to print additional information on the request and response.
-pub async fn print_request_response(
-    req: Request,
-    next: Next,
-) -> Result<impl IntoResponse, ApiErrorKind> {
-    let (parts, body) = req.into_parts();
-    let uuid = uuid::Uuid::new_v4();
+///
+/// Bodies are streamed through, only their size and, with `--log-body-prefix`,
+/// their first bytes are logged. So memory usage doesn't depend on the size of a body.
+pub async fn print_request_response(request: Request, next: Next) -> Response {
+    let id = uuid::Uuid::new_v4();
+    let start = Instant::now();
+    let limit = BODY_PREFIX_LIMIT.get().copied().unwrap_or_default();
 
     tracing::debug!(
-        id = %uuid,
-        method = %parts.method,
-        uri = %parts.uri,
+        %id,
+        method = %request.method(),
+        uri = %request.uri(),
+        headers = ?redacted(request.headers()),
         "[REQUEST]",
     );
 
-    tracing::debug!(id = %uuid, headers = ?parts.headers, "[HEADERS]");
-
-    let bytes = buffer_and_print(&uuid, body).await?;
-
-    let req = Request::from_parts(parts, Body::from(bytes));
+    let request = request.map(|body| {
+        Body::new(InspectBody::new(body, limit, move |inspected| {
+            tracing::debug!(
+                %id,
+                bytes = inspected.bytes,
+                prefix = %inspected.prefix_lossy(),
+                "[REQUEST BODY]",
+            );
+        }))
+    });
 
-    let res = next.run(req).await;
-    let (parts, body) = res.into_parts();
+    let response = next.run(request).await;
 
     tracing::debug!(
-        id = %uuid,
-        headers = ?parts.headers,
-        status = %parts.status,
+        %id,
+        status = %response.status(),
+        headers = ?redacted(response.headers()),
+        elapsed_ms = start.elapsed().as_millis(),
         "[RESPONSE]",
     );
 
-    let bytes = buffer_and_print(&uuid, body).await?;
-    let res = Response::from_parts(parts, Body::from(bytes));
+    response.map(|body| {
+        Body::new(InspectBody::new(body, limit, move |inspected| {
+            tracing::debug!(
+                %id,
+                bytes = inspected.bytes,
+                prefix = %inspected.prefix_lossy(),
+                elapsed_ms = start.elapsed().as_millis(),
+                "[RESPONSE BODY]",
+            );
+        }))
+    })
+}
+
+/// Returns a copy of the headers with the values of credentials replaced
+fn redacted(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+
+    for name in REDACTED_HEADERS {
+        if let Some(value) = headers.get_mut(&name) {
+            *value = HeaderValue::from_static("[redacted]");
+        }
+    }
+
+    headers
+}
 
-    Ok(res)
+/// What has been seen of a body
+#[derive(Debug, Default)]
+pub(crate) struct InspectedBody {
+    /// Number of bytes that passed through
+    pub bytes: u64,
+    /// The first bytes, at most as many as the limit of the `InspectBody`
+    pub prefix: Vec<u8>,
 }
 
-async fn buffer_and_print<B>(uuid: &uuid::Uuid, body: B) -> Result<Bytes, ApiErrorKind>
+impl InspectedBody {
+    pub fn prefix_lossy(&self) -> String {
+        let prefix = String::from_utf8_lossy(&self.prefix);
+        if self.bytes > self.prefix.len() as u64 {
+            format!("{prefix}...")
+        } else {
+            prefix.into_owned()
+        }
+    }
+}
+
+/// Body passing all frames through, while counting the bytes and keeping a capped prefix
+///
+/// `on_end` is called when the body is dropped, i.e. once it has been sent or
+/// read completely, or when the other side has gone away.
+#[pin_project(PinnedDrop)]
+pub(crate) struct InspectBody<F>
+where
+    F: FnOnce(&InspectedBody),
+{
+    #[pin]
+    inner: Body,
+    limit: usize,
+    inspected: InspectedBody,
+    on_end: Option<F>,
+}
+
+impl<F> InspectBody<F>
 where
-    B: axum::body::HttpBody<Data = Bytes> + Send,
-    B::Error: std::fmt::Display,
+    F: FnOnce(&InspectedBody),
 {
-    let bytes = match body.collect().await {
-        Ok(collected) => collected.to_bytes(),
-        Err(err) => {
-            return Err(ApiErrorKind::BadRequest(format!(
-                "failed to read body: {err}"
-            )));
+    pub fn new(inner: Body, limit: usize, on_end: F) -> Self {
+        Self {
+            inner,
+            limit,
+            inspected: InspectedBody::default(),
+            on_end: Some(on_end),
         }
+    }
+}
+
+impl<F> HttpBody for InspectBody<F>
+where
+    F: FnOnce(&InspectedBody),
+{
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = this.inner.poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                this.inspected.bytes += data.len() as u64;
+
+                let missing = this.limit.saturating_sub(this.inspected.prefix.len());
+                this.inspected
+                    .prefix
+                    .extend_from_slice(&data[..missing.min(data.len())]);
+            }
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[pinned_drop]
+impl<F> PinnedDrop for InspectBody<F>
+where
+    F: FnOnce(&InspectedBody),
+{
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if let Some(on_end) = this.on_end.take() {
+            on_end(this.inspected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::{Body, Bytes},
+        http::{Method, StatusCode},
+        middleware,
+        routing::post,
+        Router,
     };
+    use futures::stream;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use crate::{
+        log::{print_request_response, InspectBody},
+        testing::request_uri_for_test,
+    };
+
+    #[tokio::test]
+    async fn test_inspect_body_keeps_capped_prefix_passes() {
+        let chunks = (0..64).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![b'a'; 1024 * 1024])));
+        let body = Body::from_stream(stream::iter(chunks));
 
-    if let Ok(body) = std::str::from_utf8(&bytes) {
-        tracing::debug!(id = %uuid, body = %body, "[BODY]");
+        let seen = Arc::new(Mutex::new(None));
+        let inspect = {
+            let seen = seen.clone();
+            InspectBody::new(body, 16, move |inspected| {
+                *seen.lock().unwrap() = Some((inspected.bytes, inspected.prefix.clone()));
+            })
+        };
+
+        // the body is streamed through unchanged
+        let mut body = Body::new(inspect);
+        let mut bytes = 0;
+        while let Some(frame) = body.frame().await {
+            bytes += frame.unwrap().into_data().unwrap().len();
+        }
+        assert_eq!(bytes, 64 * 1024 * 1024);
+        drop(body);
+
+        let (counted, prefix) = seen.lock().unwrap().take().unwrap();
+        assert_eq!(counted, 64 * 1024 * 1024);
+        assert_eq!(prefix, vec![b'a'; 16]);
     }
 
-    Ok(bytes)
+    #[tokio::test]
+    async fn test_print_request_response_streams_bodies_passes() {
+        let app = Router::new()
+            .route("/echo", post(|body: Bytes| async move { body }))
+            .layer(middleware::from_fn(print_request_response));
+
+        let mut request = request_uri_for_test("/echo", Method::POST);
+        *request.body_mut() = Body::from("Hello World");
+
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Hello World");
+    }
 }
@@ -0,0 +1,374 @@
+//! Prometheus metrics
+//!
+//! Counts requests by method, type and status code, measures their latency and
+//! tracks the bytes transferred per repository and user, the usage of every
+//! repository and failed authentications. The metrics are rendered in the
+//! Prometheus text format by `/metrics`.
+
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{
+    error::{AppResult, ErrorKind},
+    quota::scan_usage,
+    storage::Storage,
+    typed_path::TpeKind,
+};
+
+/// User allowed to access `/metrics`, unless authentication is disabled for it
+pub const METRICS_USER: &str = "metrics";
+
+/// Namespace of all metrics
+const NAMESPACE: &str = "rustic_server";
+
+/// Repositories are walked for their usage at most once per this period
+pub const USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Static storage of our metrics
+pub static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub(crate) fn init_metrics(metrics: Metrics) -> AppResult<()> {
+    let _ = METRICS.get_or_init(|| metrics);
+    Ok(())
+}
+
+/// Reason of a failed authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// The `Authorization` header is missing or malformed
+    MissingHeader,
+    /// The user is unknown or the password is wrong
+    InvalidCredentials,
+    /// The bearer token is invalid or expired
+    InvalidToken,
+}
+
+impl AuthFailure {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::MissingHeader => "missing_header",
+            Self::InvalidCredentials => "invalid_credentials",
+            Self::InvalidToken => "invalid_token",
+        }
+    }
+}
+
+/// `Metrics` holds all metrics of the server
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    bytes_uploaded: IntCounterVec,
+    bytes_downloaded: IntCounterVec,
+    repository_usage: IntGaugeVec,
+    auth_failures: IntCounterVec,
+    /// When the repository usage has last been updated
+    usage_updated: Mutex<Option<Instant>>,
+    no_auth: bool,
+    no_repo_label: bool,
+}
+
+impl Metrics {
+    /// Creates and registers all metrics
+    ///
+    /// With `no_repo_label`, repository names are left out of all metrics.
+    pub fn new(no_auth: bool, no_repo_label: bool) -> AppResult<Self> {
+        Self::register(no_auth, no_repo_label).map_err(|err| {
+            ErrorKind::Config
+                .context(format!("Could not create metrics: {err}"))
+                .into()
+        })
+    }
+
+    fn register(no_auth: bool, no_repo_label: bool) -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests"),
+            &["method", "type", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of HTTP requests in seconds",
+            ),
+            &["method", "type", "status"],
+        )?;
+        let bytes_uploaded = IntCounterVec::new(
+            Opts::new("bytes_uploaded_total", "Bytes uploaded to repositories"),
+            &["repo", "user"],
+        )?;
+        let bytes_downloaded = IntCounterVec::new(
+            Opts::new(
+                "bytes_downloaded_total",
+                "Bytes downloaded from repositories",
+            ),
+            &["repo", "user"],
+        )?;
+        let repository_usage = IntGaugeVec::new(
+            Opts::new("repository_usage_bytes", "Size of repositories in bytes"),
+            &["repo"],
+        )?;
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Number of failed authentications"),
+            &["reason"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(bytes_uploaded.clone()))?;
+        registry.register(Box::new(bytes_downloaded.clone()))?;
+        registry.register(Box::new(repository_usage.clone()))?;
+        registry.register(Box::new(auth_failures.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            bytes_uploaded,
+            bytes_downloaded,
+            repository_usage,
+            auth_failures,
+            usage_updated: Mutex::default(),
+            no_auth,
+            no_repo_label,
+        })
+    }
+
+    /// `true` if `/metrics` can be accessed without authentication
+    pub const fn no_auth(&self) -> bool {
+        self.no_auth
+    }
+
+    fn repo_label(&self, repo: &Path) -> String {
+        if self.no_repo_label {
+            String::new()
+        } else {
+            repo.display().to_string()
+        }
+    }
+
+    pub fn record_request(&self, method: &str, tpe: &str, status: u16, duration: Duration) {
+        let status = status.to_string();
+        let labels = [method, tpe, status.as_str()];
+
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn add_uploaded(&self, repo: &Path, user: &str, bytes: u64) {
+        self.bytes_uploaded
+            .with_label_values(&[&self.repo_label(repo), user])
+            .inc_by(bytes);
+    }
+
+    pub fn add_downloaded(&self, repo: &Path, user: &str, bytes: u64) {
+        self.bytes_downloaded
+            .with_label_values(&[&self.repo_label(repo), user])
+            .inc_by(bytes);
+    }
+
+    pub fn add_auth_failure(&self, failure: AuthFailure) {
+        self.auth_failures
+            .with_label_values(&[failure.as_str()])
+            .inc();
+    }
+
+    /// Returns `true` if the repository usage is older than [`USAGE_REFRESH_INTERVAL`]
+    ///
+    /// The caller is expected to update it, so it is reported as fresh to everyone else.
+    pub fn claim_usage_update(&self) -> bool {
+        let mut updated = self.usage_updated.lock().unwrap();
+
+        match *updated {
+            Some(updated) if updated.elapsed() < USAGE_REFRESH_INTERVAL => false,
+            _ => {
+                *updated = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Walks all repositories and updates their usage
+    ///
+    /// This blocks; call it from the blocking thread pool.
+    pub fn update_usage(&self, storage: &dyn Storage) {
+        let usage: Vec<_> = storage
+            .repositories()
+            .into_iter()
+            .map(|repo| {
+                (
+                    self.repo_label(&repo),
+                    scan_usage(&storage.path().join(&repo), false),
+                )
+            })
+            .collect();
+
+        self.repository_usage.reset();
+
+        if self.no_repo_label {
+            let total: u64 = usage.iter().map(|(_, usage)| usage).sum();
+            self.repository_usage
+                .with_label_values(&[""])
+                .set(i64::try_from(total).unwrap_or(i64::MAX));
+        } else {
+            for (repo, usage) in usage {
+                self.repository_usage
+                    .with_label_values(&[&repo])
+                    .set(i64::try_from(usage).unwrap_or(i64::MAX));
+            }
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self) -> AppResult<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|err| ErrorKind::Io.context(format!("Could not encode metrics: {err}")))?;
+
+        String::from_utf8(buffer).map_err(|err| {
+            ErrorKind::Io
+                .context(format!("Could not encode metrics: {err}"))
+                .into()
+        })
+    }
+}
+
+/// Records an upload, if metrics are enabled
+pub fn record_upload(repo: &Path, user: &str, bytes: u64) {
+    if let Some(metrics) = METRICS.get() {
+        metrics.add_uploaded(repo, user, bytes);
+    }
+}
+
+/// Records a download, if metrics are enabled
+pub fn record_download(repo: &Path, user: &str, bytes: u64) {
+    if let Some(metrics) = METRICS.get() {
+        metrics.add_downloaded(repo, user, bytes);
+    }
+}
+
+/// Records a failed authentication, if metrics are enabled
+pub fn record_auth_failure(failure: AuthFailure) {
+    if let Some(metrics) = METRICS.get() {
+        metrics.add_auth_failure(failure);
+    }
+}
+
+/// Middleware counting requests and measuring their latency
+///
+/// Nested repository paths must already be rewritten, so the type of a request
+/// is always the second path segment.
+pub async fn record_metrics(request: Request, next: Next) -> Response {
+    let Some(metrics) = METRICS.get() else {
+        return next.run(request).await;
+    };
+
+    let start = Instant::now();
+    let method = request.method().clone();
+    let tpe = request_type(request.uri().path());
+
+    let response = next.run(request).await;
+
+    metrics.record_request(
+        method.as_str(),
+        tpe,
+        response.status().as_u16(),
+        start.elapsed(),
+    );
+
+    response
+}
+
+/// Returns the label for the type of a request path, e.g. `data` for `/repo/data/<name>`
+fn request_type(path: &str) -> &'static str {
+    let mut segments = path.trim_start_matches('/').split('/');
+
+    match (segments.next(), segments.next()) {
+        (Some("health"), _) => "health",
+        (Some("metrics"), None) => "metrics",
+        (_, Some(tpe)) if !tpe.is_empty() => TpeKind::from_str(tpe)
+            .map(TpeKind::into_str)
+            .unwrap_or("unknown"),
+        _ => "repository",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use rstest::rstest;
+
+    use crate::{
+        metrics::{request_type, AuthFailure, Metrics},
+        storage::{LocalStorage, Storage},
+    };
+
+    #[rstest]
+    #[case("/health/ready", "health")]
+    #[case("/metrics", "metrics")]
+    #[case("/repo/", "repository")]
+    #[case("/org%2Frepo/", "repository")]
+    #[case("/repo/config", "config")]
+    #[case("/repo/data/", "data")]
+    #[case("/org%2Frepo/keys/abcd", "keys")]
+    #[case("/repo/unknown/abcd", "unknown")]
+    fn test_request_type_passes(#[case] path: &str, #[case] expected: &str) {
+        assert_eq!(request_type(path), expected);
+    }
+
+    #[test]
+    fn test_metrics_render_passes() {
+        let storage = LocalStorage::init(&PathBuf::from("tests/generated/test_storage")).unwrap();
+        let repo = PathBuf::from("test_repo");
+
+        let metrics = Metrics::new(false, false).unwrap();
+        metrics.record_request("GET", "data", 200, std::time::Duration::from_millis(5));
+        metrics.add_uploaded(&repo, "rustic", 100);
+        metrics.add_downloaded(&repo, "rustic", 50);
+        metrics.add_auth_failure(AuthFailure::InvalidCredentials);
+
+        // the usage is updated once per refresh interval
+        assert!(metrics.claim_usage_update());
+        assert!(!metrics.claim_usage_update());
+        metrics.update_usage(&storage);
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered.contains(
+            r#"rustic_server_http_requests_total{method="GET",status="200",type="data"} 1"#
+        ));
+        assert!(rendered
+            .contains(r#"rustic_server_bytes_uploaded_total{repo="test_repo",user="rustic"} 100"#));
+        assert!(rendered.contains(
+            r#"rustic_server_bytes_downloaded_total{repo="test_repo",user="rustic"} 50"#
+        ));
+        assert!(rendered
+            .contains(r#"rustic_server_auth_failures_total{reason="invalid_credentials"} 1"#));
+        assert!(rendered.contains("rustic_server_http_request_duration_seconds_bucket"));
+        assert!(rendered.contains(r#"rustic_server_repository_usage_bytes{repo="test_repo"}"#));
+
+        // repository names are left out
+        let metrics = Metrics::new(false, true).unwrap();
+        metrics.add_uploaded(&repo, "rustic", 100);
+        metrics.update_usage(&storage);
+
+        let rendered = metrics.render().unwrap();
+        assert!(!rendered.contains("test_repo"));
+        assert!(
+            rendered.contains(r#"rustic_server_bytes_uploaded_total{repo="",user="rustic"} 100"#)
+        );
+    }
+}
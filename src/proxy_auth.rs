@@ -0,0 +1,311 @@
+//! Proxy authentication
+//!
+//! Behind an authenticating reverse proxy, the proxy tells the server who the
+//! user is in a header like `Remote-User` or `X-Forwarded-User`. The header is
+//! only trusted if the request comes from one of the `--trusted-proxies`, so
+//! clients which reach the server directly can't claim to be any user.
+
+use std::{fmt, net::IpAddr, str::FromStr, sync::OnceLock};
+
+use axum::http::{HeaderMap, HeaderName};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    config::HtpasswdSettings,
+    error::{ApiErrorKind, ApiResult, AppResult, ErrorKind},
+};
+
+// Static storage of our proxy authentication
+pub static PROXY_AUTH: OnceLock<ProxyAuth> = OnceLock::new();
+
+pub(crate) fn init_proxy_auth(proxy_auth: ProxyAuth) -> AppResult<()> {
+    let _ = PROXY_AUTH.get_or_init(|| proxy_auth);
+    Ok(())
+}
+
+/// An IP network, e.g. `10.0.0.0/8`, or a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    /// `true` if `ip` is part of this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets see IPv4 clients as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s
+            .split_once('/')
+            .map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|err| format!("invalid address `{addr}`: {err}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length `{prefix}`"))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        network.to_string()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// `ProxyAuth` takes the user from a header set by a trusted proxy
+#[derive(Clone, Debug)]
+pub struct ProxyAuth {
+    header: HeaderName,
+    trusted_proxies: Vec<IpNetwork>,
+}
+
+impl ProxyAuth {
+    pub fn new(header: &str, trusted_proxies: Vec<IpNetwork>) -> AppResult<Self> {
+        let header = HeaderName::from_str(header).map_err(|err| {
+            ErrorKind::Config.context(format!(
+                "Invalid proxy authentication header `{header}`: {err}"
+            ))
+        })?;
+
+        if trusted_proxies.is_empty() {
+            return Err(ErrorKind::Config
+                .context("Proxy authentication requires at least one trusted proxy.")
+                .into());
+        }
+
+        Ok(Self {
+            header,
+            trusted_proxies,
+        })
+    }
+
+    /// Creates the proxy authentication from the settings, `None` if it is disabled
+    pub fn from_settings(settings: &HtpasswdSettings) -> AppResult<Option<Self>> {
+        settings
+            .proxy_auth_username
+            .as_deref()
+            .map(|header| Self::new(header, settings.trusted_proxies.clone()))
+            .transpose()
+    }
+
+    pub const fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpNetwork] {
+        &self.trusted_proxies
+    }
+
+    /// Returns the user set by a trusted proxy, if `peer` is one and has set the header
+    ///
+    /// The request is refused if the header is set more than once, as the client
+    /// might have set it in addition to the proxy.
+    pub fn user(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> ApiResult<Option<String>> {
+        let mut values = headers.get_all(&self.header).iter();
+        let Some(value) = values.next() else {
+            return Ok(None);
+        };
+
+        if !peer.is_some_and(|peer| self.trusted_proxies.iter().any(|net| net.contains(peer))) {
+            warn!(?peer, header = %self.header, "[AUTH] ignoring proxy authentication header from untrusted address");
+            return Ok(None);
+        }
+
+        if values.next().is_some() {
+            warn!(?peer, header = %self.header, "[AUTH] refusing proxy authentication header set more than once");
+            return Err(ApiErrorKind::AuthenticationHeaderError);
+        }
+
+        Ok(value
+            .to_str()
+            .ok()
+            .map(str::trim)
+            .filter(|user| !user.is_empty())
+            .map(ToString::to_string))
+    }
+}
+
+/// Header a proxy appends the address of its client to
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Returns the address of the client of a request from `peer`
+///
+/// If `peer` is one of the `trusted_proxies`, the client is the rightmost address
+/// of `X-Forwarded-For` that isn't a trusted proxy itself, as everything left of
+/// it can be forged by the client. `None` if a trusted proxy didn't forward a
+/// valid address.
+pub fn client_ip(
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+    trusted_proxies: &[IpNetwork],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let peer = peer?;
+    if !is_trusted(peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .map(|value| value.to_str().ok())
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    for addr in forwarded.into_iter().rev() {
+        let ip: IpAddr = addr.parse().ok()?;
+        if !is_trusted(ip) {
+            return Some(ip);
+        }
+    }
+
+    None
+}
+
+/// Returns the user set by a trusted proxy, if proxy authentication is enabled
+pub fn proxy_auth_user(headers: &HeaderMap, peer: Option<IpAddr>) -> ApiResult<Option<String>> {
+    PROXY_AUTH
+        .get()
+        .map_or(Ok(None), |proxy_auth| proxy_auth.user(headers, peer))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use crate::proxy_auth::{client_ip, IpNetwork, ProxyAuth};
+
+    #[test]
+    fn test_ip_network_passes() {
+        let network: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert!(!network.contains(IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1))));
+        assert!(network.contains(IpAddr::V6(Ipv4Addr::new(10, 1, 0, 1).to_ipv6_mapped())));
+
+        let network: IpNetwork = "::1".parse().unwrap();
+        assert_eq!(network.to_string(), "::1/128");
+        assert!(network.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!(!network.contains(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+
+        let network: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(network.contains(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_proxy_auth_passes() {
+        let proxy_auth = ProxyAuth::new("Remote-User", vec!["127.0.0.1".parse().unwrap()]).unwrap();
+        let proxy = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let client = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(proxy_auth.user(&headers, proxy).unwrap(), None);
+
+        let _ = headers.insert("remote-user", HeaderValue::from_static("rustic"));
+        assert_eq!(
+            proxy_auth.user(&headers, proxy).unwrap().as_deref(),
+            Some("rustic")
+        );
+        assert_eq!(proxy_auth.user(&headers, client).unwrap(), None);
+        assert_eq!(proxy_auth.user(&headers, None).unwrap(), None);
+
+        // a second value might come from the client, so the request is refused
+        let _ = headers.append("remote-user", HeaderValue::from_static("admin"));
+        assert!(proxy_auth.user(&headers, proxy).is_err());
+        assert_eq!(proxy_auth.user(&headers, client).unwrap(), None);
+
+        assert!(ProxyAuth::new("Remote-User", Vec::new()).is_err());
+        assert!(ProxyAuth::new("Remote User", vec!["127.0.0.1".parse().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn test_client_ip_passes() {
+        let trusted: Vec<IpNetwork> = vec!["10.0.0.0/8".parse().unwrap()];
+        let proxy = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let forged = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+        // clients reaching the server directly can't forward an address
+        let mut headers = HeaderMap::new();
+        let _ = headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+        assert_eq!(client_ip(&headers, Some(client), &trusted), Some(client));
+        assert_eq!(client_ip(&headers, None, &trusted), None);
+
+        // the rightmost address not added by a trusted proxy is the client
+        let _ = headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.1, 192.0.2.1, 10.0.0.2"),
+        );
+        assert_eq!(client_ip(&headers, proxy, &trusted), Some(client));
+        assert_ne!(client_ip(&headers, proxy, &trusted), Some(forged));
+
+        let _ = headers.append("x-forwarded-for", HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(client_ip(&headers, proxy, &trusted), Some(client));
+
+        // a trusted proxy which doesn't forward the address hides the client
+        assert_eq!(client_ip(&HeaderMap::new(), proxy, &trusted), None);
+        let _ = headers.insert("x-forwarded-for", HeaderValue::from_static("unknown"));
+        assert_eq!(client_ip(&headers, proxy, &trusted), None);
+    }
+}
@@ -0,0 +1,627 @@
+//! Repository quotas
+//!
+//! Enforces the maximum size of a repository (`--max-size`) and the per-repository
+//! and per-user quotas from the `[quota]` section of the ACL file. The usage of a
+//! repository is computed once, when it is first needed, and then kept up to
+//! date incrementally on every upload and deletion. Uploads reserve their size
+//! before they start, so concurrent uploads can't overrun a quota together.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, RwLock},
+};
+
+use axum::http::{HeaderMap, HeaderValue};
+use serde_derive::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    acl::is_user_namespace,
+    error::{ApiErrorKind, ApiResult, AppResult},
+    storage::Storage,
+};
+
+/// Header containing the quota of a repository in bytes
+pub const QUOTA_LIMIT_HEADER: &str = "x-quota-limit";
+
+/// Header containing the remaining space of a repository in bytes
+pub const QUOTA_REMAINING_HEADER: &str = "x-quota-remaining";
+
+// Static storage of our quota
+pub static QUOTA: OnceLock<Quota> = OnceLock::new();
+
+pub(crate) fn init_quota(quota: Quota) -> AppResult<()> {
+    let _ = QUOTA.get_or_init(|| quota);
+    Ok(())
+}
+
+/// Per-repository and per-user quotas in bytes
+///
+/// A repository quota replaces the global maximum size for that repository.
+/// A user quota caps the total size of the repositories in the namespace of
+/// the user, i.e. the repository named like the user and all repositories
+/// below it. Writes of the user to other repositories don't count against it.
+/// If both apply, the one with less space left wins.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, default)]
+pub struct QuotaLimits {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub repos: BTreeMap<String, u64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, u64>,
+}
+
+impl QuotaLimits {
+    pub fn is_empty(&self) -> bool {
+        self.repos.is_empty() && self.users.is_empty()
+    }
+}
+
+/// Tracked usage in bytes, including the space reserved for running uploads
+#[derive(Debug, Default)]
+struct Usage {
+    /// Usage per repository, without the repositories nested in it
+    repos: BTreeMap<PathBuf, u64>,
+    /// Usage per user, of all repositories in the namespace of the user
+    users: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    /// Adds `bytes` to a repository and to the users whose namespace it is in
+    fn add(&mut self, repo: &Path, bytes: u64) {
+        for usage in self.affected(repo) {
+            *usage = usage.saturating_add(bytes);
+        }
+    }
+
+    /// Removes `bytes` from a repository and from the users whose namespace it is in
+    fn remove(&mut self, repo: &Path, bytes: u64) {
+        for usage in self.affected(repo) {
+            *usage = usage.saturating_sub(bytes);
+        }
+    }
+
+    fn affected<'a>(&'a mut self, repo: &'a Path) -> impl Iterator<Item = &'a mut u64> {
+        let repo_str = repo.to_str().unwrap_or_default();
+
+        self.repos.get_mut(repo).into_iter().chain(
+            self.users
+                .iter_mut()
+                .filter(move |(user, _)| is_user_namespace(user, repo_str))
+                .map(|(_, usage)| usage),
+        )
+    }
+}
+
+/// `Quota` holds the quotas and the tracked usage of all repositories and users
+#[derive(Debug, Default)]
+pub struct Quota {
+    max_size: Option<u64>,
+    limits: RwLock<QuotaLimits>,
+    usage: Mutex<Usage>,
+}
+
+impl Quota {
+    /// Create a new quota, a maximum size of `0` disables the global quota
+    pub fn new(max_size: Option<u64>) -> Self {
+        Self {
+            max_size: max_size.filter(|max_size| *max_size > 0),
+            limits: RwLock::default(),
+            usage: Mutex::default(),
+        }
+    }
+
+    /// Sets the per-repository and per-user quotas
+    pub fn with_limits(self, limits: QuotaLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            ..self
+        }
+    }
+
+    /// Replaces the per-repository and per-user quotas, e.g. after the ACL file has been changed
+    pub fn set_limits(&self, limits: QuotaLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub const fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub fn limits(&self) -> QuotaLimits {
+        self.limits.read().unwrap().clone()
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.max_size.is_none() && self.limits.read().unwrap().is_empty()
+    }
+
+    /// Returns the quota of a repository, or `None` if the repository is unlimited
+    pub fn limit(&self, repo: &Path) -> Option<u64> {
+        repo.to_str()
+            .and_then(|repo| self.limits.read().unwrap().repos.get(repo).copied())
+            .or(self.max_size)
+    }
+
+    /// Returns the quota of `user` if `repo` is in the namespace of the user
+    pub fn user_limit(&self, user: &str, repo: &Path) -> Option<u64> {
+        repo.to_str()
+            .filter(|repo| is_user_namespace(user, repo))
+            .and_then(|_| self.limits.read().unwrap().users.get(user).copied())
+    }
+
+    /// Returns the usage of a repository in bytes
+    ///
+    /// The repository is only walked when its usage is not tracked yet.
+    /// Repositories nested in it are not part of its usage.
+    pub fn usage(&self, storage: &dyn Storage, repo: &Path) -> u64 {
+        let tracked = self.usage.lock().unwrap().repos.get(repo).copied();
+        if let Some(usage) = tracked {
+            return usage;
+        }
+
+        let scanned = scan_usage(&storage.path().join(repo), false);
+
+        tracing::debug!(?repo, %scanned, "[quota] computed repository usage");
+
+        *self
+            .usage
+            .lock()
+            .unwrap()
+            .repos
+            .entry(repo.to_path_buf())
+            .or_insert(scanned)
+    }
+
+    /// Returns the usage of all repositories in the namespace of `user` in bytes
+    ///
+    /// The namespace is only walked when the usage of the user is not tracked yet.
+    pub fn user_usage(&self, storage: &dyn Storage, user: &str) -> u64 {
+        let tracked = self.usage.lock().unwrap().users.get(user).copied();
+        if let Some(usage) = tracked {
+            return usage;
+        }
+
+        let scanned = scan_usage(&storage.path().join(user), true);
+
+        tracing::debug!(%user, %scanned, "[quota] computed user usage");
+
+        *self
+            .usage
+            .lock()
+            .unwrap()
+            .users
+            .entry(user.to_string())
+            .or_insert(scanned)
+    }
+
+    /// Returns the quota that leaves the least space when `user` writes to `repo`
+    /// together with that space, or `None` if the repository is unlimited
+    fn binding_limit(&self, storage: &dyn Storage, user: &str, repo: &Path) -> Option<(u64, u64)> {
+        let repo_limit = self
+            .limit(repo)
+            .map(|limit| (limit, limit.saturating_sub(self.usage(storage, repo))));
+        let user_limit = self
+            .user_limit(user, repo)
+            .map(|limit| (limit, limit.saturating_sub(self.user_usage(storage, user))));
+
+        repo_limit
+            .into_iter()
+            .chain(user_limit)
+            .min_by_key(|(_, remaining)| *remaining)
+    }
+
+    /// Returns the remaining space of a repository in bytes for `user`,
+    /// or `None` if the repository is unlimited
+    pub fn remaining(&self, storage: &dyn Storage, user: &str, repo: &Path) -> Option<u64> {
+        self.binding_limit(storage, user, repo)
+            .map(|(_, remaining)| remaining)
+    }
+
+    /// Computes the usage of a repository and of the namespace of `user`
+    /// where a quota applies and the usage is not tracked yet
+    ///
+    /// This walks the file system, so async callers should run it on a
+    /// blocking thread before they reserve space.
+    pub fn track(&self, storage: &dyn Storage, user: &str, repo: &Path) {
+        if self.limit(repo).is_some() {
+            let _ = self.usage(storage, repo);
+        }
+        if self.user_limit(user, repo).is_some() {
+            let _ = self.user_usage(storage, user);
+        }
+    }
+
+    /// Reserves space for `user` to add `size` bytes to a repository
+    ///
+    /// The reservation counts as usage until it is dropped, so concurrent uploads
+    /// can't exceed the quota together. Uploads of unknown size reserve all of
+    /// the remaining space. Commit the reservation with the number of bytes
+    /// actually written once the upload is complete.
+    pub fn reserve(
+        &self,
+        storage: &dyn Storage,
+        user: &str,
+        repo: &Path,
+        size: Option<u64>,
+    ) -> ApiResult<Reservation<'_>> {
+        let repo_limit = self.limit(repo);
+        let user_limit = self.user_limit(user, repo);
+
+        // walk the repository and the namespace, if needed, outside of the critical section
+        self.track(storage, user, repo);
+
+        let mut tracked = self.usage.lock().unwrap();
+        let repo_remaining = repo_limit.map(|limit| {
+            limit.saturating_sub(tracked.repos.get(repo).copied().unwrap_or_default())
+        });
+        let user_remaining = user_limit.map(|limit| {
+            limit.saturating_sub(tracked.users.get(user).copied().unwrap_or_default())
+        });
+        let remaining = repo_remaining.into_iter().chain(user_remaining).min();
+
+        let bytes = match (size, remaining) {
+            (Some(size), Some(remaining)) if size > remaining => {
+                return Err(ApiErrorKind::QuotaExceeded(format!(
+                    "{size} bytes requested, but only {remaining} bytes left in repository `{}`",
+                    repo.display()
+                )))
+            }
+            (Some(size), _) => size,
+            (None, remaining) => remaining.unwrap_or_default(),
+        };
+
+        tracked.add(repo, bytes);
+
+        Ok(Reservation {
+            quota: self,
+            repo: repo.to_path_buf(),
+            bytes,
+            limited: remaining.is_some(),
+        })
+    }
+
+    /// Adds `bytes` to the tracked usage of a repository and of the users whose namespace it is in
+    pub fn add_usage(&self, repo: &Path, bytes: u64) {
+        self.usage.lock().unwrap().add(repo, bytes);
+    }
+
+    /// Removes `bytes` from the tracked usage of a repository and of the users whose namespace it is in
+    pub fn remove_usage(&self, repo: &Path, bytes: u64) {
+        self.usage.lock().unwrap().remove(repo, bytes);
+    }
+
+    /// Stops tracking a repository and the repositories nested in it, e.g. after
+    /// it has been removed, together with the users whose namespace overlaps it
+    pub fn forget(&self, repo: &Path) {
+        let mut tracked = self.usage.lock().unwrap();
+        tracked
+            .repos
+            .retain(|tracked, _| !tracked.starts_with(repo));
+        tracked
+            .users
+            .retain(|user, _| !Path::new(user).starts_with(repo) && !repo.starts_with(user));
+    }
+
+    /// Returns the quota headers for a repository
+    ///
+    /// The headers are empty if the repository is unlimited.
+    pub fn headers(&self, storage: &dyn Storage, user: &str, repo: &Path) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        if let Some((limit, remaining)) = self.binding_limit(storage, user, repo) {
+            let _ = headers.insert(QUOTA_LIMIT_HEADER, HeaderValue::from(limit));
+            let _ = headers.insert(QUOTA_REMAINING_HEADER, HeaderValue::from(remaining));
+        }
+
+        headers
+    }
+
+    /// Returns the usage and quota of every tracked repository
+    pub fn report(&self) -> Vec<QuotaReportEntry> {
+        let usage = self.usage.lock().unwrap().repos.clone();
+
+        usage
+            .into_iter()
+            .map(|(repo, usage)| QuotaReportEntry {
+                limit: self.limit(&repo),
+                repo: repo.display().to_string(),
+                usage,
+            })
+            .collect()
+    }
+
+    /// Returns the usage and quota of every user with a quota
+    pub fn user_report(&self, storage: &dyn Storage) -> Vec<UserQuotaReportEntry> {
+        let limits = self.limits();
+
+        limits
+            .users
+            .into_iter()
+            .map(|(user, limit)| UserQuotaReportEntry {
+                usage: self.user_usage(storage, &user),
+                user,
+                limit,
+            })
+            .collect()
+    }
+}
+
+/// Returns the size of all files below `root`, except the ones of hidden
+/// directories and, unless `with_nested` is set, of nested repositories
+pub(crate) fn scan_usage(root: &Path, with_nested: bool) -> u64 {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            let is_nested_repo =
+                !with_nested && entry.file_type().is_dir() && entry.path().join("config").is_file();
+            let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+            entry.depth() == 0 || !(is_nested_repo || is_hidden)
+        })
+        .filter_map(walkdir::Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Space reserved for an upload by [`Quota::reserve`]
+///
+/// The reserved space is released when the reservation is dropped.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    quota: &'a Quota,
+    repo: PathBuf,
+    bytes: u64,
+    limited: bool,
+}
+
+impl Reservation<'_> {
+    /// Returns the number of bytes the upload may write, or `None` if the repository is unlimited
+    pub const fn limit(&self) -> Option<u64> {
+        if self.limited {
+            Some(self.bytes)
+        } else {
+            None
+        }
+    }
+
+    /// Adds the `written` bytes of the completed upload to the usage of the repository
+    pub fn commit(self, written: u64) {
+        self.quota.add_usage(&self.repo, written);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.quota.remove_usage(&self.repo, self.bytes);
+    }
+}
+
+/// Usage and quota of a repository
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct QuotaReportEntry {
+    pub repo: String,
+    pub usage: u64,
+    pub limit: Option<u64>,
+}
+
+/// Usage and quota of a user, summed over the repositories in their namespace
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct UserQuotaReportEntry {
+    pub user: String,
+    pub usage: u64,
+    pub limit: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        quota::{Quota, QuotaLimits, QUOTA_LIMIT_HEADER, QUOTA_REMAINING_HEADER},
+        storage::{LocalStorage, Storage},
+    };
+
+    #[test]
+    fn test_quota_tracks_usage_passes() {
+        let base = PathBuf::from("tests/generated/test_quota");
+        let repo = PathBuf::from("quota_repo");
+        let keys = base.join(&repo).join("keys");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&keys).unwrap();
+        fs::write(keys.join("key"), [0u8; 100]).unwrap();
+
+        let storage = LocalStorage::init(&base).unwrap();
+
+        let quota = Quota::new(Some(150));
+        assert_eq!(quota.usage(&storage, &repo), 100);
+        assert_eq!(quota.remaining(&storage, "rustic", &repo), Some(50));
+        assert!(quota.reserve(&storage, "rustic", &repo, Some(50)).is_ok());
+        assert!(quota.reserve(&storage, "rustic", &repo, Some(51)).is_err());
+
+        quota.add_usage(&repo, 40);
+        assert_eq!(quota.remaining(&storage, "rustic", &repo), Some(10));
+
+        quota.remove_usage(&repo, 90);
+        assert_eq!(quota.usage(&storage, &repo), 50);
+
+        let headers = quota.headers(&storage, "rustic", &repo);
+        assert_eq!(headers.get(QUOTA_LIMIT_HEADER).unwrap(), "150");
+        assert_eq!(headers.get(QUOTA_REMAINING_HEADER).unwrap(), "100");
+
+        // after forgetting, the usage is scanned again
+        quota.forget(&repo);
+        assert_eq!(quota.usage(&storage, &repo), 100);
+
+        // a quota of zero is disabled
+        let quota = Quota::new(Some(0));
+        assert!(quota.is_disabled());
+        assert!(quota
+            .reserve(&storage, "rustic", &repo, Some(u64::MAX))
+            .is_ok_and(|reservation| reservation.limit().is_none()));
+        assert!(quota.headers(&storage, "rustic", &repo).is_empty());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_quota_reservations_passes() {
+        let base = PathBuf::from("tests/generated/test_quota_reservations");
+        let repo = PathBuf::from("repo");
+        let keys = base.join(&repo).join("keys");
+        let nested_keys = base.join(&repo).join("nested").join("keys");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&keys).unwrap();
+        fs::create_dir_all(&nested_keys).unwrap();
+        fs::write(base.join(&repo).join("config"), [0u8; 10]).unwrap();
+        fs::write(keys.join("key"), [0u8; 90]).unwrap();
+        fs::write(base.join(&repo).join("nested").join("config"), [0u8; 10]).unwrap();
+        fs::write(nested_keys.join("key"), [0u8; 1000]).unwrap();
+
+        let storage = LocalStorage::init(&base).unwrap();
+        let quota = Quota::new(Some(150));
+
+        // nested repositories are not charged to their parent
+        assert_eq!(quota.usage(&storage, &repo), 100);
+        assert_eq!(quota.usage(&storage, &repo.join("nested")), 1010);
+
+        // concurrent uploads can't exceed the quota together
+        let first = quota.reserve(&storage, "rustic", &repo, Some(30)).unwrap();
+        assert_eq!(first.limit(), Some(30));
+        assert!(quota.reserve(&storage, "rustic", &repo, Some(30)).is_err());
+
+        // failed uploads release their reservation
+        drop(first);
+        let second = quota.reserve(&storage, "rustic", &repo, Some(30)).unwrap();
+
+        // completed uploads count what they have written
+        second.commit(20);
+        assert_eq!(quota.usage(&storage, &repo), 120);
+
+        // uploads of unknown size reserve the remaining space
+        let unknown = quota.reserve(&storage, "rustic", &repo, None).unwrap();
+        assert_eq!(unknown.limit(), Some(30));
+        assert!(quota.reserve(&storage, "rustic", &repo, Some(1)).is_err());
+        unknown.commit(0);
+        assert_eq!(quota.remaining(&storage, "rustic", &repo), Some(30));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_quota_limits_passes() {
+        let mut limits = QuotaLimits::default();
+        let _ = limits.repos.insert("gold".to_string(), 1_000);
+        let _ = limits.users.insert("restic".to_string(), 500);
+
+        let quota = Quota::new(Some(200)).with_limits(limits);
+        assert!(!quota.is_disabled());
+
+        // the global maximum size applies to all other repositories
+        assert_eq!(quota.limit(&PathBuf::from("bronze")), Some(200));
+        // a repository quota replaces the global maximum size
+        assert_eq!(quota.limit(&PathBuf::from("gold")), Some(1_000));
+        // a user quota applies to the namespace of the user
+        assert_eq!(
+            quota.user_limit("restic", &PathBuf::from("restic")),
+            Some(500)
+        );
+        assert_eq!(
+            quota.user_limit("restic", &PathBuf::from("restic/host1")),
+            Some(500)
+        );
+        assert_eq!(quota.user_limit("restic", &PathBuf::from("gold")), None);
+        assert_eq!(quota.user_limit("rustic", &PathBuf::from("rustic")), None);
+    }
+
+    #[test]
+    fn test_user_quota_sums_repositories_passes() {
+        let base = PathBuf::from("tests/generated/test_user_quota");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        for repo in ["restic/host1", "restic/host2", "shared"] {
+            fs::create_dir_all(base.join(repo).join("keys")).unwrap();
+            fs::write(base.join(repo).join("config"), [0u8; 10]).unwrap();
+            fs::write(base.join(repo).join("keys").join("key"), [0u8; 90]).unwrap();
+        }
+
+        let storage = LocalStorage::init(&base).unwrap();
+        let mut limits = QuotaLimits::default();
+        let _ = limits.users.insert("restic".to_string(), 250);
+        let quota = Quota::new(None).with_limits(limits);
+
+        // all repositories in the namespace of the user count
+        assert_eq!(quota.user_usage(&storage, "restic"), 200);
+        let host1 = PathBuf::from("restic/host1");
+        let host3 = PathBuf::from("restic/host3");
+        assert_eq!(quota.remaining(&storage, "restic", &host1), Some(50));
+        assert_eq!(quota.remaining(&storage, "restic", &host3), Some(50));
+
+        // a new repository can't get around the quota of the user
+        assert!(quota.reserve(&storage, "restic", &host3, Some(60)).is_err());
+        let reservation = quota.reserve(&storage, "restic", &host3, Some(40)).unwrap();
+        assert!(quota.reserve(&storage, "restic", &host1, Some(20)).is_err());
+        reservation.commit(40);
+        assert_eq!(quota.user_usage(&storage, "restic"), 240);
+
+        let headers = quota.headers(&storage, "restic", &host1);
+        assert_eq!(headers.get(QUOTA_LIMIT_HEADER).unwrap(), "250");
+        assert_eq!(headers.get(QUOTA_REMAINING_HEADER).unwrap(), "10");
+
+        // deletions free space of the user
+        quota.remove_usage(&host1, 100);
+        assert_eq!(quota.user_usage(&storage, "restic"), 140);
+
+        // repositories outside of the namespace are not limited by the user quota
+        assert_eq!(
+            quota.remaining(&storage, "restic", &PathBuf::from("shared")),
+            None
+        );
+
+        let report = quota.user_report(&storage);
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].usage, report[0].limit), (140, 250));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_user_quota_covers_only_namespace_passes() {
+        let base = PathBuf::from("tests/generated/test_user_quota_namespace");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        for repo in ["restic", "shared"] {
+            fs::create_dir_all(base.join(repo).join("keys")).unwrap();
+        }
+
+        let storage = LocalStorage::init(&base).unwrap();
+        let mut limits = QuotaLimits::default();
+        let _ = limits.users.insert("restic".to_string(), 100);
+        let quota = Quota::new(None).with_limits(limits);
+
+        // writes to a repository outside of the namespace are not charged to the user
+        let shared = PathBuf::from("shared");
+        assert_eq!(quota.user_limit("restic", &shared), None);
+        let reservation = quota
+            .reserve(&storage, "restic", &shared, Some(1000))
+            .unwrap();
+        reservation.commit(1000);
+        assert_eq!(quota.user_usage(&storage, "restic"), 0);
+        assert_eq!(
+            quota.remaining(&storage, "restic", &PathBuf::from("restic")),
+            Some(100)
+        );
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
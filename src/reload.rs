@@ -0,0 +1,363 @@
+//! Hot reload
+//!
+//! Reloads the htpasswd file, the token file, the keys of the JWT issuer, the ACL file and the
+//! TLS certificates of a running server on `SIGHUP` and, with `--reload-interval`, whenever one of them has
+//! been modified. Every file is validated before it replaces the current state,
+//! so a broken file is rejected and the previous state stays in place.
+
+use std::{
+    fs,
+    future::pending,
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{task::JoinHandle, time::Interval};
+use tracing::{info, warn};
+
+use crate::{
+    acl::{replace_acl, Acl},
+    auth::{replace_auth, Auth},
+    config::{AclSettings, HtpasswdSettings},
+    context::TlsOptions,
+    error::{AppResult, ErrorKind},
+    htpasswd::Htpasswd,
+    jwt::{replace_jwt, JwtAuth},
+    quota::QUOTA,
+    tls::server_config,
+    tokens::{replace_tokens, TokenStore},
+};
+
+/// `Reloader` knows where the state of the server has been loaded from
+#[derive(Clone, Debug)]
+pub struct Reloader {
+    auth: HtpasswdSettings,
+    acl: AclSettings,
+    data_dir: PathBuf,
+    tls: Option<(RustlsConfig, TlsOptions)>,
+    interval: Option<Duration>,
+}
+
+impl Reloader {
+    pub fn new(
+        auth: HtpasswdSettings,
+        acl: AclSettings,
+        data_dir: PathBuf,
+        interval: Option<Duration>,
+    ) -> Self {
+        Self {
+            auth,
+            acl,
+            data_dir,
+            tls: None,
+            interval,
+        }
+    }
+
+    /// Reloads the certificate and key of `config` too
+    pub fn with_tls(self, config: RustlsConfig, tls: TlsOptions) -> Self {
+        Self {
+            tls: Some((config, tls)),
+            ..self
+        }
+    }
+
+    pub const fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// `true` if clients are authenticated only by their certificate, so there are no credentials to reload
+    fn client_auth_only(&self) -> bool {
+        self.tls
+            .as_ref()
+            .is_some_and(|(_, tls)| tls.client_auth_only)
+    }
+
+    /// Reads and validates the htpasswd file, `None` if it isn't used
+    pub fn load_auth(&self) -> AppResult<Option<Auth>> {
+        // Users are verified against the LDAP directory instead
+        if self.auth.is_disabled() || self.client_auth_only() || self.auth.ldap_url.is_some() {
+            return Ok(None);
+        }
+
+        let path = self.auth.htpasswd_file_or_default(self.data_dir.clone())?;
+
+        Ok(Some(
+            Auth::from(Htpasswd::from_file_strict(&path)?).set_settings(&self.auth),
+        ))
+    }
+
+    /// Reads and validates the token file, `None` if it isn't used
+    pub fn load_tokens(&self) -> AppResult<Option<TokenStore>> {
+        if self.auth.is_disabled() || self.client_auth_only() {
+            return Ok(None);
+        }
+
+        let path = self.auth.token_file_or_default(self.data_dir.clone());
+
+        Ok(Some(TokenStore::from_file(&path)?))
+    }
+
+    /// Loads the keys of the issuer of bearer tokens, `None` if they aren't used
+    pub async fn load_jwt(&self) -> AppResult<Option<JwtAuth>> {
+        if self.auth.is_disabled() || self.client_auth_only() {
+            return Ok(None);
+        }
+
+        match JwtAuth::from_settings(&self.auth)? {
+            Some(jwt) => jwt.reload().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads and validates the ACL file, `None` if the ACL is disabled
+    pub fn load_acl(&self) -> AppResult<Option<Acl>> {
+        if self.acl.is_disabled() {
+            return Ok(None);
+        }
+
+        let path = self.acl.acl_file_or_default(self.data_dir.clone())?;
+
+        Ok(Some(Acl::from_config(&self.acl, Some(path))?))
+    }
+
+    fn reload_tls(&self) -> AppResult<bool> {
+        let Some((config, tls)) = &self.tls else {
+            return Ok(false);
+        };
+
+        config.reload_from_config(Arc::new(server_config(tls)?));
+
+        Ok(true)
+    }
+
+    /// Reloads all files, keeping the current state for every file that is invalid
+    pub async fn reload(&self) {
+        match self.load_auth() {
+            Ok(Some(auth)) => {
+                replace_auth(auth);
+                info!("[reload] reloaded htpasswd file");
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "[reload] rejected htpasswd file, keeping the previous one"),
+        }
+
+        match self.load_tokens() {
+            Ok(Some(tokens)) => {
+                replace_tokens(tokens);
+                info!("[reload] reloaded token file");
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "[reload] rejected token file, keeping the previous one"),
+        }
+
+        match self.load_jwt().await {
+            Ok(Some(jwt)) => {
+                replace_jwt(jwt);
+                info!("[reload] reloaded JWT issuer keys");
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "[reload] rejected JWT issuer keys, keeping the previous ones"),
+        }
+
+        match self.load_acl() {
+            Ok(Some(acl)) => {
+                if let Some(quota) = QUOTA.get() {
+                    quota.set_limits(acl.quota().clone());
+                }
+                replace_acl(acl);
+                info!("[reload] reloaded ACL file");
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "[reload] rejected ACL file, keeping the previous one"),
+        }
+
+        match self.reload_tls() {
+            Ok(true) => info!("[reload] reloaded TLS certificate"),
+            Ok(false) => {}
+            Err(err) => warn!(%err, "[reload] rejected TLS certificate, keeping the previous one"),
+        }
+    }
+
+    /// Files that are checked for modifications
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+
+        if !self.auth.is_disabled() && !self.client_auth_only() {
+            if self.auth.ldap_url.is_none() {
+                files.extend(
+                    self.auth
+                        .htpasswd_file_or_default(self.data_dir.clone())
+                        .ok(),
+                );
+            }
+            files.push(self.auth.token_file_or_default(self.data_dir.clone()));
+            files.extend(
+                self.auth
+                    .jwt_jwks
+                    .as_ref()
+                    .filter(|jwks| !jwks.starts_with("https://"))
+                    .map(PathBuf::from),
+            );
+        }
+        if !self.acl.is_disabled() {
+            files.extend(self.acl.acl_file_or_default(self.data_dir.clone()).ok());
+        }
+        if let Some((_, tls)) = &self.tls {
+            files.extend([tls.tls_cert.clone(), tls.tls_key.clone()]);
+            files.extend(tls.client_ca.clone());
+        }
+
+        files
+    }
+
+    /// Reloads all files on `SIGHUP` and whenever one of them has been modified
+    pub fn spawn(self) -> AppResult<JoinHandle<()>> {
+        let mut hangup = listen_for_hangup()
+            .map_err(|err| ErrorKind::Io.context(format!("Could not listen for SIGHUP: {err}")))?;
+
+        let files = self.watched_files();
+        let mut modified = modification_times(&files);
+        let mut interval = self.interval.map(tokio::time::interval);
+
+        Ok(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    () = next_hangup(&mut hangup) => info!("[reload] received SIGHUP"),
+                    () = next_tick(&mut interval) => {
+                        let current = modification_times(&files);
+                        if current == modified {
+                            continue;
+                        }
+                        modified = current;
+                        info!("[reload] files have been modified");
+                    }
+                }
+
+                self.reload().await;
+            }
+        }))
+    }
+}
+
+fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            fs::metadata(file)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => _ = interval.tick().await,
+        None => pending().await,
+    }
+}
+
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+
+#[cfg(unix)]
+fn listen_for_hangup() -> io::Result<Hangup> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())
+}
+
+#[cfg(unix)]
+async fn next_hangup(hangup: &mut Hangup) {
+    if hangup.recv().await.is_none() {
+        pending::<()>().await;
+    }
+}
+
+/// There is no `SIGHUP` on other platforms, files are only reloaded when they are modified
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(not(unix))]
+fn listen_for_hangup() -> io::Result<Hangup> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn next_hangup(_hangup: &mut Hangup) {
+    pending::<()>().await;
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        acl::{AccessType, AclChecker},
+        config::{AclSettings, HtpasswdSettings},
+        reload::{modification_times, Reloader},
+        testing::test_data_path,
+        tokens::DEFAULT_TOKEN_FILE,
+        typed_path::TpeKind,
+    };
+
+    #[test]
+    fn test_reloader_validates_files_passes() {
+        let base = PathBuf::from("tests/generated/test_reload");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&base).unwrap();
+
+        let htpasswd = base.join(".htpasswd");
+        let acl = base.join("acl.toml");
+        fs::copy(test_data_path().join(".htpasswd"), &htpasswd).unwrap();
+        fs::copy(test_data_path().join("acl.toml"), &acl).unwrap();
+
+        let reloader = Reloader::new(
+            HtpasswdSettings {
+                htpasswd_file: Some(htpasswd.clone()),
+                ..Default::default()
+            },
+            AclSettings {
+                disable_acl: false,
+                private_repos: true,
+                append_only: false,
+                acl_path: Some(acl.clone()),
+            },
+            base.clone(),
+            None,
+        );
+        let files = reloader.watched_files();
+        assert_eq!(
+            files,
+            vec![htpasswd.clone(), base.join(DEFAULT_TOKEN_FILE), acl.clone()]
+        );
+        let modified = modification_times(&files);
+
+        let auth = reloader.load_auth().unwrap().unwrap();
+        assert!(reloader.load_tokens().unwrap().is_some());
+        assert!(auth.verify("rustic", "rustic"));
+        let loaded = reloader.load_acl().unwrap().unwrap();
+        assert!(loaded.is_allowed("rustic", "test_repo", Some(TpeKind::Keys), AccessType::Read));
+
+        // a malformed line rejects the whole htpasswd file
+        fs::write(&htpasswd, "rustic:hash\nnot a credential\n").unwrap();
+        assert!(reloader.load_auth().is_err());
+
+        // invalid TOML rejects the ACL file
+        fs::write(&acl, "[test_repo\nrustic = \"Read\"\n").unwrap();
+        assert!(reloader.load_acl().is_err());
+
+        // removed files are rejected too
+        fs::remove_file(&htpasswd).unwrap();
+        assert!(reloader.load_auth().is_err());
+        assert_ne!(modification_times(&files), modified);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
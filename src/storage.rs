@@ -1,9 +1,19 @@
+pub mod janitor;
+pub mod scrubber;
+
 use std::{
+    fs, io,
     path::{Path, PathBuf},
     sync::{Arc, OnceLock},
 };
 
-use tokio::fs::{create_dir_all, remove_dir_all, remove_file, File};
+use tokio::{
+    fs::{
+        create_dir_all, read_to_string, remove_dir, remove_dir_all, remove_file, File, OpenOptions,
+    },
+    io::AsyncWriteExt,
+};
+use uuid::Uuid;
 use walkdir::WalkDir;
 
 use crate::{
@@ -12,6 +22,12 @@ use crate::{
     handlers::file_helpers::WriteOrDeleteFile,
 };
 
+/// Directory in the data directory holding uploads until they are complete
+pub const STAGING_DIR: &str = ".staging";
+
+/// Directory in the data directory recording the user who created each lock
+pub const LOCK_OWNER_DIR: &str = ".locks";
+
 //Static storage of our credentials
 pub static STORAGE: OnceLock<Arc<dyn Storage>> = OnceLock::new();
 
@@ -20,6 +36,45 @@ pub(crate) fn init_storage(storage: impl Storage) -> AppResult<()> {
     Ok(())
 }
 
+// Static storage of whether uploads are verified against their hash
+pub static VERIFY_UPLOAD: OnceLock<bool> = OnceLock::new();
+
+pub(crate) fn init_verify_upload(verify_upload: bool) -> AppResult<()> {
+    let _ = VERIFY_UPLOAD.get_or_init(|| verify_upload);
+    Ok(())
+}
+
+/// Replaces the file at `path` with `contents`, so readers see either the old or the new content
+///
+/// The contents are written to a hidden temporary file next to `path`, which is
+/// then renamed over it. The permissions of an existing file are kept.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        Uuid::new_v4()
+    ));
+
+    let result = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        io::Write::write_all(&mut file, contents)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    result
+}
+
 #[async_trait::async_trait]
 //#[enum_dispatch(StorageEnum)]
 pub trait Storage: Send + Sync + 'static {
@@ -53,6 +108,55 @@ pub trait Storage: Send + Sync + 'static {
     async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()>;
 
     async fn remove_repository(&self, path: &Path) -> ApiResult<()>;
+
+    /// Returns the user who created the lock `name` of a repository, if it is known
+    async fn lock_owner(&self, path: &Path, name: &str) -> Option<String> {
+        read_to_string(self.path().join(LOCK_OWNER_DIR).join(path).join(name))
+            .await
+            .ok()
+    }
+
+    /// Records `user` as the creator of the lock `name` of a repository
+    ///
+    /// Fails if the lock already has an owner, so a record is never overwritten.
+    async fn set_lock_owner(&self, path: &Path, name: &str, user: &str) -> ApiResult<()> {
+        let owner_dir = self.path().join(LOCK_OWNER_DIR).join(path);
+        create_dir_all(&owner_dir).await.map_err(|err| {
+            ApiErrorKind::CreatingDirectoryFailed(format!("Could not create directory: {err}"))
+        })?;
+
+        let error = |err: io::Error| {
+            ApiErrorKind::WritingToFileFailed(format!("Could not record lock owner: {err}"))
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(owner_dir.join(name))
+            .await
+            .map_err(error)?;
+        file.write_all(user.as_bytes()).await.map_err(error)?;
+        file.sync_all().await.map_err(error)
+    }
+
+    /// Forgets the creator of the lock `name` of a repository
+    async fn remove_lock_owner(&self, path: &Path, name: &str) {
+        // locks created before their owners were recorded have no record
+        let _ = remove_file(self.path().join(LOCK_OWNER_DIR).join(path).join(name)).await;
+    }
+
+    /// Returns the paths of all repositories, i.e. all directories with a `config` file
+    fn repositories(&self) -> Vec<PathBuf> {
+        self.read_dir(Path::new(""), None)
+            .filter(|entry| entry.file_name() == "config")
+            .filter_map(|entry| {
+                entry
+                    .path()
+                    .parent()
+                    .and_then(|repo| repo.strip_prefix(self.path()).ok())
+                    .map(PathBuf::from)
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -152,7 +256,7 @@ impl Storage for LocalStorage {
         name: Option<&str>,
     ) -> ApiResult<WriteOrDeleteFile> {
         let file_path = self.filename(path, tpe, name);
-        WriteOrDeleteFile::new(file_path).await
+        WriteOrDeleteFile::new(file_path, &self.path.join(STAGING_DIR)).await
     }
 
     async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
@@ -167,16 +271,92 @@ impl Storage for LocalStorage {
             "Deleting repository: {}",
             self.path.join(path).to_string_lossy()
         );
+        let locks: Vec<_> = self
+            .read_dir(path, Some("locks"))
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.file_name().to_owned())
+            .collect();
+
         remove_dir_all(self.path.join(path)).await.map_err(|err| {
             ApiErrorKind::RemovingRepositoryFailed(format!("Could not remove repository: {err}"))
-        })
+        })?;
+
+        // Only the records of the locks of this repository are removed, the
+        // directory also holds the records of repositories nested in it
+        let owner_dir = self.path.join(LOCK_OWNER_DIR).join(path);
+        for lock in locks {
+            let _ = remove_file(owner_dir.join(lock)).await;
+        }
+        let _ = remove_dir(owner_dir).await;
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::storage::{init_storage, LocalStorage, Storage, STORAGE};
-    use std::path::PathBuf;
+    use crate::storage::{
+        init_storage, write_atomic, LocalStorage, Storage, LOCK_OWNER_DIR, STORAGE,
+    };
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn test_write_atomic_passes() {
+        let base = PathBuf::from("tests/generated/test_write_atomic");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&base).unwrap();
+
+        let path = base.join("file");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(&base).unwrap().count(), 1);
+
+        // a missing directory fails without touching anything
+        assert!(write_atomic(&base.join("missing").join("file"), b"new").is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_repository_passes() {
+        let base = PathBuf::from("tests/generated/test_remove_repository");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        let storage = LocalStorage::init(&base).unwrap();
+        let (repo, nested) = (Path::new("org"), Path::new("org/team"));
+        for (path, lock) in [(repo, "lock1"), (nested, "lock2")] {
+            fs::create_dir_all(base.join(path).join("locks")).unwrap();
+            fs::write(base.join(path).join("locks").join(lock), "lock").unwrap();
+            storage.set_lock_owner(path, lock, "rustic").await.unwrap();
+        }
+
+        // an owner is never overwritten
+        assert!(storage
+            .set_lock_owner(repo, "lock1", "restic")
+            .await
+            .is_err());
+        assert_eq!(
+            storage.lock_owner(repo, "lock1").await,
+            Some("rustic".to_string())
+        );
+
+        // only the owners of the locks of the removed repository are forgotten
+        storage.remove_repository(repo).await.unwrap();
+        let owners = base.join(LOCK_OWNER_DIR);
+        assert!(!owners.join("org").join("lock1").exists());
+        assert!(owners.join("org/team").join("lock2").exists());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
 
     #[test]
     fn test_file_access_passes() {
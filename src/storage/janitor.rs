@@ -0,0 +1,305 @@
+//! Janitor
+//!
+//! Removes leftovers of interrupted uploads from the staging directory and
+//! repositories without any files, e.g. after `remove_repository` failed
+//! half-way. Only leftovers older than the grace period are touched, so uploads
+//! and repositories that are just being created are left alone.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::{config::StorageSettings, storage::STAGING_DIR};
+
+/// Default age of a leftover before it is removed
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default interval between two runs of the janitor
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Leftovers found by the janitor
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JanitorReport {
+    /// Temporary files of interrupted uploads
+    pub staging_files: Vec<PathBuf>,
+
+    /// Directory trees without any files
+    pub empty_dirs: Vec<PathBuf>,
+}
+
+impl JanitorReport {
+    pub fn is_empty(&self) -> bool {
+        self.staging_files.is_empty() && self.empty_dirs.is_empty()
+    }
+}
+
+/// `Janitor` cleans up the data directory
+#[derive(Debug, Clone)]
+pub struct Janitor {
+    data_dir: PathBuf,
+    grace_period: Duration,
+    interval: Duration,
+}
+
+impl Janitor {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            grace_period: DEFAULT_GRACE_PERIOD,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Creates a janitor for `data_dir` with the interval and grace period from the settings
+    pub fn from_settings(settings: &StorageSettings, data_dir: impl Into<PathBuf>) -> Self {
+        let mut janitor = Self::new(data_dir);
+
+        if let Some(interval) = settings.janitor_interval {
+            janitor = janitor.with_interval(Duration::from_secs(interval));
+        }
+
+        if let Some(grace_period) = settings.janitor_grace_period {
+            janitor = janitor.with_grace_period(Duration::from_secs(grace_period));
+        }
+
+        janitor
+    }
+
+    pub fn with_grace_period(self, grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            ..self
+        }
+    }
+
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    pub const fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub const fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Finds all leftovers without removing them
+    pub fn dry_run(&self) -> JanitorReport {
+        JanitorReport {
+            staging_files: self.stale_staging_files(),
+            empty_dirs: self.empty_dirs(),
+        }
+    }
+
+    /// Finds and removes all leftovers
+    ///
+    /// Returns what was removed.
+    pub fn run(&self) -> JanitorReport {
+        let mut report = self.dry_run();
+
+        report.staging_files.retain(|file| {
+            fs::remove_file(file)
+                .map_err(|err| warn!(?file, %err, "[janitor] could not remove staging file"))
+                .is_ok()
+        });
+
+        report
+            .empty_dirs
+            .retain(|dir| match remove_empty_tree(dir) {
+                Ok(()) => true,
+                // an upload has created the directory or a file in it in the meantime
+                Err(_) if !dir.exists() || has_entries(dir) => false,
+                Err(err) => {
+                    warn!(?dir, %err, "[janitor] could not remove empty directory");
+                    false
+                }
+            });
+
+        for file in &report.staging_files {
+            info!(?file, "[janitor] removed stale staging file");
+        }
+        for dir in &report.empty_dirs {
+            info!(?dir, "[janitor] removed empty directory");
+        }
+
+        report
+    }
+
+    /// Runs the janitor now and then every `interval`
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+
+            loop {
+                let _ = interval.tick().await;
+
+                let janitor = self.clone();
+                match tokio::task::spawn_blocking(move || janitor.run()).await {
+                    Ok(report) if report.is_empty() => debug!("[janitor] nothing to clean up"),
+                    Ok(_) => {}
+                    Err(err) => warn!(%err, "[janitor] run failed"),
+                }
+            }
+        })
+    }
+
+    fn is_stale(&self, path: &Path) -> bool {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age >= self.grace_period)
+    }
+
+    fn stale_staging_files(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(self.data_dir.join(STAGING_DIR)) else {
+            return Vec::new();
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && self.is_stale(path))
+            .collect()
+    }
+
+    fn empty_dirs(&self) -> Vec<PathBuf> {
+        let mut empty_dirs = Vec::new();
+        self.collect_empty_dirs(&self.data_dir, &mut empty_dirs);
+        empty_dirs
+    }
+
+    /// Collects the topmost directories below `dir` without any files
+    ///
+    /// Repositories, i.e. directories with a `config` file, and hidden
+    /// directories are never touched.
+    fn collect_empty_dirs(&self, dir: &Path, empty_dirs: &mut Vec<PathBuf>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+            let is_hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+
+            if !path.is_dir() || is_hidden || path.join("config").is_file() {
+                continue;
+            }
+
+            if !has_files(&path) {
+                if self.is_stale(&path) {
+                    empty_dirs.push(path);
+                }
+            } else {
+                self.collect_empty_dirs(&path, empty_dirs);
+            }
+        }
+    }
+}
+
+/// Removes the directory tree `dir`, which holds no files, from the bottom up
+///
+/// Unlike `remove_dir_all`, this fails instead of removing anything an upload
+/// creates in the tree in the meantime.
+fn remove_empty_tree(dir: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(dir).contents_first(true) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            match fs::remove_dir(entry.path()) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn has_entries(dir: &Path) -> bool {
+    fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+fn has_files(dir: &Path) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(walkdir::Result::ok)
+        .any(|entry| !entry.file_type().is_dir())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use crate::storage::{
+        janitor::{remove_empty_tree, Janitor},
+        STAGING_DIR,
+    };
+
+    #[test]
+    fn test_janitor_passes() {
+        let base = PathBuf::from("tests/generated/test_janitor");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+
+        // a live repository with empty type directories
+        fs::create_dir_all(base.join("live").join("data").join("00")).unwrap();
+        fs::create_dir_all(base.join("live").join("locks")).unwrap();
+        fs::write(base.join("live").join("config"), "config").unwrap();
+        // leftovers of a removed repository
+        fs::create_dir_all(base.join("removed").join("data").join("00")).unwrap();
+        // a nested leftover next to a live nested repository
+        fs::create_dir_all(base.join("org").join("old").join("keys")).unwrap();
+        fs::create_dir_all(base.join("org").join("team").join("keys")).unwrap();
+        fs::write(base.join("org").join("team").join("config"), "config").unwrap();
+        // an interrupted upload
+        fs::create_dir_all(base.join(STAGING_DIR)).unwrap();
+        fs::write(base.join(STAGING_DIR).join("upload.tmp"), "partial").unwrap();
+
+        // nothing is old enough yet
+        let janitor = Janitor::new(&base).with_grace_period(Duration::from_secs(3600));
+        assert!(janitor.dry_run().is_empty());
+
+        let janitor = janitor.with_grace_period(Duration::ZERO);
+        let report = janitor.dry_run();
+        assert_eq!(
+            report.staging_files,
+            vec![base.join(STAGING_DIR).join("upload.tmp")]
+        );
+        let mut empty_dirs = report.empty_dirs.clone();
+        empty_dirs.sort();
+        assert_eq!(
+            empty_dirs,
+            vec![base.join("org").join("old"), base.join("removed")]
+        );
+
+        // a dry run doesn't remove anything
+        assert!(base.join("removed").exists());
+
+        let removed = janitor.run();
+        assert_eq!(removed, report);
+        assert!(!base.join("removed").exists());
+        assert!(!base.join("org").join("old").exists());
+        assert!(!base.join(STAGING_DIR).join("upload.tmp").exists());
+        assert!(base.join("live").join("locks").exists());
+        assert!(base.join("org").join("team").join("keys").exists());
+        assert!(janitor.dry_run().is_empty());
+
+        // a directory an upload has just written to is kept
+        fs::create_dir_all(base.join("busy").join("keys")).unwrap();
+        fs::write(base.join("busy").join("keys").join("key"), "key").unwrap();
+        assert!(remove_empty_tree(&base.join("busy")).is_err());
+        assert!(base.join("busy").join("keys").join("key").exists());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
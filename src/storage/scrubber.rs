@@ -0,0 +1,402 @@
+//! Scrubber
+//!
+//! Re-hashes all content-addressed files of every repository in the background
+//! and reports files whose content no longer matches their name, e.g. due to
+//! bitrot. Reads are throttled to a configurable rate, so the scrubber doesn't
+//! compete with running backups.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::{AppResult, ErrorKind},
+    handlers::file_exchange::is_sha256_hash,
+    storage::{Storage, STORAGE},
+    typed_path::TpeKind,
+};
+
+/// Directory in the data directory holding the scrub reports
+pub const SCRUB_DIR: &str = ".scrub";
+
+/// File name of the scrub report of a repository
+pub const SCRUB_REPORT_FILE: &str = "report.toml";
+
+/// Default maximum read rate in bytes per second
+pub const DEFAULT_RATE: u64 = 10 * 1024 * 1024;
+
+/// Types of files named after the hash of their content
+///
+/// Locks are skipped, as they are short-lived.
+const SCRUBBED_TYPES: [TpeKind; 4] = [
+    TpeKind::Data,
+    TpeKind::Index,
+    TpeKind::Keys,
+    TpeKind::Snapshots,
+];
+
+/// Size of the chunks files are read in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Static storage of our scrubber
+pub static SCRUBBER: OnceLock<Scrubber> = OnceLock::new();
+
+pub(crate) fn init_scrubber(scrubber: Scrubber) -> AppResult<()> {
+    let _ = SCRUBBER.get_or_init(|| scrubber);
+    Ok(())
+}
+
+/// Result of scrubbing a repository
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub repo: String,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub files_checked: u64,
+    pub bytes_checked: u64,
+    /// Files whose content does not match their name, e.g. `data/<name>`
+    pub mismatches: Vec<String>,
+    /// Files that could not be read
+    pub errors: Vec<String>,
+}
+
+/// Summary of the last scrub of all repositories
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct ScrubStatus {
+    pub running: bool,
+    pub last_run: Option<DateTime<Local>>,
+    pub repositories: u64,
+    pub files_checked: u64,
+    pub mismatches: u64,
+    pub errors: u64,
+}
+
+/// `Scrubber` periodically verifies all repositories
+#[derive(Clone, Debug)]
+pub struct Scrubber {
+    interval: Duration,
+    rate: u64,
+    status: Arc<Mutex<ScrubStatus>>,
+}
+
+impl Scrubber {
+    /// Creates a scrubber running every `interval` and reading at most `rate` bytes per second
+    pub fn new(interval: Duration, rate: u64) -> Self {
+        Self {
+            interval,
+            rate: rate.max(1),
+            status: Arc::default(),
+        }
+    }
+
+    pub const fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub const fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Returns the summary of the last scrub
+    pub fn status(&self) -> ScrubStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Scrubs all repositories now and then every `interval`
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let storage = STORAGE.get().unwrap();
+            let mut interval = tokio::time::interval(self.interval);
+
+            loop {
+                let _ = interval.tick().await;
+                let _ = self.run(Arc::clone(storage)).await;
+            }
+        })
+    }
+
+    /// Scrubs all repositories and updates the status
+    ///
+    /// Walking and hashing the repositories blocks, so it runs on the blocking thread pool.
+    pub async fn run(&self, storage: Arc<dyn Storage>) -> Vec<ScrubReport> {
+        self.status.lock().unwrap().running = true;
+
+        let scrubber = self.clone();
+        let reports = tokio::task::spawn_blocking(move || {
+            storage
+                .repositories()
+                .into_iter()
+                .map(|repo| {
+                    let report = scrubber.scrub_repository(storage.as_ref(), &repo);
+
+                    if let Err(err) = write_report(storage.path(), &report) {
+                        warn!(%err, repo = %report.repo, "[scrubber] could not write report");
+                    }
+
+                    report
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!(%err, "[scrubber] scrub aborted");
+            Vec::new()
+        });
+
+        let mut status = self.status.lock().unwrap();
+        *status = ScrubStatus {
+            running: false,
+            last_run: Some(Local::now()),
+            repositories: reports.len() as u64,
+            files_checked: reports.iter().map(|report| report.files_checked).sum(),
+            mismatches: reports
+                .iter()
+                .map(|report| report.mismatches.len() as u64)
+                .sum(),
+            errors: reports
+                .iter()
+                .map(|report| report.errors.len() as u64)
+                .sum(),
+        };
+
+        info!(?status, "[scrubber] finished");
+
+        reports
+    }
+
+    /// Re-hashes all content-addressed files of a repository
+    ///
+    /// This blocks; call it from the blocking thread pool.
+    pub fn scrub_repository(&self, storage: &dyn Storage, repo: &Path) -> ScrubReport {
+        let mut report = ScrubReport {
+            repo: repo.display().to_string(),
+            started: Local::now(),
+            finished: Local::now(),
+            files_checked: 0,
+            bytes_checked: 0,
+            mismatches: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        debug!(repo = %report.repo, "[scrubber] scrubbing repository");
+
+        let mut throttle = Throttle::new(self.rate);
+
+        for tpe in SCRUBBED_TYPES {
+            let tpe = tpe.into_str();
+
+            let names: Vec<String> = storage
+                .read_dir(repo, Some(tpe))
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| is_sha256_hash(name))
+                .collect();
+
+            for name in names {
+                scrub_file(storage, repo, tpe, &name, &mut throttle, &mut report);
+            }
+        }
+
+        report.finished = Local::now();
+        report
+    }
+}
+
+/// Re-hashes a single file and records the result in `report`
+///
+/// Files removed while scrubbing, e.g. by a concurrent prune, are skipped.
+fn scrub_file(
+    storage: &dyn Storage,
+    repo: &Path,
+    tpe: &str,
+    name: &str,
+    throttle: &mut Throttle,
+    report: &mut ScrubReport,
+) {
+    let file = format!("{tpe}/{name}");
+
+    match hash_file(&storage.filename(repo, tpe, Some(name)), throttle) {
+        Ok((hash, bytes)) => {
+            report.files_checked += 1;
+            report.bytes_checked += bytes;
+
+            if hash != name {
+                warn!(
+                    repo = %report.repo, %file, %hash,
+                    "[scrubber] content does not match its hash"
+                );
+                report.mismatches.push(file);
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!(repo = %report.repo, %file, "[scrubber] file vanished, skipping");
+        }
+        Err(err) => {
+            warn!(
+                repo = %report.repo, %file, %err,
+                "[scrubber] could not read file"
+            );
+            report.errors.push(file);
+        }
+    }
+}
+
+/// Returns the path of the scrub report of a repository
+pub fn report_path(data_dir: &Path, repo: &str) -> PathBuf {
+    data_dir.join(SCRUB_DIR).join(repo).join(SCRUB_REPORT_FILE)
+}
+
+fn write_report(data_dir: &Path, report: &ScrubReport) -> AppResult<()> {
+    let path = report_path(data_dir, &report.repo);
+
+    let toml_string = toml::to_string(report)
+        .map_err(|err| ErrorKind::Io.context(format!("Could not serialize scrub report: {err}")))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml_string)?;
+
+    Ok(())
+}
+
+fn hash_file(path: &Path, throttle: &mut Throttle) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut bytes = 0;
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        bytes += read as u64;
+        throttle.consume(read as u64);
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), bytes))
+}
+
+/// Limits the read rate to `rate` bytes per second
+struct Throttle {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+
+        if expected > elapsed {
+            std::thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+    use crate::storage::{
+        scrubber::{report_path, scrub_file, ScrubReport, Scrubber, Throttle},
+        LocalStorage, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_scrubber_finds_mismatches_passes() {
+        // SHA-256 of "Hello World"
+        let hash = "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e";
+
+        let base = PathBuf::from("tests/generated/test_scrubber");
+        let repo = base.join("org").join("repo");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+
+        fs::create_dir_all(repo.join("keys")).unwrap();
+        fs::create_dir_all(repo.join("data").join("a5")).unwrap();
+        fs::write(repo.join("config"), "config").unwrap();
+        fs::write(repo.join("keys").join(hash), "Hello World").unwrap();
+        fs::write(repo.join("data").join("a5").join(hash), "Hello Wörld").unwrap();
+
+        let storage = LocalStorage::init(&base).unwrap();
+        let scrubber = Scrubber::new(Duration::from_secs(3600), u64::MAX);
+
+        let reports = scrubber.run(Arc::new(storage)).await;
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].repo, "org/repo");
+        assert_eq!(reports[0].files_checked, 2);
+        assert_eq!(reports[0].mismatches, vec![format!("data/{hash}")]);
+        assert!(reports[0].errors.is_empty());
+
+        let status = scrubber.status();
+        assert!(!status.running);
+        assert!(status.last_run.is_some());
+        assert_eq!(status.mismatches, 1);
+
+        // the report is persisted
+        let persisted = fs::read_to_string(report_path(&base, "org/repo")).unwrap();
+        let persisted: ScrubReport = toml::from_str(&persisted).unwrap();
+        assert_eq!(persisted, reports[0]);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_scrubber_skips_vanished_files_passes() {
+        let hash = "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e";
+
+        let base = PathBuf::from("tests/generated/test_scrubber_vanished");
+        let repo = PathBuf::from("repo");
+
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(base.join(&repo).join("keys")).unwrap();
+
+        let storage = LocalStorage::init(&base).unwrap();
+        let mut report =
+            Scrubber::new(Duration::from_secs(3600), u64::MAX).scrub_repository(&storage, &repo);
+
+        // e.g. removed by a prune after the directory was listed
+        scrub_file(
+            &storage,
+            &repo,
+            "keys",
+            hash,
+            &mut Throttle::new(u64::MAX),
+            &mut report,
+        );
+        assert_eq!(report.files_checked, 0);
+        assert!(report.mismatches.is_empty());
+        assert!(report.errors.is_empty());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
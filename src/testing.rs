@@ -17,7 +17,9 @@ use crate::{
     config::{
         default_data_dir, AclSettings, HtpasswdSettings, RusticServerConfig, StorageSettings,
     },
-    storage::{init_storage, LocalStorage, Storage},
+    quota::{init_quota, Quota},
+    storage::{init_storage, init_verify_upload, LocalStorage, Storage},
+    tokens::{init_tokens, TokenStore},
 };
 
 // ------------------------------------------------
@@ -80,6 +82,7 @@ pub(crate) fn server_config() -> RusticServerConfig {
 pub(crate) fn init_test_environment(server_config: RusticServerConfig) {
     init_tracing();
     init_static_htpasswd(server_config.auth);
+    init_tokens(TokenStore::default()).unwrap();
     init_static_auth(server_config.acl);
     init_static_storage(server_config.storage);
 }
@@ -97,6 +100,14 @@ fn init_static_auth(acl_settings: AclSettings) {
 }
 
 fn init_static_storage(storage_settings: StorageSettings) {
+    let quota = Quota::new(
+        storage_settings
+            .quota
+            .and_then(|quota| u64::try_from(quota).ok()),
+    );
+    init_quota(quota).unwrap();
+    init_verify_upload(!storage_settings.disable_verify_upload).unwrap();
+
     let local_storage = LocalStorage::init(
         storage_settings
             .data_dir
@@ -0,0 +1,263 @@
+//! TLS
+//!
+//! Builds the rustls configuration of the server. With `--tls-client-ca`,
+//! clients may present a certificate issued by one of the given CAs. The
+//! common name or a subject alternative name of a verified certificate is the
+//! user of all requests on that connection, so hosts authenticate without a
+//! password. With `--tls-client-auth-only`, a certificate is required and the
+//! htpasswd file isn't used at all.
+
+use std::{io, sync::Arc};
+
+use axum::{middleware::AddExtension, Extension};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures_util::future::BoxFuture;
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower::Layer;
+use tracing::{debug, warn};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::{
+    context::TlsOptions,
+    error::{AppResult, ErrorKind},
+};
+
+/// Which name of a client certificate is the user
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    strum::Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ClientCertField {
+    /// The common name of the subject
+    #[default]
+    Cn,
+    /// The first DNS name, e-mail address or URI of the subject alternative names
+    San,
+}
+
+/// The user of a verified client certificate, added to every request of a TLS connection
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub user: Option<String>,
+}
+
+/// Builds the rustls configuration from the certificate, the key and the client CA of `tls`
+pub fn server_config(tls: &TlsOptions) -> AppResult<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&tls.tls_cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| {
+            ErrorKind::Io.context(format!(
+                "Could not read TLS certificate `{}`: {err}",
+                tls.tls_cert.display()
+            ))
+        })?;
+
+    let key = PrivateKeyDer::from_pem_file(&tls.tls_key).map_err(|err| {
+        ErrorKind::Io.context(format!(
+            "Could not read TLS key `{}`: {err}",
+            tls.tls_key.display()
+        ))
+    })?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca)
+                .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+                .map_err(|err| {
+                    ErrorKind::Io.context(format!(
+                        "Could not read TLS client CA `{}`: {err}",
+                        client_ca.display()
+                    ))
+                })?
+            {
+                roots.add(cert).map_err(|err| {
+                    ErrorKind::Config.context(format!(
+                        "Invalid TLS client CA `{}`: {err}",
+                        client_ca.display()
+                    ))
+                })?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if tls.client_auth_only {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+
+            builder.with_client_cert_verifier(verifier.build().map_err(|err| {
+                ErrorKind::Config.context(format!(
+                    "Invalid TLS client CA `{}`: {err}",
+                    client_ca.display()
+                ))
+            })?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certs, key).map_err(|err| {
+        ErrorKind::Config.context(format!("Invalid TLS certificate or key: {err}"))
+    })?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Loads the rustls configuration of `tls`
+pub fn rustls_config(tls: &TlsOptions) -> AppResult<RustlsConfig> {
+    Ok(RustlsConfig::from_config(Arc::new(server_config(tls)?)))
+}
+
+/// Returns the user named by `field` of a client certificate
+pub fn user_from_certificate(cert: &CertificateDer<'_>, field: ClientCertField) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+
+    let user = match field {
+        ClientCertField::Cn => cert
+            .subject()
+            .iter_common_name()
+            .find_map(|name| name.as_str().ok())?,
+        ClientCertField::San => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(*name),
+                _ => None,
+            })?,
+    };
+
+    Some(user.to_string())
+}
+
+/// Accepts TLS connections and adds the user of the client certificate to their requests
+#[derive(Clone, Debug)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+    field: ClientCertField,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig, field: ClientCertField) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+            field,
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = AddExtension<S, ClientCertificate>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        let field = self.field;
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+
+            // rustls only hands out certificates it has verified against the client CA
+            let user = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(<[_]>::first)
+                .map(|cert| {
+                    let user = user_from_certificate(cert, field);
+                    if user.is_none() {
+                        warn!(%field, "[TLS] client certificate has no name to use as user");
+                    }
+                    user
+                })
+                .unwrap_or_default();
+            debug!(?user, "[TLS] accepted connection");
+
+            Ok((stream, Extension(ClientCertificate { user }).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+    use crate::{
+        context::TlsOptions,
+        tls::{server_config, user_from_certificate, ClientCertField},
+    };
+
+    fn certs() -> PathBuf {
+        PathBuf::from("tests/fixtures/test_data/certs")
+    }
+
+    #[test]
+    fn test_user_from_certificate_passes() {
+        let cert = CertificateDer::from_pem_file(certs().join("client.crt")).unwrap();
+
+        assert_eq!(
+            user_from_certificate(&cert, ClientCertField::Cn).as_deref(),
+            Some("rustic")
+        );
+        assert_eq!(
+            user_from_certificate(&cert, ClientCertField::San).as_deref(),
+            Some("backup-host.example.com")
+        );
+    }
+
+    #[test]
+    fn test_server_config_passes() {
+        let tls = TlsOptions {
+            tls_key: certs().join("test.key"),
+            tls_cert: certs().join("test.crt"),
+            ..Default::default()
+        };
+        assert!(server_config(&tls).is_ok());
+
+        let tls = TlsOptions {
+            client_ca: Some(certs().join("client_ca.crt")),
+            client_auth_only: true,
+            ..tls
+        };
+        assert!(server_config(&tls).is_ok());
+
+        // the client CA must contain a certificate
+        let tls = TlsOptions {
+            client_ca: Some(certs().join("client.key")),
+            ..tls
+        };
+        assert!(server_config(&tls).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! API tokens
+//!
+//! Tokens are an alternative to the htpasswd password of a user, meant for
+//! automated hosts. A token belongs to a user, is limited to some repositories
+//! and an `AccessType`, and expires. Clients pass a token as the password of
+//! basic authentication, so restic and rustic can use them unchanged.
+//!
+//! Only a SHA-256 hash of the secret part of a token is stored, in `.tokens.toml`
+//! in the data directory by default. The file is hidden, so it is never served.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Local};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{
+    acl::{lock_access, AccessType},
+    error::{AppResult, ErrorKind},
+    storage::write_atomic,
+    typed_path::TpeKind,
+};
+
+/// Prefix of all tokens, so they can be told apart from passwords
+pub const TOKEN_PREFIX: &str = "rustic_";
+
+/// Default file name of the token store
+pub const DEFAULT_TOKEN_FILE: &str = ".tokens.toml";
+
+/// Default file name of the token store of earlier versions
+const LEGACY_TOKEN_FILE: &str = "tokens.toml";
+
+const ID_LEN: usize = 8;
+const SECRET_LEN: usize = 32;
+
+// Static storage of our tokens
+pub static TOKENS: OnceLock<ArcSwap<TokenStore>> = OnceLock::new();
+
+pub(crate) fn init_tokens(tokens: TokenStore) -> AppResult<()> {
+    let _ = TOKENS.get_or_init(|| ArcSwap::from_pointee(tokens));
+    Ok(())
+}
+
+/// Replaces the tokens of the running server, e.g. after the token file has been changed
+pub fn replace_tokens(tokens: TokenStore) {
+    if let Some(current) = TOKENS.get() {
+        current.store(Arc::new(tokens));
+    }
+}
+
+/// What a token may access
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenScope {
+    /// Repositories, including the ones nested below them; all if empty
+    #[serde(default)]
+    pub repos: Vec<String>,
+    /// Highest access to these repositories
+    pub access: AccessType,
+}
+
+impl TokenScope {
+    /// `true` if the scope allows `access_type` to `tpe` in the repository `path`
+    ///
+    /// The ACL still has to allow the access for the user of the token.
+    pub fn allows(&self, path: &str, tpe: Option<TpeKind>, access_type: AccessType) -> bool {
+        // Locks have their own semantics, just like in the ACL
+        let access_type = lock_access(tpe, access_type);
+
+        let repo_allowed = self.repos.is_empty()
+            || self.repos.iter().any(|repo| {
+                let repo = repo.trim_matches('/');
+                path.strip_prefix(repo)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            });
+
+        repo_allowed && self.access.grants(access_type)
+    }
+}
+
+/// A token as it is stored
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StoredToken {
+    pub user: String,
+    /// SHA-256 of the secret, hex encoded
+    hash: String,
+    #[serde(flatten)]
+    pub scope: TokenScope,
+    pub created: DateTime<Local>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Local>>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+}
+
+impl StoredToken {
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Renames the default token file of earlier versions in `data_dir`, so it isn't served anymore
+pub fn migrate_legacy_token_file(data_dir: &Path) -> AppResult<()> {
+    let legacy = data_dir.join(LEGACY_TOKEN_FILE);
+    let path = data_dir.join(DEFAULT_TOKEN_FILE);
+
+    if !legacy.is_file() || path.exists() {
+        return Ok(());
+    }
+
+    fs::rename(&legacy, &path).map_err(|err| {
+        ErrorKind::Io.context(format!(
+            "Could not rename token file `{}` to `{}`: {err}",
+            legacy.display(),
+            path.display()
+        ))
+    })?;
+
+    info!(
+        "Renamed token file `{}` to `{}`.",
+        legacy.display(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// `TokenStore` holds all tokens by their id
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TokenStore {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    tokens: BTreeMap<String, StoredToken>,
+}
+
+impl TokenStore {
+    /// Reads the token store at `path`, it is empty if the file doesn't exist
+    pub fn from_file(path: &Path) -> AppResult<Self> {
+        let mut store = if path.exists() {
+            let content = fs::read_to_string(path).map_err(|err| {
+                ErrorKind::Io.context(format!(
+                    "Could not read token file `{}`: {err}",
+                    path.display()
+                ))
+            })?;
+
+            toml::from_str(&content).map_err(|err| {
+                ErrorKind::Config.context(format!(
+                    "Could not parse token file `{}`: {err}",
+                    path.display()
+                ))
+            })?
+        } else {
+            Self::default()
+        };
+
+        store.path = path.to_path_buf();
+        Ok(store)
+    }
+
+    pub fn to_file(&self) -> AppResult<()> {
+        let content = toml::to_string(self).map_err(|err| {
+            ErrorKind::Io.context(format!("Could not serialize token file: {err}"))
+        })?;
+
+        write_atomic(&self.path, content.as_bytes()).map_err(|err| {
+            ErrorKind::Io
+                .context(format!(
+                    "Could not write token file `{}`: {err}",
+                    self.path.display()
+                ))
+                .into()
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = (&String, &StoredToken)> {
+        self.tokens.iter()
+    }
+
+    /// Creates a token and returns it with its id
+    ///
+    /// This is the only time the token is known, only its hash is stored.
+    pub fn create(
+        &mut self,
+        user: &str,
+        scope: TokenScope,
+        expires: Option<DateTime<Local>>,
+        description: String,
+    ) -> (String, String) {
+        let id = loop {
+            let id = random_string(ID_LEN).to_lowercase();
+            if !self.tokens.contains_key(&id) {
+                break id;
+            }
+        };
+        let secret = random_string(SECRET_LEN);
+
+        let _ = self.tokens.insert(
+            id.clone(),
+            StoredToken {
+                user: user.to_string(),
+                hash: hash_secret(&secret),
+                scope,
+                created: Local::now(),
+                expires,
+                description,
+            },
+        );
+
+        (format!("{TOKEN_PREFIX}{id}_{secret}"), id)
+    }
+
+    /// Removes a token by its id
+    pub fn revoke(&mut self, id: &str) -> Option<StoredToken> {
+        self.tokens.remove(id)
+    }
+
+    /// Returns the scope of `token`, if it is a valid token of `user`
+    pub fn verify(&self, user: &str, token: &str) -> Option<&TokenScope> {
+        let (id, secret) = token.strip_prefix(TOKEN_PREFIX)?.split_once('_')?;
+        let stored = self.tokens.get(id)?;
+
+        (stored.user == user
+            && !stored.is_expired(Local::now())
+            && stored.hash == hash_secret(secret))
+        .then_some(&stored.scope)
+    }
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Returns the scope of `token`, if tokens are enabled and it is a valid token of `user`
+pub fn verify_token(user: &str, token: &str) -> Option<TokenScope> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+
+    TOKENS
+        .get()
+        .and_then(|tokens| tokens.load().verify(user, token).cloned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use chrono::{Duration, Local};
+
+    use crate::{
+        acl::AccessType,
+        tokens::{migrate_legacy_token_file, TokenScope, TokenStore, DEFAULT_TOKEN_FILE},
+        typed_path::TpeKind,
+    };
+
+    #[test]
+    fn test_token_scope_passes() {
+        let scope = TokenScope {
+            repos: vec!["org/repo".to_string()],
+            access: AccessType::Append,
+        };
+
+        assert!(scope.allows("org/repo", Some(TpeKind::Data), AccessType::Append));
+        assert!(scope.allows("org/repo/host1", Some(TpeKind::Keys), AccessType::Read));
+        assert!(scope.allows("org/repo", Some(TpeKind::Locks), AccessType::Modify));
+        assert!(!scope.allows("org/repo", Some(TpeKind::Locks), AccessType::ForceUnlock));
+        assert!(!scope.allows("org/repo", Some(TpeKind::Data), AccessType::Modify));
+        assert!(!scope.allows("org/repository", Some(TpeKind::Data), AccessType::Read));
+        assert!(!scope.allows("other", None, AccessType::Read));
+    }
+
+    #[test]
+    fn test_token_store_passes() {
+        let base = PathBuf::from("tests/generated/test_tokens");
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        fs::create_dir_all(&base).unwrap();
+        let path = base.join("tokens.toml");
+
+        let scope = TokenScope {
+            repos: Vec::new(),
+            access: AccessType::Read,
+        };
+
+        let mut store = TokenStore::from_file(&path).unwrap();
+        let (token, id) = store.create("rustic", scope.clone(), None, String::new());
+        let (expired, _) = store.create(
+            "rustic",
+            scope.clone(),
+            Some(Local::now() - Duration::days(1)),
+            String::new(),
+        );
+        store.to_file().unwrap();
+
+        // only the hash is stored
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains(token.rsplit('_').next().unwrap()));
+
+        let store = TokenStore::from_file(&path).unwrap();
+        assert_eq!(store.verify("rustic", &token), Some(&scope));
+        assert_eq!(store.verify("restic", &token), None);
+        assert_eq!(store.verify("rustic", &expired), None);
+        assert_eq!(store.verify("rustic", &format!("{token}x")), None);
+        assert_eq!(store.verify("rustic", "rustic"), None);
+
+        let mut store = store;
+        assert!(store.revoke(&id).is_some());
+        assert_eq!(store.verify("rustic", &token), None);
+
+        // the token file of earlier versions is moved out of sight
+        migrate_legacy_token_file(&base).unwrap();
+        assert!(!path.exists());
+        let store = TokenStore::from_file(&base.join(DEFAULT_TOKEN_FILE)).unwrap();
+        assert_eq!(store.verify("rustic", &token), Some(&scope));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
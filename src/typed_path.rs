@@ -1,3 +1,9 @@
+use axum::{
+    extract::Request,
+    http::{uri::PathAndQuery, Uri},
+    middleware::Next,
+    response::Response,
+};
 use axum_extra::routing::TypedPath;
 use serde_derive::{Deserialize, Serialize};
 use strum::{AsRefStr, Display, EnumString, IntoStaticStr, VariantNames};
@@ -54,6 +60,81 @@ impl TpeKind {
     }
 }
 
+/// Paths that are served by the server itself and never name a repository
+const RESERVED_PATHS: [&str; 1] = ["/health/"];
+
+/// Middleware allowing repository names with several path segments, e.g. `/org/team/host1/`
+///
+/// The typed routes only match a single segment for `:repo`, so the segments of a nested
+/// repository are joined with an encoded slash (`%2F`) before routing. The path extractors
+/// decode them again, so handlers see the repository as `org/team/host1`.
+///
+/// This has to wrap the whole `Router`, as layers added with `Router::layer` run after routing.
+pub async fn nested_repository_path(mut request: Request, next: Next) -> Response {
+    if let Some(uri) = encode_nested_repository_uri(request.uri()) {
+        tracing::debug!(original = %request.uri(), rewritten = %uri, "[nested_repository_path]");
+        *request.uri_mut() = uri;
+    }
+
+    next.run(request).await
+}
+
+fn encode_nested_repository_uri(uri: &Uri) -> Option<Uri> {
+    if RESERVED_PATHS
+        .iter()
+        .any(|reserved| uri.path().starts_with(reserved))
+    {
+        return None;
+    }
+
+    let path = encode_nested_repository_path(uri.path())?;
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Joins the segments of a nested repository with `%2F`
+///
+/// The repository ends right before the first segment that names a [`TpeKind`],
+/// or at the end of the path. Returns `None` if nothing needs to be rewritten.
+fn encode_nested_repository_path(path: &str) -> Option<String> {
+    let path = path.strip_prefix('/')?;
+    let (path, trailing_slash) = match path.strip_suffix('/') {
+        Some(path) => (path, "/"),
+        None => (path, ""),
+    };
+
+    let segments: Vec<&str> = path.split('/').collect();
+    let repo_len = segments
+        .iter()
+        .position(|segment| TpeKind::VARIANTS.contains(segment))
+        .unwrap_or(segments.len());
+
+    // Single segment repositories are routed as they are, and empty segments
+    // don't form a valid repository name
+    if repo_len < 2
+        || segments[..repo_len]
+            .iter()
+            .any(|segment| segment.is_empty())
+    {
+        return None;
+    }
+
+    let mut encoded = format!("/{}", segments[..repo_len].join("%2F"));
+    for segment in &segments[repo_len..] {
+        encoded.push('/');
+        encoded.push_str(segment);
+    }
+    encoded.push_str(trailing_slash);
+
+    Some(encoded)
+}
+
 // A type safe route with `"/:repo/config"` as its associated path.
 #[derive(TypedPath, Deserialize, Debug)]
 #[typed_path("/:repo/config")]
@@ -151,3 +232,44 @@ impl PathParts for RepositoryTpeNamePath {
         Some(self.name.clone())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use axum::http::Uri;
+    use rstest::rstest;
+
+    use super::{encode_nested_repository_path, encode_nested_repository_uri};
+
+    #[rstest]
+    #[case("/repo/", None)]
+    #[case("/repo/config", None)]
+    #[case("/repo/data/abc", None)]
+    #[case("/org/team/host1/", Some("/org%2Fteam%2Fhost1/"))]
+    #[case("/org/team/host1/config", Some("/org%2Fteam%2Fhost1/config"))]
+    #[case("/org/team/keys/", Some("/org%2Fteam/keys/"))]
+    #[case("/org/team/data/abc", Some("/org%2Fteam/data/abc"))]
+    #[case("/org//team/", None)]
+    #[case("/", None)]
+    fn test_encode_nested_repository_path_passes(
+        #[case] path: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        assert_eq!(
+            encode_nested_repository_path(path).as_deref(),
+            expected,
+            "path: {path}"
+        );
+    }
+
+    #[test]
+    fn test_encode_nested_repository_uri_passes() {
+        let uri: Uri = "/org/team/?create=true".parse().unwrap();
+        assert_eq!(
+            encode_nested_repository_uri(&uri).unwrap(),
+            "/org%2Fteam/?create=true"
+        );
+
+        let uri: Uri = "/health/live".parse().unwrap();
+        assert!(encode_nested_repository_uri(&uri).is_none());
+    }
+}
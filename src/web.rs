@@ -1,10 +1,13 @@
+use std::net::SocketAddr;
+
 use axum::{middleware, routing::get, Router};
 use axum_extra::routing::RouterExt;
-use axum_server::tls_rustls::RustlsConfig;
 use tokio::net::TcpListener;
+use tower::Layer;
 use tracing::{info, level_filters::LevelFilter};
 
 use crate::{
+    access_log::{init_access_log, reopen_on_sighup, write_access_log, AccessLog},
     acl::init_acl,
     auth::init_auth,
     context::ServerRuntimeContext,
@@ -14,12 +17,23 @@ use crate::{
         file_exchange::{add_file, delete_file, get_file},
         file_length::file_length,
         files_list::list_files,
-        health::{init_start_time, live_check},
+        health::{init_readiness, init_start_time, live_check, ready_check, Readiness},
+        metrics::get_metrics,
         repository::{create_repository, delete_repository},
     },
-    log::print_request_response,
-    storage::{init_storage, Storage},
-    typed_path::{RepositoryConfigPath, RepositoryPath, RepositoryTpeNamePath, RepositoryTpePath},
+    jwt::init_jwt,
+    lockout::init_lockout,
+    log::{init_body_prefix_limit, print_request_response},
+    metrics::{init_metrics, record_metrics, Metrics},
+    proxy_auth::init_proxy_auth,
+    quota::{init_quota, Quota},
+    storage::{init_storage, init_verify_upload, scrubber::init_scrubber, Storage},
+    tls::{rustls_config, ClientCertAcceptor},
+    tokens::init_tokens,
+    typed_path::{
+        nested_repository_path, RepositoryConfigPath, RepositoryPath, RepositoryTpeNamePath,
+        RepositoryTpePath,
+    },
 };
 
 /// Start the web server
@@ -33,17 +47,87 @@ where
 {
     let ServerRuntimeContext {
         socket_address,
+        access_log,
         acl,
         auth,
+        janitor,
+        jwt,
+        lockout,
+        log_body_prefix,
+        metrics,
+        min_free_space,
+        proxy_auth,
+        quota,
+        reloader,
+        scrubber,
         storage,
         tls,
-        ..
+        tokens,
+        verify_upload,
     } = runtime_ctx;
 
     init_start_time();
+    init_body_prefix_limit(log_body_prefix)?;
+    init_readiness(Readiness::new(
+        min_free_space,
+        tls.as_ref().map(|tls| tls.tls_cert.clone()),
+    ))?;
+    init_quota(Quota::new(quota).with_limits(acl.quota().clone()))?;
     init_acl(acl)?;
     init_auth(auth)?;
+    if let Some(lockout) = lockout {
+        init_lockout(lockout)?;
+    }
+    if let Some(proxy_auth) = proxy_auth {
+        init_proxy_auth(proxy_auth)?;
+    }
+    if let Some(tokens) = tokens {
+        init_tokens(tokens)?;
+    }
+    if let Some(jwt) = jwt {
+        let jwt = jwt.reload().await?;
+        info!(
+            "Bearer tokens are enabled: loaded `{}` keys from `{}`.",
+            jwt.key_count(),
+            jwt.source()
+        );
+        init_jwt(jwt)?;
+    }
     init_storage(storage)?;
+    init_verify_upload(verify_upload)?;
+
+    if let Some(access_log) = access_log {
+        init_access_log(AccessLog::open(access_log)?)?;
+        _ = reopen_on_sighup()?;
+    }
+
+    // The certificate is loaded here already, so it can be reloaded while serving
+    let rustls_config = match &tls {
+        Some(tls) => Some((
+            rustls_config(tls).map_err(|err|
+                ErrorKind::Io.context(
+                    format!("Failed to load TLS certificate/key. Please make sure the paths are correct. `{err}`")
+                )
+            )?,
+            tls.client_user,
+        )),
+        None => None,
+    };
+
+    let reloader = match (&rustls_config, tls) {
+        (Some((config, _)), Some(tls)) => reloader.with_tls(config.clone(), tls),
+        _ => reloader,
+    };
+    _ = reloader.spawn()?;
+
+    if let Some(janitor) = janitor {
+        _ = janitor.spawn();
+    }
+
+    if let Some(scrubber) = scrubber {
+        init_scrubber(scrubber.clone())?;
+        _ = scrubber.spawn();
+    }
 
     let mut app = Router::new();
 
@@ -56,8 +140,20 @@ where
     // /health/ready
     //
     // Readiness probe. This is used to check if the server is ready to accept requests.
-    // Returns “200 OK” if the server is ready to accept requests.
-    // app = app.route("/health/ready", get(ready_check));
+    // Returns “200 OK” if the server is ready to accept requests,
+    // “503 Service Unavailable” otherwise.
+    app = app.route("/health/ready", get(ready_check));
+
+    // /metrics
+    //
+    // Prometheus metrics, only served with `--prometheus`.
+    if let Some(metrics) = &metrics {
+        init_metrics(Metrics::new(
+            metrics.prometheus_no_auth,
+            metrics.prometheus_no_repo_label,
+        )?)?;
+        app = app.route("/metrics", get(get_metrics));
+    }
 
     // /:repo/:tpe/:name
     app = app
@@ -171,33 +267,36 @@ where
     //     app = app.route(path.as_str(), get(list_files::<TpePath>));
     // }
 
+    // Count requests and measure their latency for `/metrics`
+    if metrics.is_some() {
+        app = app.layer(middleware::from_fn(record_metrics));
+    }
+
     // Extra logging requested. Handlers will log too
-    // TODO: Use LogSettings here, this should be set from the cli by `--log`
-    // TODO: and then needs to go to a file
-    // e.g. log_opts.is_disabled() or other checks
+    // Bodies are streamed through, so this is cheap, but it only logs at debug level
     match LevelFilter::current() {
-        LevelFilter::TRACE | LevelFilter::DEBUG | LevelFilter::INFO => {
+        LevelFilter::TRACE | LevelFilter::DEBUG => {
             app = app.layer(middleware::from_fn(print_request_response));
         }
         _ => {}
     };
 
+    // Nested repositories, e.g. `/org/team/host1/`, need to be rewritten before routing
+    let app = Router::new()
+        .fallback_service(middleware::from_fn(nested_repository_path).layer(app))
+        // Access log in the combined log format, written with `--log`
+        .layer(middleware::from_fn(write_access_log));
+
     info!("Starting web server ...");
 
-    if let Some(tls) = tls {
+    if let Some((config, client_user)) = rustls_config {
         // Start server with or without TLS
-        let config = RustlsConfig::from_pem_file(tls.tls_cert, tls.tls_key)
-            .await
-        .map_err(|err|
-            ErrorKind::Io.context(
-                format!("Failed to load TLS certificate/key. Please make sure the paths are correct. `{err}`")
-            )
-        )?;
-
         info!("Listening on: `https://{socket_address}`");
 
-        axum_server::bind_rustls(socket_address, config)
-            .serve(app.into_make_service())
+        // Requests carry the user of a verified client certificate
+        axum_server::bind(socket_address)
+            .acceptor(ClientCertAcceptor::new(config, client_user))
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .expect("Failed to start server. Is the address already in use?");
     } else {
@@ -207,7 +306,7 @@ where
             TcpListener::bind(socket_address)
                 .await
                 .expect("Failed to bind to socket. Please make sure the address is correct."),
-            app.into_make_service(),
+            app.into_make_service_with_connect_info::<SocketAddr>(),
         )
         .await
         .expect("Failed to start server. Is the address already in use?");